@@ -0,0 +1,53 @@
+//! Pin assignment of the CANBED Dual. Another board only needs its pins changed here, the types
+//! keep the main loop unaware of which GPIOs it drives.
+
+use rp_pico::hal::gpio::{bank0, FunctionSpi, Pin, PinId, Pins, PullUpInput, PushPullOutput};
+
+/// Declares each pin's alias together with the `Pins` field it's taken from and its mode, so the
+/// alias, the Board field and `Board::new` can't disagree about a pin.
+macro_rules! board_pins {
+    ($($(#[$doc:meta])* $name:ident: $alias:ident = $gpio:ident($field:ident), $mode:ty;)*) => {
+        $($(#[$doc])* pub type $alias = bank0::$gpio;)*
+
+        pub struct Board {
+            $(pub $name: Pin<$alias, $mode>,)*
+        }
+
+        impl Board {
+            pub fn new(pins: Pins) -> Self {
+                Board {
+                    $($name: pins.$field.into_mode(),)*
+                }
+            }
+        }
+    };
+}
+
+board_pins! {
+    /// SPI0 clock.
+    spi_sclk: SpiSclk = Gpio2(gpio2), FunctionSpi;
+    /// SPI0 data to the MCP2515.
+    spi_mosi: SpiMosi = Gpio3(gpio3), FunctionSpi;
+    /// SPI0 data from the MCP2515.
+    spi_miso: SpiMiso = Gpio4(gpio4), FunctionSpi;
+    /// MCP2515 chip select, driven as a plain output so any pin works.
+    mcp2515_cs: Mcp2515Cs = Gpio9(gpio9), PushPullOutput;
+    status_led: StatusLed = Gpio25(gpio25), PushPullOutput;
+    /// Button taking a snapshot of the last received frames, active low.
+    snapshot_button: SnapshotButton = Gpio28(gpio28), PullUpInput;
+}
+
+/// Bit rate the controller runs at from init until the host programs its own timing. None
+/// keeps it in listen-only mode instead, so it never acknowledges or sends at a rate nobody
+/// chose.
+pub const DEFAULT_BIT_RATE: Option<u32> = None;
+
+/// Time the MCP2515 crystal is given after power-up before the controller is first accessed,
+/// in microseconds. The firmware also waits for the controller to come out of reset, so this
+/// only matters on boards whose power rails settle slowly.
+pub const STARTUP_DELAY_US: u64 = 0;
+
+// The HAL checks that a pin can be switched to the SPI function, not that it's routed to SPI0.
+const _: () = assert!(matches!(<SpiSclk as PinId>::DYN.num, 2 | 6 | 18 | 22));
+const _: () = assert!(matches!(<SpiMosi as PinId>::DYN.num, 3 | 7 | 19 | 23));
+const _: () = assert!(matches!(<SpiMiso as PinId>::DYN.num, 0 | 4 | 16 | 20));
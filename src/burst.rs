@@ -0,0 +1,126 @@
+/// Number of MCP2515 transmit buffers, and so the largest burst.
+pub const MAX_BURST: usize = 3;
+
+/// Tracks a burst: the next frames from the host are loaded into separate transmit buffers and
+/// released together, so they go out back to back, and are echoed to the host as one batch once
+/// all of them have left.
+pub struct Burst {
+    requested: usize,
+    in_flight: u8,
+}
+
+impl Burst {
+    pub fn new() -> Self {
+        Burst {
+            requested: 0,
+            in_flight: 0,
+        }
+    }
+
+    /// Starts collecting the next `count` frames, up to MAX_BURST. Ignored while a burst is
+    /// already under way.
+    pub fn start(&mut self, count: usize) {
+        if !self.is_active() {
+            self.requested = core::cmp::min(count, MAX_BURST);
+        }
+    }
+
+    /// Whether normal transmission is held off for a burst.
+    pub fn is_active(&self) -> bool {
+        self.requested > 0
+    }
+
+    /// Number of frames to load once enough are queued, or None if not collecting.
+    pub fn collecting(&self) -> Option<usize> {
+        match self.in_flight {
+            0 if self.requested > 0 => Some(self.requested),
+            _ => None,
+        }
+    }
+
+    /// Records that the transmit buffers in `mask`, bit n for buffer n, have been loaded and
+    /// released.
+    pub fn launched(&mut self, mask: u8) {
+        self.in_flight = mask;
+    }
+
+    /// Gives up on the burst, for instance when a frame can't be loaded.
+    pub fn cancel(&mut self) {
+        self.requested = 0;
+        self.in_flight = 0;
+    }
+
+    /// Updates the burst with the currently pending buffers. Returns the number of frames in the
+    /// batch once every one of them has been sent.
+    pub fn update(&mut self, pending: u8) -> Option<usize> {
+        if self.in_flight == 0 {
+            return None;
+        }
+
+        self.in_flight &= pending;
+
+        match self.in_flight {
+            0 => {
+                let count = self.requested;
+                self.requested = 0;
+                Some(count)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_collects_up_to_the_number_of_buffers() {
+        let mut burst = Burst::new();
+        assert!(!burst.is_active());
+        assert_eq!(burst.collecting(), None);
+
+        burst.start(5);
+        assert!(burst.is_active());
+        assert_eq!(burst.collecting(), Some(MAX_BURST));
+
+        // A second start doesn't change the burst under way.
+        burst.start(1);
+        assert_eq!(burst.collecting(), Some(MAX_BURST));
+    }
+
+    #[test]
+    fn batch_completes_once_every_launched_buffer_left() {
+        let mut burst = Burst::new();
+        burst.start(2);
+        burst.launched(0b011);
+        assert_eq!(burst.collecting(), None);
+
+        assert_eq!(burst.update(0b011), None);
+        // A pending buffer outside the burst, like a frame sent by other means, is no concern.
+        assert_eq!(burst.update(0b110), None);
+        assert_eq!(burst.update(0b100), Some(2));
+        assert!(!burst.is_active());
+    }
+
+    #[test]
+    fn nothing_is_reported_before_the_launch() {
+        let mut burst = Burst::new();
+        burst.start(2);
+
+        assert_eq!(burst.update(0), None);
+        assert_eq!(burst.collecting(), Some(2));
+    }
+
+    #[test]
+    fn cancel_drops_the_burst() {
+        let mut burst = Burst::new();
+        burst.start(3);
+        burst.launched(0b111);
+        burst.cancel();
+
+        assert!(!burst.is_active());
+        assert_eq!(burst.collecting(), None);
+        assert_eq!(burst.update(0), None);
+    }
+}
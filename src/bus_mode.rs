@@ -0,0 +1,100 @@
+use super::usbd_gs::{ChannelFlagsBit, ChannelMode};
+use mcp2515::regs::OpMode;
+
+/// How the controller takes part in bus traffic for a host mode request.
+///
+/// A mode that keeps acknowledging frames but never transmits isn't a controller mode: it's
+/// `Normal` with the host's frames dropped by the firmware. `Monitor` is the only mode that
+/// guarantees the device never drives the bus.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BusMode {
+    /// The channel is stopped, the controller is kept off the bus in the `StopMode` passed to
+    /// `controller_mode`.
+    Off,
+    /// The low-power setting: the controller is asleep and ignores the bus until bus activity
+    /// wakes it.
+    Sleep,
+    /// Frames are received, transmitted and acknowledged.
+    Normal,
+    /// Transmitted frames are looped back internally and never reach the bus.
+    Loopback,
+    /// Frames are received but TXCAN stays recessive, so the device never sends a dominant ACK
+    /// bit or error flag. Maps to the MCP2515 listen-only mode.
+    Monitor,
+}
+
+impl BusMode {
+    /// Listen-only takes precedence over loopback, so a host asking for both gets a monitor.
+    pub fn from_channel_mode(mode: &ChannelMode) -> BusMode {
+        if !mode.is_on() {
+            BusMode::Off
+        } else if mode.flags.is_set(ChannelFlagsBit::ListenOnly) {
+            BusMode::Monitor
+        } else if mode.flags.is_set(ChannelFlagsBit::Loopback) {
+            BusMode::Loopback
+        } else {
+            BusMode::Normal
+        }
+    }
+
+    /// The low-power alternate setting or a suspended bus puts the controller to sleep whatever
+    /// the host asked for, leaving it restores the requested mode.
+    pub fn for_power_state(requested: BusMode, low_power: bool) -> BusMode {
+        match low_power {
+            true => BusMode::Sleep,
+            false => requested,
+        }
+    }
+
+    /// Controller mode implementing the bus mode. Without hardware loopback the firmware loops
+    /// frames back itself and keeps the controller asleep, so nothing reaches the bus.
+    pub fn controller_mode(&self, hw_loopback: bool, stop: StopMode) -> OpMode {
+        match self {
+            BusMode::Loopback if !hw_loopback => OpMode::Sleep,
+            _ => self.op_mode(stop),
+        }
+    }
+
+    pub fn op_mode(&self, stop: StopMode) -> OpMode {
+        match self {
+            BusMode::Off => stop.op_mode(),
+            BusMode::Sleep => OpMode::Sleep,
+            BusMode::Normal => OpMode::Normal,
+            BusMode::Loopback => OpMode::Loopback,
+            BusMode::Monitor => OpMode::ListenOnly,
+        }
+    }
+
+    /// Whether the channel takes part in bus traffic, as reported to the host.
+    pub fn is_running(&self) -> bool {
+        !matches!(self, BusMode::Off | BusMode::Sleep)
+    }
+
+    /// Whether the controller drives the ACK slot for frames it receives.
+    #[allow(dead_code)]
+    pub fn acknowledges(&self) -> bool {
+        *self == BusMode::Normal
+    }
+}
+
+/// Controller mode a stopped channel is left in. A gs_usb reset stops the channel, it doesn't
+/// ask for low power.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StopMode {
+    /// Configuration mode: off the bus with every register accessible, so the next start is a
+    /// plain mode change.
+    Configuration,
+    /// Sleep mode, for the lowest current draw. The controller comes back through a wake-up
+    /// sequence, which the next start has to wait out.
+    Sleep,
+}
+
+impl StopMode {
+    fn op_mode(&self) -> OpMode {
+        match self {
+            StopMode::Configuration => OpMode::Configuration,
+            StopMode::Sleep => OpMode::Sleep,
+        }
+    }
+}
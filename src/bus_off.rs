@@ -0,0 +1,100 @@
+/// Bus-off recovery takes 128 occurrences of 11 consecutive recessive bits (ISO 11898-1).
+const RECOVERY_BITS: u64 = 128 * 11;
+
+/// Holds transmission back after the controller went bus-off. The MCP2515 recovers on its own
+/// once it has seen the recovery sequence, frames from the host are only loaded again when it
+/// has left bus-off and at least the recovery time has passed since it was noticed.
+pub struct BusOffRecovery {
+    /// Share of the datasheet recovery time to wait, in percent. Below 100 only for test setups
+    /// that cycle through bus-off quickly.
+    delay_percent: u64,
+    resume_at: Option<u64>,
+}
+
+impl BusOffRecovery {
+    pub fn new(delay_percent: u64) -> Self {
+        BusOffRecovery {
+            delay_percent,
+            resume_at: None,
+        }
+    }
+
+    /// Feeds the controller's bus-off state at `now` (microseconds).
+    pub fn update(&mut self, bus_off: bool, now: u64, bit_rate: u32) {
+        match (bus_off, self.resume_at) {
+            (true, None) => self.resume_at = Some(now + self.delay(bit_rate)),
+            (false, Some(resume_at)) if now >= resume_at => self.resume_at = None,
+            _ => {}
+        }
+    }
+
+    pub fn holds_tx(&self) -> bool {
+        self.resume_at.is_some()
+    }
+
+    fn delay(&self, bit_rate: u32) -> u64 {
+        match bit_rate {
+            0 => 0,
+            _ => RECOVERY_BITS * 1_000_000 / bit_rate as u64 * self.delay_percent / 100,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The recovery sequence at 500 kbit/s, in microseconds.
+    const RECOVERY_AT_500K: u64 = RECOVERY_BITS * 2;
+
+    #[test]
+    fn tx_is_held_for_the_recovery_time() {
+        let mut recovery = BusOffRecovery::new(100);
+        assert!(!recovery.holds_tx());
+
+        recovery.update(true, 1_000, 500_000);
+        assert!(recovery.holds_tx());
+
+        // Out of bus-off early, the controller only saw part of the recovery sequence.
+        recovery.update(false, 1_000 + RECOVERY_AT_500K - 1, 500_000);
+        assert!(recovery.holds_tx());
+
+        recovery.update(false, 1_000 + RECOVERY_AT_500K, 500_000);
+        assert!(!recovery.holds_tx());
+    }
+
+    #[test]
+    fn tx_is_held_while_still_bus_off() {
+        let mut recovery = BusOffRecovery::new(100);
+
+        recovery.update(true, 0, 500_000);
+        recovery.update(true, 10 * RECOVERY_AT_500K, 500_000);
+        assert!(recovery.holds_tx());
+
+        recovery.update(false, 10 * RECOVERY_AT_500K + 1, 500_000);
+        assert!(!recovery.holds_tx());
+    }
+
+    #[test]
+    fn delay_scales_with_the_configured_share() {
+        let mut recovery = BusOffRecovery::new(50);
+
+        recovery.update(true, 0, 500_000);
+        recovery.update(false, RECOVERY_AT_500K / 2 - 1, 500_000);
+        assert!(recovery.holds_tx());
+
+        recovery.update(false, RECOVERY_AT_500K / 2, 500_000);
+        assert!(!recovery.holds_tx());
+    }
+
+    #[test]
+    fn unknown_bit_rate_waits_for_bus_off_only() {
+        let mut recovery = BusOffRecovery::new(100);
+
+        recovery.update(true, 1_000, 0);
+        assert!(recovery.holds_tx());
+
+        recovery.update(false, 1_000, 0);
+        assert!(!recovery.holds_tx());
+    }
+}
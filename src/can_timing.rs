@@ -0,0 +1,137 @@
+use super::usbd_gs::BitTiming;
+use mcp2515::CanSpeed;
+
+/// CNF1, CNF2 and CNF3 values for a 16 MHz MCP2515 crystal, in the order
+/// `can_speed_from_bit_rate` picks them. These are the values the MCP2515 driver programs for
+/// `McpSpeed::MHz16`, listed here so the resulting timing can be checked against the datasheet
+/// (section 5) without a board.
+///
+/// CNF1 holds SJW (bits 7-6) and BRP (bits 5-0), CNF2 BTLMODE, SAM, PHSEG1 and PRSEG, and CNF3
+/// PHSEG2. With TQ = 2 * (BRP + 1) / 16 MHz, the bit time is (1 + (PRSEG + 1) + (PHSEG1 + 1) +
+/// (PHSEG2 + 1)) TQ.
+pub const CNF_16MHZ: [(u32, [u8; 3]); 14] = [
+    (5_000, [0x3f, 0xff, 0x87]),
+    (10_000, [0x1f, 0xff, 0x87]),
+    (20_000, [0x0f, 0xff, 0x87]),
+    (31_250, [0x0f, 0xf1, 0x85]),
+    (33_300, [0x4e, 0xf1, 0x85]),
+    (40_000, [0x07, 0xff, 0x87]),
+    (50_000, [0x07, 0xfa, 0x87]),
+    (80_000, [0x03, 0xff, 0x87]),
+    (100_000, [0x03, 0xfa, 0x87]),
+    (125_000, [0x03, 0xf0, 0x86]),
+    (200_000, [0x01, 0xfa, 0x87]),
+    (250_000, [0x41, 0xf1, 0x85]),
+    (500_000, [0x00, 0xf0, 0x86]),
+    (1_000_000, [0x00, 0xd0, 0x82]),
+];
+
+/// Returns the nominal bit rate and CNF1..3 values used for `speed` with a 16 MHz crystal.
+pub fn cnf_registers(speed: &CanSpeed) -> Option<(u32, [u8; 3])> {
+    let index = match speed {
+        CanSpeed::Kbps5 => 0,
+        CanSpeed::Kbps10 => 1,
+        CanSpeed::Kbps20 => 2,
+        CanSpeed::Kbps31_25 => 3,
+        CanSpeed::Kbps33_3 => 4,
+        CanSpeed::Kbps40 => 5,
+        CanSpeed::Kbps50 => 6,
+        CanSpeed::Kbps80 => 7,
+        CanSpeed::Kbps100 => 8,
+        CanSpeed::Kbps125 => 9,
+        CanSpeed::Kbps200 => 10,
+        CanSpeed::Kbps250 => 11,
+        CanSpeed::Kbps500 => 12,
+        CanSpeed::Kbps1000 => 13,
+        #[allow(unreachable_patterns)]
+        _ => return None,
+    };
+
+    Some(CNF_16MHZ[index])
+}
+
+/// Converts CNF1..3 back to gs_usb bit timing, relative to an fclk_can of half the oscillator
+/// frequency (the MCP2515 prescaler divides Fosc by 2 * (BRP + 1)). `BitTiming::bit_rate` then
+/// gives the rate actually achieved, which may differ from the one requested.
+pub fn bit_timing_from_cnf(cnf: &[u8; 3]) -> BitTiming {
+    BitTiming {
+        prop_seg: (cnf[1] & 0x07) as u32 + 1,
+        phase_seg1: ((cnf[1] >> 3) & 0x07) as u32 + 1,
+        phase_seg2: (cnf[2] & 0x07) as u32 + 1,
+        sjw: (cnf[0] >> 6) as u32 + 1,
+        brp: (cnf[0] & 0x3f) as u32 + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbd_gs::{Channel, ChannelConstraints, ChannelFeatures};
+
+    fn channel() -> Channel {
+        Channel {
+            features: ChannelFeatures::new(&[]),
+            fclk_can: 8_000_000,
+            constraints: ChannelConstraints {
+                tseg1_min: 3,
+                tseg1_max: 8,
+                tseg2_min: 2,
+                tseg2_max: 8,
+                sjw_max: 4,
+                brp_min: 1,
+                brp_max: 64,
+                brp_inc: 1,
+            },
+            data_constraints: None,
+        }
+    }
+
+    #[test]
+    fn table_follows_the_datasheet_timing_rules() {
+        for (bit_rate, cnf) in CNF_16MHZ {
+            let timing = bit_timing_from_cnf(&cnf);
+
+            // BTLMODE, PHSEG2 is taken from CNF3.
+            assert!(cnf[1] & 0x80 != 0, "{}", bit_rate);
+            assert!(timing.phase_seg2 >= 2, "{}", bit_rate);
+            assert!(timing.prop_seg + timing.phase_seg1 >= timing.phase_seg2, "{}", bit_rate);
+            assert!(timing.sjw <= timing.phase_seg2, "{}", bit_rate);
+
+            let bit_time = 1 + timing.prop_seg + timing.phase_seg1 + timing.phase_seg2;
+            assert!((8..=25).contains(&bit_time), "{}", bit_rate);
+        }
+    }
+
+    #[test]
+    fn cnf_registers_match_the_table() {
+        assert_eq!(cnf_registers(&CanSpeed::Kbps5), Some((5_000, [0x3f, 0xff, 0x87])));
+        assert_eq!(cnf_registers(&CanSpeed::Kbps125), Some((125_000, [0x03, 0xf0, 0x86])));
+        assert_eq!(cnf_registers(&CanSpeed::Kbps500), Some((500_000, [0x00, 0xf0, 0x86])));
+        assert_eq!(cnf_registers(&CanSpeed::Kbps1000), Some((1_000_000, [0x00, 0xd0, 0x82])));
+    }
+
+    #[test]
+    fn achieved_rate_matches_the_nominal_one() {
+        for (bit_rate, cnf) in CNF_16MHZ.into_iter().filter(|(rate, _)| *rate != 33_300) {
+            assert_eq!(bit_timing_from_cnf(&cnf).bit_rate(&channel()), bit_rate);
+        }
+    }
+
+    #[test]
+    fn achieved_rate_shows_the_quantization() {
+        // BRP 15 and 16 TQ: 16 MHz / 2 / 15 / 16.
+        let (_, cnf) = cnf_registers(&CanSpeed::Kbps33_3).unwrap();
+        assert_eq!(bit_timing_from_cnf(&cnf).bit_rate(&channel()), 33_333);
+    }
+
+    #[test]
+    fn cnf_fields_convert_to_bit_timing() {
+        let timing = bit_timing_from_cnf(&[0x41, 0xf1, 0x85]);
+
+        assert_eq!(timing.brp, 2);
+        assert_eq!(timing.sjw, 2);
+        assert_eq!(timing.prop_seg, 2);
+        assert_eq!(timing.phase_seg1, 7);
+        assert_eq!(timing.phase_seg2, 6);
+    }
+}
@@ -0,0 +1,142 @@
+use super::mcp_ext::{Mcp2515Ext, TxPriority};
+use super::usbd_gs::{ChannelFeatures, ChannelFeaturesBit};
+use core::fmt::Debug;
+use embedded_hal::blocking::{delay::DelayMs, spi::Transfer};
+use embedded_hal::can::Frame;
+use embedded_hal::digital::v2::OutputPin;
+use mcp2515::{error::Error, frame::CanFrame, MCP2515};
+
+/// Capabilities of the CAN controller behind a channel.
+pub trait Controller {
+    /// Whether the controller can send and receive CAN FD frames.
+    fn supports_fd(&self) -> bool;
+
+    /// Whether the controller can loop transmitted frames back internally. Without it, loopback
+    /// is done in software.
+    fn has_hw_loopback(&self) -> bool;
+
+    /// Channel features the firmware can honor with this controller.
+    fn capabilities(&self) -> ChannelFeatures;
+}
+
+impl<SPI, CS, D> Controller for MCP2515<SPI, CS, D> {
+    fn supports_fd(&self) -> bool {
+        false
+    }
+
+    fn has_hw_loopback(&self) -> bool {
+        true
+    }
+
+    /// Triple sampling exists in CNF2 but the bit timing table doesn't use it. Echoes are up to
+    /// the firmware, so skipping them works with any controller.
+    fn capabilities(&self) -> ChannelFeatures {
+        ChannelFeatures::new(&[
+            ChannelFeaturesBit::ListenOnly,
+            ChannelFeaturesBit::Loopback,
+            ChannelFeaturesBit::OneShot,
+            ChannelFeaturesBit::BerrReporting,
+            ChannelFeaturesBit::NoEcho,
+        ])
+    }
+}
+
+/// Moving frames through the controller, what the bridging in `host_link` needs from it.
+pub trait CanBus {
+    type Error;
+
+    /// Loads `frame` into a free transmit buffer and requests its transmission, returning the
+    /// buffer. TxBusy while every buffer is taken.
+    fn send(&mut self, frame: &CanFrame) -> Result<usize, Self::Error>;
+
+    /// Next received frame, None if there's none. A frame the driver can't decode is discarded,
+    /// freeing its receive buffer, and reported as the error.
+    fn receive(&mut self) -> Result<Option<CanFrame>, Self::Error>;
+}
+
+impl<SPI, CS, D, SPIE, CSE> CanBus for MCP2515<SPI, CS, D>
+where
+    SPI: Transfer<u8, Error = SPIE>,
+    CS: OutputPin<Error = CSE>,
+    D: DelayMs<u8>,
+    SPIE: Debug,
+    CSE: Debug,
+{
+    type Error = Error<SPIE, CSE>;
+
+    fn send(&mut self, frame: &CanFrame) -> Result<usize, Self::Error> {
+        self.send_with_priority(frame, TxPriority::from_id(&frame.id()))
+    }
+
+    fn receive(&mut self) -> Result<Option<CanFrame>, Self::Error> {
+        // The driver leaves RXnIF set when it can't decode the frame, and would return the same
+        // frame forever while the buffer stays blocked for new ones.
+        let buffer = self.next_rx_buffer()?;
+
+        match self.read_message() {
+            Ok(frame) => Ok(Some(frame)),
+            Err(Error::NoMessage) => Ok(None),
+            Err(error @ (Error::InvalidDlc | Error::InvalidFrameId)) => {
+                if let Some(buffer) = buffer {
+                    self.discard_rx(buffer)?;
+                }
+
+                Err(error)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcp_ext::fake_chip;
+    use embedded_hal::can::{Id, StandardId};
+    use mcp2515::regs::Register;
+
+    const RX0IF: u8 = 1 << 0;
+    const RX1IF: u8 = 1 << 1;
+
+    /// Puts a standard data frame with `dlc` into RXB0, the data bytes counting up from 1.
+    fn receive_into_rxb0(chip: &fake_chip::SharedChip, id: u16, dlc: u8) {
+        let mut chip = chip.borrow_mut();
+        chip.set(Register::RXB0SIDH, (id >> 3) as u8);
+        chip.set(Register::RXB0SIDL, ((id & 0x07) << 5) as u8);
+        chip.set(Register::RXB0DLC, dlc);
+
+        for offset in 0..8 {
+            chip.registers[Register::RXB0DATA as usize + offset] = offset as u8 + 1;
+        }
+
+        let flags = chip.get(Register::CANINTF);
+        chip.set(Register::CANINTF, flags | RX0IF);
+    }
+
+    #[test]
+    fn received_frame_is_read_and_its_buffer_freed() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        receive_into_rxb0(&chip, 0x123, 3);
+
+        let frame = mcp2515.receive().unwrap().unwrap();
+        assert!(frame.id() == Id::Standard(StandardId::new(0x123).unwrap()));
+        assert_eq!(frame.data(), [1, 2, 3]);
+        assert_eq!(chip.borrow().get(Register::CANINTF) & RX0IF, 0);
+
+        assert!(mcp2515.receive().unwrap().is_none());
+    }
+
+    #[test]
+    fn frame_with_an_invalid_dlc_is_reported_and_its_buffer_freed() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        receive_into_rxb0(&chip, 0x123, 12);
+
+        assert!(matches!(mcp2515.receive(), Err(Error::InvalidDlc)));
+        assert_eq!(chip.borrow().get(Register::CANINTF) & (RX0IF | RX1IF), 0);
+
+        // The buffer takes the next frame instead of returning the broken one again.
+        assert!(mcp2515.receive().unwrap().is_none());
+        receive_into_rxb0(&chip, 0x456, 1);
+        assert_eq!(mcp2515.receive().unwrap().unwrap().data(), [1]);
+    }
+}
@@ -0,0 +1,148 @@
+use super::frame_ext::CAN_ERR_CRTL_ACTIVE;
+
+/// TEC or REC value at which the controller reaches error warning, and error passive.
+const WARNING_LIMIT: u8 = 96;
+const PASSIVE_LIMIT: u8 = 128;
+
+/// How far a counter has to fall below a limit before the level drops again, so a counter
+/// hovering around a limit doesn't report every step across it.
+const HYSTERESIS: u8 = 8;
+
+/// SocketCAN controller state bits, as reported in data byte 1 of a CAN_ERR_CRTL frame.
+const CAN_ERR_CRTL_RX_WARNING: u8 = 0x04;
+const CAN_ERR_CRTL_TX_WARNING: u8 = 0x08;
+const CAN_ERR_CRTL_RX_PASSIVE: u8 = 0x10;
+const CAN_ERR_CRTL_TX_PASSIVE: u8 = 0x20;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Active,
+    Warning,
+    Passive,
+}
+
+impl Level {
+    fn of(count: u8) -> Level {
+        match count {
+            count if count >= PASSIVE_LIMIT => Level::Passive,
+            count if count >= WARNING_LIMIT => Level::Warning,
+            _ => Level::Active,
+        }
+    }
+
+    /// Rises as soon as a limit is reached, falls only once the count is HYSTERESIS below it.
+    fn next(self, count: u8) -> Level {
+        let rising = Level::of(count);
+        let falling = Level::of(count.saturating_add(HYSTERESIS));
+
+        if rising > self {
+            rising
+        } else if falling < self {
+            falling
+        } else {
+            self
+        }
+    }
+}
+
+/// Follows the transmit and receive error counters for the host's bus error reporting, and tells
+/// when either of them crossed the error warning or error passive limit.
+pub struct ErrorCounters {
+    tx: Level,
+    rx: Level,
+}
+
+impl ErrorCounters {
+    pub fn new() -> Self {
+        ErrorCounters {
+            tx: Level::Active,
+            rx: Level::Active,
+        }
+    }
+
+    /// Feeds the current TEC and REC. Returns the controller state byte of the error frame to
+    /// send if a level changed.
+    pub fn update(&mut self, tec: u8, rec: u8) -> Option<u8> {
+        let (tx, rx) = (self.tx.next(tec), self.rx.next(rec));
+
+        if (tx, rx) == (self.tx, self.rx) {
+            return None;
+        }
+
+        self.tx = tx;
+        self.rx = rx;
+
+        let state = match tx {
+            Level::Active => 0,
+            Level::Warning => CAN_ERR_CRTL_TX_WARNING,
+            Level::Passive => CAN_ERR_CRTL_TX_PASSIVE,
+        } | match rx {
+            Level::Active => 0,
+            Level::Warning => CAN_ERR_CRTL_RX_WARNING,
+            Level::Passive => CAN_ERR_CRTL_RX_PASSIVE,
+        };
+
+        match state {
+            0 => Some(CAN_ERR_CRTL_ACTIVE),
+            _ => Some(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_to_report_below_the_warning_limit() {
+        let mut counters = ErrorCounters::new();
+
+        assert_eq!(counters.update(0, 0), None);
+        assert_eq!(counters.update(95, 95), None);
+    }
+
+    #[test]
+    fn reports_each_limit_when_reached() {
+        let mut counters = ErrorCounters::new();
+
+        assert_eq!(counters.update(96, 0), Some(CAN_ERR_CRTL_TX_WARNING));
+        assert_eq!(counters.update(128, 0), Some(CAN_ERR_CRTL_TX_PASSIVE));
+        assert_eq!(
+            counters.update(128, 96),
+            Some(CAN_ERR_CRTL_TX_PASSIVE | CAN_ERR_CRTL_RX_WARNING)
+        );
+        assert_eq!(
+            counters.update(128, 128),
+            Some(CAN_ERR_CRTL_TX_PASSIVE | CAN_ERR_CRTL_RX_PASSIVE)
+        );
+    }
+
+    #[test]
+    fn counter_hovering_around_a_limit_reports_once() {
+        let mut counters = ErrorCounters::new();
+        assert_eq!(counters.update(0, 96), Some(CAN_ERR_CRTL_RX_WARNING));
+
+        for rec in [95, 96, 90, 97, 89, 96] {
+            assert_eq!(counters.update(0, rec), None);
+        }
+    }
+
+    #[test]
+    fn level_drops_below_the_limit_minus_hysteresis() {
+        let mut counters = ErrorCounters::new();
+        counters.update(130, 0);
+
+        assert_eq!(counters.update(120, 0), None);
+        assert_eq!(counters.update(119, 0), Some(CAN_ERR_CRTL_TX_WARNING));
+        assert_eq!(counters.update(88, 0), None);
+        assert_eq!(counters.update(87, 0), Some(CAN_ERR_CRTL_ACTIVE));
+    }
+
+    #[test]
+    fn falling_straight_from_passive_to_active() {
+        let mut counters = ErrorCounters::new();
+        counters.update(0, 200);
+
+        assert_eq!(counters.update(0, 0), Some(CAN_ERR_CRTL_ACTIVE));
+    }
+}
@@ -0,0 +1,226 @@
+use super::mcp_ext::OPMOD_MASK;
+use core::fmt::Debug;
+use mcp2515::error::Error;
+
+/// Why the CAN controller can't be used. The discriminant is what the GetFault request reports,
+/// zero meaning no fault.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ControllerFault {
+    /// The controller answers over SPI but never enters the requested mode. Mode changes are
+    /// clocked by the controller's oscillator, so its crystal has most likely stopped.
+    Oscillator = 1,
+    /// CANSTAT reads back all ones, or a mode the controller can't be in, nothing is answering
+    /// on the SPI bus.
+    NoResponse = 2,
+    /// Any other init failure.
+    Other = 3,
+}
+
+/// Classifies the outcome of init together with a read of CANSTAT taken right after it. `opmod`
+/// is the CANSTAT.OPMOD value of the mode init puts the controller in.
+///
+/// A successful init only proves that CANSTAT read back the requested mode, and with nothing
+/// on the SPI bus reads return all zeros, which is what Normal mode looks like. So the read is
+/// still checked: all ones, or a mode other than the one init reported, means nothing is
+/// answering. After a failed init, all zeros can't be the configuration mode the controller
+/// is stuck in either.
+pub fn classify<SPIE: Debug, CSE: Debug>(
+    init: &Result<(), Error<SPIE, CSE>>,
+    canstat: Option<u8>,
+    opmod: u8,
+) -> Option<ControllerFault> {
+    match (init, canstat) {
+        (_, Some(0xff)) => Some(ControllerFault::NoResponse),
+        (Ok(()), Some(canstat)) if canstat & OPMOD_MASK != opmod => {
+            Some(ControllerFault::NoResponse)
+        }
+        (Ok(()), _) => None,
+        (Err(_), Some(0x00)) => Some(ControllerFault::NoResponse),
+        (Err(Error::NewModeTimeout), _) => Some(ControllerFault::Oscillator),
+        (Err(_), _) => Some(ControllerFault::Other),
+    }
+}
+
+/// How long init is retried for. After power-up the controller's oscillator needs a few
+/// milliseconds before mode changes go through. Times are in microseconds.
+pub const INIT_TIMEOUT_US: u64 = 100_000;
+
+/// Polls `running` until the controller's oscillator runs or `timeout` has passed, and returns
+/// whether it does. Init is attempted either way, it's what classifies the fault.
+pub fn wait_for_oscillator(
+    mut running: impl FnMut() -> bool,
+    now: impl Fn() -> u64,
+    timeout: u64,
+) -> bool {
+    let deadline = now() + timeout;
+
+    loop {
+        if running() {
+            return true;
+        }
+
+        if now() >= deadline {
+            return false;
+        }
+    }
+}
+
+/// Retries `init` until it succeeds or `timeout` has passed, then classifies the last attempt.
+/// `init` puts the controller in the mode with CANSTAT.OPMOD `opmod` and returns the result
+/// together with a read of CANSTAT taken right after it.
+pub fn init_with_timeout<SPIE: Debug, CSE: Debug>(
+    opmod: u8,
+    mut init: impl FnMut() -> (Result<(), Error<SPIE, CSE>>, Option<u8>),
+    now: impl Fn() -> u64,
+    timeout: u64,
+) -> Option<ControllerFault> {
+    let deadline = now() + timeout;
+
+    loop {
+        let (result, canstat) = init();
+        let fault = classify(&result, canstat, opmod);
+
+        if fault.is_none() || now() >= deadline {
+            return fault;
+        }
+    }
+}
+
+/// Status LED state at `now` (microseconds) for a fault: the fault code is blinked as that many
+/// 200 ms flashes, followed by a pause, every two seconds.
+pub fn led_on(fault: ControllerFault, now: u64) -> bool {
+    let phase_ms = (now / 1000) % 2000;
+    let flash = phase_ms / 400;
+
+    flash < fault as u64 && phase_ms % 400 < 200
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    const NORMAL: u8 = 0x00;
+    const LISTEN_ONLY: u8 = 0x60;
+    const CONFIGURATION: u8 = 0x80;
+
+    const OK: Result<(), Error<(), ()>> = Ok(());
+    const TIMEOUT: Result<(), Error<(), ()>> = Err(Error::NewModeTimeout);
+
+    #[test]
+    fn init_into_normal_mode_reads_back_zero() {
+        assert!(classify(&OK, Some(NORMAL), NORMAL).is_none());
+    }
+
+    #[test]
+    fn init_into_listen_only_mode() {
+        // ICOD bits set by a pending interrupt don't matter.
+        assert!(classify(&OK, Some(LISTEN_ONLY), LISTEN_ONLY).is_none());
+        assert!(classify(&OK, Some(LISTEN_ONLY | 0x0e), LISTEN_ONLY).is_none());
+    }
+
+    #[test]
+    fn init_without_a_canstat_read() {
+        assert!(classify(&OK, None, NORMAL).is_none());
+    }
+
+    #[test]
+    fn all_ones_is_no_response() {
+        let no_response = Some(ControllerFault::NoResponse);
+
+        assert!(classify(&OK, Some(0xff), LISTEN_ONLY) == no_response);
+        assert!(classify(&TIMEOUT, Some(0xff), NORMAL) == no_response);
+    }
+
+    #[test]
+    fn mode_other_than_the_one_init_saw_is_no_response() {
+        let fault = classify(&OK, Some(NORMAL), LISTEN_ONLY);
+        assert!(fault == Some(ControllerFault::NoResponse));
+    }
+
+    #[test]
+    fn failed_init_reading_zero_is_no_response() {
+        let fault = classify(&TIMEOUT, Some(0x00), LISTEN_ONLY);
+        assert!(fault == Some(ControllerFault::NoResponse));
+    }
+
+    #[test]
+    fn stuck_in_configuration_mode_is_the_oscillator() {
+        let oscillator = Some(ControllerFault::Oscillator);
+
+        assert!(classify(&TIMEOUT, Some(CONFIGURATION), NORMAL) == oscillator);
+        assert!(classify(&TIMEOUT, None, NORMAL) == oscillator);
+    }
+
+    #[test]
+    fn init_is_retried_until_it_succeeds() {
+        let attempts = Cell::new(0);
+        let fault = init_with_timeout(
+            NORMAL,
+            || {
+                attempts.set(attempts.get() + 1);
+
+                match attempts.get() {
+                    3 => (OK, Some(NORMAL)),
+                    _ => (TIMEOUT, Some(CONFIGURATION)),
+                }
+            },
+            || attempts.get() * 10,
+            100,
+        );
+
+        assert!(fault.is_none());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn init_gives_up_after_the_timeout_with_the_last_fault() {
+        let now = Cell::new(0);
+        let fault = init_with_timeout(
+            NORMAL,
+            || {
+                now.set(now.get() + 30);
+                (TIMEOUT, Some(CONFIGURATION))
+            },
+            || now.get(),
+            100,
+        );
+
+        assert!(fault == Some(ControllerFault::Oscillator));
+        assert!(now.get() >= 100);
+        assert!(now.get() < 160);
+    }
+
+    #[test]
+    fn oscillator_is_polled_until_it_runs() {
+        let polls = Cell::new(0);
+        let running = wait_for_oscillator(
+            || {
+                polls.set(polls.get() + 1);
+                polls.get() == 4
+            },
+            || polls.get() * 10,
+            1_000,
+        );
+
+        assert!(running);
+        assert_eq!(polls.get(), 4);
+    }
+
+    #[test]
+    fn oscillator_that_never_runs_times_out() {
+        let now = Cell::new(0);
+        let running = wait_for_oscillator(
+            || false,
+            || {
+                now.set(now.get() + 10);
+                now.get()
+            },
+            100,
+        );
+
+        assert!(!running);
+        assert!(now.get() >= 100);
+    }
+}
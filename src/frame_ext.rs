@@ -1,5 +1,10 @@
+mod frame_data;
+#[cfg(test)]
+mod replay;
+
+pub use frame_data::*;
+
 use super::usbd_gs::{HostCanId, HostCanIdBits, HostFrame, HostFrameFlags};
-use embedded_hal::can::{ExtendedId, Frame, Id, StandardId};
 use mcp2515::frame::CanFrame;
 
 pub trait ToHostFrame {
@@ -7,45 +12,72 @@ pub trait ToHostFrame {
 }
 
 pub trait FromHostFrame: Sized {
-    fn from_host_frame(frame: &HostFrame) -> Option<Self>;
+    fn from_host_frame(frame: &HostFrame) -> Result<Self, FrameConvertError>;
+}
+
+/// Why a frame from the host can't be sent by the controller.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FrameConvertError {
+    /// The id doesn't fit in 11 bits (standard) or 29 bits (extended).
+    InvalidId,
+    /// The DLC is larger than the controller can send.
+    InvalidDlc,
+    /// The frame asks for something the controller can't do, like FD or an error frame.
+    UnsupportedFlags,
 }
 
 impl ToHostFrame for CanFrame {
     fn to_host_frame(&self, channel: u8) -> HostFrame {
-        let flags = HostFrameFlags::new(&[]);
-        let can_id = match (self.id(), self.is_remote_frame()) {
-            (Id::Standard(id), true) => {
-                HostCanId::new(id.as_raw() as u32, &[HostCanIdBits::RemoteFrame])
-            }
-            (Id::Standard(id), false) => HostCanId::new(id.as_raw() as u32, &[]),
-            (Id::Extended(id), true) => HostCanId::new(
-                id.as_raw(),
-                &[HostCanIdBits::RemoteFrame, HostCanIdBits::ExtendedId],
-            ),
-            (Id::Extended(id), false) => HostCanId::new(id.as_raw(), &[HostCanIdBits::ExtendedId]),
-        };
-
-        let mut bytes: [u8; 64] = [0; 64];
-
-        bytes.copy_from_slice(self.data());
-
-        HostFrame::new(None, can_id, self.dlc() as u8, channel, flags, bytes)
+        CanFrameData::from_frame(self).to_host_frame(channel)
     }
 }
 
 impl FromHostFrame for CanFrame {
-    fn from_host_frame(frame: &HostFrame) -> Option<Self> {
-        let id = unsafe {
-            match frame.can_id.is_set(HostCanIdBits::ExtendedId) {
-                true => Id::Extended(ExtendedId::new_unchecked(frame.can_id.id())),
-                false => Id::Standard(StandardId::new_unchecked(frame.can_id.id() as u16)),
-            }
-        };
-
-        if frame.can_id.is_set(HostCanIdBits::RemoteFrame) {
-            CanFrame::new_remote(id, frame.can_dlc as usize)
-        } else {
-            CanFrame::new(id, &frame.bytes[..(frame.can_dlc as usize)])
-        }
+    fn from_host_frame(frame: &HostFrame) -> Result<Self, FrameConvertError> {
+        CanFrameData::from_host_frame(frame)?
+            .to_frame()
+            .ok_or(FrameConvertError::InvalidDlc)
     }
 }
+
+/// SocketCAN error class for controller problems, with the state in data byte 1.
+const CAN_ERR_CRTL: u32 = 0x00000004;
+/// SocketCAN error class flag: TEC and REC are in data bytes 6 and 7.
+const CAN_ERR_CNT: u32 = 0x00000200;
+/// Controller state byte: back to error active.
+pub const CAN_ERR_CRTL_ACTIVE: u8 = 0x40;
+/// Controller state byte: the controller's receive buffers overflowed.
+const CAN_ERR_CRTL_RX_OVERFLOW: u8 = 0x01;
+
+/// Error frame telling the host the controller is taking part in bus traffic again, the way
+/// SocketCAN reports a controller recovering.
+pub fn controller_active_frame(channel: u8) -> HostFrame {
+    controller_error_frame(channel, CAN_ERR_CRTL_ACTIVE)
+}
+
+/// Error frame telling the host the controller lost a frame to a receive buffer overrun. Frames
+/// lost because the host didn't keep up are flagged with Overflow instead.
+pub fn rx_overrun_frame(channel: u8) -> HostFrame {
+    controller_error_frame(channel, CAN_ERR_CRTL_RX_OVERFLOW)
+}
+
+/// Error frame reporting a change of the controller's error state, `state` being the
+/// controller state byte, together with the error counters.
+pub fn error_counter_frame(channel: u8, state: u8, tec: u8, rec: u8) -> HostFrame {
+    let mut host_frame = controller_error_frame(channel, state);
+    host_frame.can_id = HostCanId::new(CAN_ERR_CRTL | CAN_ERR_CNT, &[HostCanIdBits::ErrorFrame])
+        .expect("error classes fit a standard id");
+    host_frame.bytes[6] = tec;
+    host_frame.bytes[7] = rec;
+
+    host_frame
+}
+
+fn controller_error_frame(channel: u8, state: u8) -> HostFrame {
+    let can_id = HostCanId::new(CAN_ERR_CRTL, &[HostCanIdBits::ErrorFrame])
+        .expect("error classes fit a standard id");
+    let mut bytes: [u8; 64] = [0; 64];
+    bytes[1] = state;
+
+    HostFrame::new(None, can_id, 8, channel, HostFrameFlags::new(&[]), bytes)
+}
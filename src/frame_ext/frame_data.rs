@@ -0,0 +1,235 @@
+use super::FrameConvertError;
+use crate::usbd_gs::{
+    len_to_dlc, HostCanId, HostCanIdBits, HostFrame, HostFrameFlags, HostFrameFlagsBits,
+};
+use embedded_hal::can::{ExtendedId, Frame, Id, StandardId};
+
+/// A classic CAN frame independent of any controller driver, the hop between HostFrame and the
+/// frame type of a driver. Any driver frame implementing embedded-hal's Frame converts to and
+/// from it, so only this type knows about the gs_usb layout.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CanFrameData {
+    pub id: Id,
+    pub remote: bool,
+    /// Data length of a data frame, the length requested by a remote frame.
+    pub dlc: usize,
+    /// Zero past the data, and entirely for a remote frame.
+    pub data: [u8; 8],
+}
+
+impl CanFrameData {
+    pub fn from_frame<F: Frame>(frame: &F) -> Self {
+        // A remote frame has no data, so whatever the controller left in its receive buffer
+        // isn't passed on.
+        let mut data = [0; 8];
+
+        if !frame.is_remote_frame() {
+            let length = core::cmp::min(frame.data().len(), data.len());
+            data[..length].copy_from_slice(&frame.data()[..length]);
+        }
+
+        CanFrameData {
+            id: frame.id(),
+            remote: frame.is_remote_frame(),
+            dlc: frame.dlc(),
+            data,
+        }
+    }
+
+    /// None if the driver can't represent the frame.
+    pub fn to_frame<F: Frame>(self) -> Option<F> {
+        match self.remote {
+            true => F::new_remote(self.id, self.dlc),
+            false => F::new(self.id, &self.data[..self.dlc]),
+        }
+    }
+
+    /// Bytes past the data always read as zero. A data frame's DLC is derived from its length,
+    /// so a classic DLC of 9 to 15 is reported as the 8 bytes it carries. A remote frame keeps
+    /// the length it requests.
+    pub fn to_host_frame(self, channel: u8) -> HostFrame {
+        let flags = HostFrameFlags::new(&[]);
+        let can_id = match (self.id, self.remote) {
+            (Id::Standard(id), true) => {
+                HostCanId::new(id.as_raw() as u32, &[HostCanIdBits::RemoteFrame])
+            }
+            (Id::Standard(id), false) => HostCanId::new(id.as_raw() as u32, &[]),
+            (Id::Extended(id), true) => HostCanId::new(
+                id.as_raw(),
+                &[HostCanIdBits::RemoteFrame, HostCanIdBits::ExtendedId],
+            ),
+            (Id::Extended(id), false) => HostCanId::new(id.as_raw(), &[HostCanIdBits::ExtendedId]),
+        }
+        .expect("embedded-hal ids always fit their width");
+
+        let mut bytes: [u8; 64] = [0; 64];
+        bytes[..self.data.len()].copy_from_slice(&self.data);
+
+        let dlc = match self.remote {
+            true => self.dlc as u8,
+            false => len_to_dlc(core::cmp::min(self.dlc, self.data.len())),
+        };
+
+        HostFrame::new(None, can_id, dlc, channel, flags, bytes)
+    }
+
+    /// A remote frame carries no data, its DLC is the length requested from the responding node.
+    /// Any DLC from 0 to 8 is valid and whatever the host left in the data bytes is ignored,
+    /// rather than treated as an inconsistent frame.
+    pub fn from_host_frame(frame: &HostFrame) -> Result<Self, FrameConvertError> {
+        if frame.can_id.is_set(HostCanIdBits::ErrorFrame)
+            || frame.flags.is_set(HostFrameFlagsBits::Fd)
+            || frame.flags.is_set(HostFrameFlagsBits::Brs)
+            || frame.flags.is_set(HostFrameFlagsBits::Esi)
+        {
+            return Err(FrameConvertError::UnsupportedFlags);
+        }
+
+        if !frame.can_id.has_valid_width() {
+            defmt::warn!("standard id {=u32:x} wider than 11 bits", frame.can_id.id());
+            return Err(FrameConvertError::InvalidId);
+        }
+
+        let id = match frame.can_id.is_set(HostCanIdBits::ExtendedId) {
+            true => ExtendedId::new(frame.can_id.id()).map(Id::Extended),
+            false => StandardId::new(frame.can_id.id() as u16).map(Id::Standard),
+        }
+        .ok_or(FrameConvertError::InvalidId)?;
+
+        let dlc = frame.can_dlc as usize;
+
+        if dlc > 8 {
+            return Err(FrameConvertError::InvalidDlc);
+        }
+
+        let remote = frame.can_id.is_set(HostCanIdBits::RemoteFrame);
+        let mut data = [0; 8];
+
+        if !remote {
+            data[..dlc].copy_from_slice(&frame.bytes[..dlc]);
+        }
+
+        Ok(CanFrameData {
+            id,
+            remote,
+            dlc,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mcp2515::frame::CanFrame;
+    use scroll::{Pread, LE};
+
+    fn host_frame(can_id: HostCanId, dlc: u8, flags: HostFrameFlags) -> HostFrame {
+        HostFrame::new(None, can_id, dlc, 0, flags, [0; 64])
+    }
+
+    #[test]
+    fn accepts_a_classic_frame() {
+        let frame = host_frame(
+            HostCanId::new(0x123, &[]).unwrap(),
+            8,
+            HostFrameFlags::new(&[]),
+        );
+        let data = CanFrameData::from_host_frame(&frame).ok().unwrap();
+
+        assert!(data.id == Id::Standard(StandardId::new(0x123).unwrap()));
+        assert_eq!(data.dlc, 8);
+        assert!(!data.remote);
+    }
+
+    #[test]
+    fn standard_id_wider_than_11_bits_is_invalid_id() {
+        // HostCanId::new refuses such an id, the host can still send it.
+        let can_id = 0x800u32.to_le_bytes().pread_with(0, LE).unwrap();
+        let frame = host_frame(can_id, 0, HostFrameFlags::new(&[]));
+
+        assert!(CanFrameData::from_host_frame(&frame) == Err(FrameConvertError::InvalidId));
+    }
+
+    #[test]
+    fn dlc_above_8_is_invalid_dlc() {
+        for dlc in 9..=15 {
+            let frame = host_frame(
+                HostCanId::new(0x123, &[]).unwrap(),
+                dlc,
+                HostFrameFlags::new(&[]),
+            );
+
+            assert!(CanFrameData::from_host_frame(&frame) == Err(FrameConvertError::InvalidDlc));
+        }
+    }
+
+    #[test]
+    fn fd_and_error_frames_are_unsupported_flags() {
+        for bit in [
+            HostFrameFlagsBits::Fd,
+            HostFrameFlagsBits::Brs,
+            HostFrameFlagsBits::Esi,
+        ] {
+            let frame = host_frame(
+                HostCanId::new(0x123, &[]).unwrap(),
+                8,
+                HostFrameFlags::new(&[bit]),
+            );
+
+            assert!(
+                CanFrameData::from_host_frame(&frame) == Err(FrameConvertError::UnsupportedFlags)
+            );
+        }
+
+        let frame = host_frame(
+            HostCanId::new(0x004, &[HostCanIdBits::ErrorFrame]).unwrap(),
+            8,
+            HostFrameFlags::new(&[]),
+        );
+
+        assert!(CanFrameData::from_host_frame(&frame) == Err(FrameConvertError::UnsupportedFlags));
+    }
+
+    #[test]
+    fn host_dlc_follows_the_data_length() {
+        let mut data = CanFrameData {
+            id: Id::Standard(StandardId::new(0x123).unwrap()),
+            remote: false,
+            dlc: 5,
+            data: [0; 8],
+        };
+        assert_eq!(data.to_host_frame(0).can_dlc, 5);
+
+        // A classic DLC above 8 still carries 8 bytes.
+        data.dlc = 12;
+        assert_eq!(data.to_host_frame(0).can_dlc, 8);
+
+        data.remote = true;
+        data.dlc = 5;
+        assert_eq!(data.to_host_frame(0).can_dlc, 5);
+    }
+
+    #[test]
+    fn driver_frames_round_trip() {
+        let standard = Id::Standard(StandardId::new(0x123).unwrap());
+        let extended = Id::Extended(ExtendedId::new(0x1234_5678).unwrap());
+        let frames = [
+            CanFrame::new(standard, &[1, 2, 3]).unwrap(),
+            CanFrame::new(extended, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap(),
+            CanFrame::new(extended, &[]).unwrap(),
+            CanFrame::new_remote(standard, 4).unwrap(),
+        ];
+
+        for frame in frames {
+            let data = CanFrameData::from_frame(&frame);
+            let converted: CanFrame = data.to_frame().unwrap();
+
+            assert!(CanFrameData::from_frame(&converted) == data);
+            assert!(converted.id() == frame.id());
+            assert_eq!(converted.is_remote_frame(), frame.is_remote_frame());
+            assert_eq!(converted.dlc(), frame.dlc());
+            assert_eq!(converted.data(), frame.data());
+        }
+    }
+}
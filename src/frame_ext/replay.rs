@@ -0,0 +1,123 @@
+//! Replays a short candump capture through the HostFrame conversions and checks that every frame
+//! survives the round trip.
+
+use super::{CanFrameData, FromHostFrame, ToHostFrame};
+use crate::usbd_gs::HostCanIdBits;
+use embedded_hal::can::{ExtendedId, Frame, Id, StandardId};
+use mcp2515::frame::CanFrame;
+
+struct CapturedFrame {
+    id: u32,
+    extended: bool,
+    remote: bool,
+    dlc: usize,
+    data: [u8; 8],
+}
+
+const fn captured(id: u32, extended: bool, remote: bool, dlc: usize, data: [u8; 8]) -> CapturedFrame {
+    CapturedFrame {
+        id,
+        extended,
+        remote,
+        dlc,
+        data,
+    }
+}
+
+#[rustfmt::skip]
+const CAPTURE: [CapturedFrame; 12] = [
+    // OBD-II engine RPM request and response
+    captured(0x7df, false, false, 8, [0x02, 0x01, 0x0c, 0x55, 0x55, 0x55, 0x55, 0x55]),
+    captured(0x7e8, false, false, 8, [0x04, 0x41, 0x0c, 0x1a, 0xf8, 0x00, 0x00, 0x00]),
+    // J1939 EEC1 and CCVS broadcasts
+    captured(0x0cf00400, true, false, 8, [0xf0, 0x7d, 0x7d, 0x00, 0x00, 0x00, 0xf0, 0x7d]),
+    captured(0x18fef100, true, false, 8, [0xf3, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00, 0xff]),
+    // Remote requests, standard and extended
+    captured(0x123, false, true, 8, [0; 8]),
+    captured(0x1abcdef0, true, true, 2, [0; 8]),
+    captured(0x7ff, false, true, 0, [0; 8]),
+    // Extended remote requests with every id bit set and with none, next to the flag bits
+    captured(0x1fffffff, true, true, 8, [0; 8]),
+    captured(0x00000000, true, true, 1, [0; 8]),
+    // CANopen NMT start all nodes and a SYNC without data
+    captured(0x000, false, false, 2, [0x01, 0x00, 0, 0, 0, 0, 0, 0]),
+    captured(0x080, false, false, 0, [0; 8]),
+    // J1939 request-to-send keepalive with no data
+    captured(0x18ea00fe, true, false, 0, [0; 8]),
+];
+
+fn original(captured: &CapturedFrame) -> CanFrame {
+    let id = match captured.extended {
+        true => Id::Extended(ExtendedId::new(captured.id).unwrap()),
+        false => Id::Standard(StandardId::new(captured.id as u16).unwrap()),
+    };
+
+    match captured.remote {
+        true => CanFrame::new_remote(id, captured.dlc),
+        false => CanFrame::new(id, &captured.data[..captured.dlc]),
+    }
+    .unwrap()
+}
+
+fn same_frame(left: &CanFrame, right: &CanFrame) -> bool {
+    left.id() == right.id()
+        && left.is_remote_frame() == right.is_remote_frame()
+        && left.dlc() == right.dlc()
+        && left.data() == right.data()
+}
+
+#[test]
+fn every_frame_survives_the_round_trip() {
+    for (index, captured) in CAPTURE.iter().enumerate() {
+        let original = original(captured);
+        let host_frame = original.to_host_frame(0);
+        let frame = CanFrame::from_host_frame(&host_frame).ok().unwrap();
+
+        assert!(same_frame(&frame, &original), "frame {}", index);
+    }
+}
+
+// Both hops through the controller-neutral frame have to be lossless on their own.
+#[test]
+fn each_hop_is_lossless() {
+    for (index, captured) in CAPTURE.iter().enumerate() {
+        let original = original(captured);
+        let data = CanFrameData::from_frame(&original);
+
+        let frame: CanFrame = data.to_frame().unwrap();
+        assert!(same_frame(&frame, &original), "frame {}", index);
+
+        let host_data = CanFrameData::from_host_frame(&data.to_host_frame(0));
+        assert!(host_data == Ok(data), "frame {}", index);
+    }
+}
+
+// The remote and extended flags sit right above the 29 id bits and mustn't leak into them.
+#[test]
+fn id_and_flag_bits_stay_apart() {
+    for (index, captured) in CAPTURE.iter().enumerate() {
+        let can_id = original(captured).to_host_frame(0).can_id;
+
+        assert_eq!(can_id.id(), captured.id, "frame {}", index);
+        assert_eq!(can_id.is_set(HostCanIdBits::ExtendedId), captured.extended);
+        assert_eq!(can_id.is_set(HostCanIdBits::RemoteFrame), captured.remote);
+        assert!(!can_id.is_set(HostCanIdBits::ErrorFrame), "frame {}", index);
+    }
+}
+
+// The host sees exactly DLC bytes, the rest of the data has to read as zero. A remote frame has
+// no data at all, and a zero-DLC data frame reaches the host as a frame without data.
+#[test]
+fn host_sees_the_dlc_and_zeroed_padding() {
+    for (index, captured) in CAPTURE.iter().enumerate() {
+        let host_frame = original(captured).to_host_frame(0);
+        let data_length = match captured.remote {
+            true => 0,
+            false => captured.dlc,
+        };
+
+        assert_eq!(host_frame.can_dlc as usize, captured.dlc, "frame {}", index);
+        assert_eq!(host_frame.bytes[..data_length], captured.data[..data_length]);
+        assert!(host_frame.bytes[data_length..].iter().all(|byte| *byte == 0), "frame {}", index);
+    }
+}
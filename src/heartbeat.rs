@@ -0,0 +1,112 @@
+use super::usbd_gs::{HeartbeatConfig, HostFrame, HostFrameFlags};
+
+/// Schedules the periodic heartbeat frame. Times are timer ticks in microseconds.
+pub struct Heartbeat {
+    config: Option<HeartbeatConfig>,
+    channel: u8,
+    next_due: u64,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            config: None,
+            channel: 0,
+            next_due: 0,
+        }
+    }
+
+    /// Replaces the current heartbeat. The first frame is sent right away.
+    pub fn configure(&mut self, config: HeartbeatConfig, channel: u8, now: u64) {
+        self.channel = channel;
+        self.next_due = now;
+        self.config = match config.interval_ms {
+            0 => None,
+            _ => Some(config),
+        };
+    }
+
+    /// Returns the frame to send if a heartbeat is due. Nothing is sent while `may_send` is
+    /// false, like while the channel is stopped, and heartbeats missed in the meantime aren't
+    /// made up for once sending is possible again.
+    pub fn poll(&mut self, now: u64, may_send: bool) -> Option<HostFrame> {
+        let config = self.config.as_ref()?;
+
+        if !may_send || now < self.next_due {
+            return None;
+        }
+
+        self.next_due = now + config.interval_ms as u64 * 1000;
+
+        let mut bytes: [u8; 64] = [0; 64];
+        bytes[..8].copy_from_slice(&config.data);
+
+        Some(HostFrame::new(
+            None,
+            config.can_id,
+            config.can_dlc,
+            self.channel,
+            HostFrameFlags::new(&[]),
+            bytes,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scroll::{Pread, LE};
+
+    fn config(interval_ms: u32) -> HeartbeatConfig {
+        let mut bytes = [0u8; 20];
+        bytes[..4].copy_from_slice(&0x700u32.to_le_bytes());
+        bytes[4..8].copy_from_slice(&interval_ms.to_le_bytes());
+        bytes[8] = 1;
+        bytes[12] = 0x05;
+        bytes.pread_with(0, LE).unwrap()
+    }
+
+    #[test]
+    fn first_frame_is_sent_right_away_then_every_interval() {
+        let mut heartbeat = Heartbeat::new();
+        heartbeat.configure(config(100), 0, 1_000);
+
+        let frame = heartbeat.poll(1_000, true).unwrap();
+        assert_eq!(frame.can_id.id(), 0x700);
+        assert_eq!(frame.can_dlc, 1);
+        assert_eq!(frame.bytes[0], 0x05);
+
+        assert!(heartbeat.poll(100_999, true).is_none());
+        assert!(heartbeat.poll(101_000, true).is_some());
+        assert!(heartbeat.poll(150_000, true).is_none());
+    }
+
+    #[test]
+    fn nothing_is_sent_while_the_channel_is_stopped() {
+        let mut heartbeat = Heartbeat::new();
+        heartbeat.configure(config(100), 0, 0);
+
+        assert!(heartbeat.poll(0, false).is_none());
+        assert!(heartbeat.poll(500_000, false).is_none());
+    }
+
+    #[test]
+    fn missed_heartbeats_arent_made_up_for() {
+        let mut heartbeat = Heartbeat::new();
+        heartbeat.configure(config(100), 0, 0);
+        heartbeat.poll(0, true).unwrap();
+
+        assert!(heartbeat.poll(1_000_000, true).is_some());
+        assert!(heartbeat.poll(1_000_001, true).is_none());
+        assert!(heartbeat.poll(1_100_000, true).is_some());
+    }
+
+    #[test]
+    fn zero_interval_disables_it() {
+        let mut heartbeat = Heartbeat::new();
+        heartbeat.configure(config(100), 0, 0);
+        heartbeat.configure(config(0), 0, 0);
+
+        assert!(heartbeat.poll(0, true).is_none());
+    }
+}
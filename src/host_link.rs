@@ -0,0 +1,544 @@
+//! Moves frames between the host port, the queues and the controller. It only relies on the
+//! HostPort and CanBus traits, so the servicing logic doesn't depend on the USB class or the
+//! controller driver that implement them.
+
+use super::controller::CanBus;
+use super::frame_ext::{FrameConvertError, FromHostFrame};
+use super::host_queue::HostQueue;
+use super::id_remap::IdRemap;
+use super::usbd_gs::{FlowControl, GsUsbPort, HostFrame, HostFrameFlagsBits, Stats};
+use core::fmt::Debug;
+use mcp2515::{error::Error, frame::CanFrame};
+use ringbuffer::*;
+use usb_device::{class_prelude::UsbBus, device::UsbDeviceState, UsbError};
+
+/// The host side of the device: where frames to transmit come from and echoes and received
+/// frames go.
+pub trait HostPort {
+    /// Next frame from the host, WouldBlock if none has arrived.
+    fn read_frame(&mut self) -> usb_device::Result<HostFrame>;
+
+    /// Hands a frame to the host, WouldBlock while the previous one is still being sent.
+    fn write_frame(&mut self, frame: &HostFrame) -> usb_device::Result<()>;
+
+    /// Whether the host configured the device, so its endpoints are serviced.
+    fn is_configured(&self) -> bool;
+
+    fn flow_control(&self) -> FlowControl;
+
+    fn delivery_paused(&self) -> bool;
+
+    fn stats_mut(&mut self) -> &mut Stats;
+
+    #[cfg(feature = "tx-depth-hint")]
+    fn set_tx_depth_hint(&mut self, depth: usize);
+}
+
+impl<B: UsbBus, const C: usize> HostPort for GsUsbPort<'_, B, C> {
+    fn read_frame(&mut self) -> usb_device::Result<HostFrame> {
+        GsUsbPort::read_frame(self)
+    }
+
+    fn write_frame(&mut self, frame: &HostFrame) -> usb_device::Result<()> {
+        GsUsbPort::write_frame(self, frame)
+    }
+
+    fn is_configured(&self) -> bool {
+        GsUsbPort::usb_state(self) == UsbDeviceState::Configured
+    }
+
+    fn flow_control(&self) -> FlowControl {
+        GsUsbPort::flow_control(self)
+    }
+
+    fn delivery_paused(&self) -> bool {
+        GsUsbPort::delivery_paused(self)
+    }
+
+    fn stats_mut(&mut self) -> &mut Stats {
+        GsUsbPort::stats_mut(self)
+    }
+
+    #[cfg(feature = "tx-depth-hint")]
+    fn set_tx_depth_hint(&mut self, depth: usize) {
+        GsUsbPort::set_tx_depth_hint(self, depth)
+    }
+}
+
+/// Takes every frame the outbox has room for from the port, with ids remapped, and hands the
+/// next frame of the inbox to the port. Nothing moves until the host configured the device, or
+/// while it's suspended: the endpoints aren't enabled then and only return errors.
+///
+/// With flow control accepting every frame, the oldest frame of a full outbox makes room and is
+/// echoed as failed at `now`.
+pub fn exchange<P: HostPort, const I: usize, const O: usize, const R: usize>(
+    port: &mut P,
+    inbox: &mut HostQueue<I>,
+    outbox: &mut ConstGenericRingBuffer<HostFrame, O>,
+    id_remap: &IdRemap<R>,
+    now: u64,
+) {
+    if !port.is_configured() {
+        return;
+    }
+
+    // A transfer can carry several frames, take all of them the queue has room for. Frames stay
+    // with the host while the echoes of those already taken would fill the echo queue, every
+    // one of them is echoed and none of the echoes may be lost.
+    while outbox.len() < inbox.echo_room() && port.flow_control().accepts_frame(outbox.is_full()) {
+        match port.read_frame() {
+            Ok(mut host_frame) => {
+                // Only when flow control accepts every frame. The host still gets an echo for
+                // the frame that's dropped, so it doesn't wait for it and frees its echo id.
+                if outbox.is_full() {
+                    let stats = port.stats_mut();
+                    stats.tx_busy_drops = stats.tx_busy_drops.wrapping_add(1);
+
+                    if let Some(mut evicted) = outbox.dequeue() {
+                        evicted.flags.set(HostFrameFlagsBits::Overflow);
+                        inbox.push_echo(evicted, now);
+                    }
+                }
+
+                host_frame.can_id = id_remap.translate(host_frame.can_id);
+                outbox.push(host_frame);
+            }
+            Err(UsbError::ParseError) => {}
+            Err(_) => break,
+        }
+    }
+
+    #[cfg(feature = "tx-depth-hint")]
+    port.set_tx_depth_hint(outbox.len());
+
+    // While paused, frames wait in the inbox. Once it's full the oldest are dropped, the same as
+    // when the host stops reading.
+    if let Some(host_frame) = inbox.peek().filter(|_| !port.delivery_paused()) {
+        match port.write_frame(host_frame) {
+            Ok(_) => inbox.skip(),
+            Err(UsbError::WouldBlock) => {}
+            Err(_) => inbox.skip(),
+        };
+    }
+}
+
+/// What `transmit` did with the frame at the head of the outbox.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TxOutcome {
+    /// The outbox is empty.
+    Idle,
+    /// The controller has no free transmit buffer, the frame is tried again next time.
+    Busy,
+    /// The frame was loaded into the transmit buffer and stays at the head of the outbox, its
+    /// outcome is tracked by the caller.
+    Loaded(usize),
+    /// The frame was handed to the controller and echoed.
+    Sent,
+    /// The controller refused the frame, it was echoed as failed.
+    Failed,
+    /// The controller can't send the frame, it was echoed as failed.
+    Dropped(FrameConvertError),
+}
+
+/// Hands the frame at the head of the outbox to the controller. With `track`, a loaded frame
+/// stays queued for the caller to echo once its transmission completed, otherwise it's echoed
+/// right away.
+pub fn transmit<C, SPIE, CSE, const I: usize, const O: usize>(
+    bus: &mut C,
+    outbox: &mut ConstGenericRingBuffer<HostFrame, O>,
+    inbox: &mut HostQueue<I>,
+    track: bool,
+    now: u64,
+) -> TxOutcome
+where
+    C: CanBus<Error = Error<SPIE, CSE>>,
+    SPIE: Debug,
+    CSE: Debug,
+{
+    let host_frame = match outbox.peek() {
+        Some(host_frame) => host_frame,
+        None => return TxOutcome::Idle,
+    };
+
+    let outcome = match CanFrame::from_host_frame(host_frame) {
+        Ok(frame) => match bus.send(&frame) {
+            Ok(buffer) if track => return TxOutcome::Loaded(buffer),
+            Ok(_) => TxOutcome::Sent,
+            Err(Error::TxBusy) | Err(Error::NewModeTimeout) => return TxOutcome::Busy,
+            Err(_) => TxOutcome::Failed,
+        },
+        Err(error) => TxOutcome::Dropped(error),
+    };
+
+    // A frame that can't be sent is echoed as failed, so the host frees the echo id instead of
+    // waiting for an echo that never comes.
+    if let Some(mut host_frame) = outbox.dequeue() {
+        if outcome != TxOutcome::Sent {
+            host_frame.flags.set(HostFrameFlagsBits::Overflow);
+        }
+
+        inbox.push_echo(host_frame, now);
+    }
+
+    outcome
+}
+
+/// Counts the outcome of reading a frame from the controller in `stats` and returns the frame,
+/// if one was read. A frame the driver can't decode is dropped, the controller already freed its
+/// receive buffer.
+pub fn count_received<SPIE: Debug, CSE: Debug>(
+    stats: &mut Stats,
+    received: Result<Option<CanFrame>, Error<SPIE, CSE>>,
+) -> Option<CanFrame> {
+    match received {
+        Ok(Some(frame)) => {
+            stats.rx_frames = stats.rx_frames.wrapping_add(1);
+            Some(frame)
+        }
+        Ok(None) => None,
+        Err(Error::InvalidDlc) => {
+            stats.dlc_mismatches = stats.dlc_mismatches.wrapping_add(1);
+            None
+        }
+        Err(_) => {
+            defmt::warn!("discarded a received frame the driver can't decode");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_ext::{CanFrameData, ToHostFrame};
+    use crate::usbd_gs::{HostCanId, HostFrameFlags, RxOverflowPolicy};
+    use embedded_hal::can::{Id, StandardId};
+
+    /// A configured host that sends `from_host` and records what it's sent back as echo id,
+    /// CAN id and overflow flag.
+    struct MockPort {
+        from_host: Vec<HostFrame>,
+        to_host: Vec<(u32, u32, bool)>,
+        configured: bool,
+        /// Whether the host reads what the port writes, WouldBlock otherwise.
+        reading: bool,
+        flow_control: FlowControl,
+        stats: Stats,
+    }
+
+    impl MockPort {
+        fn new(from_host: Vec<HostFrame>) -> Self {
+            MockPort {
+                from_host,
+                to_host: Vec::new(),
+                configured: true,
+                reading: true,
+                flow_control: FlowControl::HoldWhenFull,
+                stats: Stats::default(),
+            }
+        }
+    }
+
+    impl HostPort for MockPort {
+        fn read_frame(&mut self) -> usb_device::Result<HostFrame> {
+            match self.from_host.is_empty() {
+                true => Err(UsbError::WouldBlock),
+                false => Ok(self.from_host.remove(0)),
+            }
+        }
+
+        fn write_frame(&mut self, frame: &HostFrame) -> usb_device::Result<()> {
+            if !self.reading {
+                return Err(UsbError::WouldBlock);
+            }
+
+            let overflow = frame.flags.is_set(HostFrameFlagsBits::Overflow);
+            self.to_host.push((frame.echo_id, frame.can_id.id(), overflow));
+            Ok(())
+        }
+
+        fn is_configured(&self) -> bool {
+            self.configured
+        }
+
+        fn flow_control(&self) -> FlowControl {
+            self.flow_control
+        }
+
+        fn delivery_paused(&self) -> bool {
+            false
+        }
+
+        fn stats_mut(&mut self) -> &mut Stats {
+            &mut self.stats
+        }
+
+        #[cfg(feature = "tx-depth-hint")]
+        fn set_tx_depth_hint(&mut self, _depth: usize) {}
+    }
+
+    /// A controller with `free` transmit buffers that completes every transmission right away,
+    /// looping the frames back as received when `loopback` is set.
+    struct MockBus {
+        free: usize,
+        loopback: bool,
+        sent: Vec<CanFrameData>,
+        received: Vec<CanFrameData>,
+    }
+
+    impl MockBus {
+        fn new(free: usize) -> Self {
+            MockBus {
+                free,
+                loopback: false,
+                sent: Vec::new(),
+                received: Vec::new(),
+            }
+        }
+    }
+
+    impl CanBus for MockBus {
+        type Error = Error<(), ()>;
+
+        fn send(&mut self, frame: &CanFrame) -> Result<usize, Self::Error> {
+            if self.free == 0 {
+                return Err(Error::TxBusy);
+            }
+
+            let data = CanFrameData::from_frame(frame);
+            self.sent.push(data);
+
+            if self.loopback {
+                self.received.push(data);
+            }
+
+            Ok(self.free - 1)
+        }
+
+        fn receive(&mut self) -> Result<Option<CanFrame>, Self::Error> {
+            match self.received.is_empty() {
+                true => Ok(None),
+                false => Ok(self.received.remove(0).to_frame()),
+            }
+        }
+    }
+
+    fn host_frame(echo_id: u32, id: u32, flags: HostFrameFlags) -> HostFrame {
+        let can_id = HostCanId::new(id, &[]).unwrap();
+        HostFrame::new(Some(echo_id), can_id, 2, 0, flags, [0; 64])
+    }
+
+    fn sent_ids(bus: &MockBus) -> Vec<u32> {
+        bus.sent
+            .iter()
+            .map(|data| data.to_host_frame(0).can_id.id())
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_bridged_both_ways() {
+        let mut port = MockPort::new(vec![
+            host_frame(1, 0x100, HostFrameFlags::new(&[])),
+            host_frame(2, 0x200, HostFrameFlags::new(&[])),
+        ]);
+        let mut bus = MockBus::new(3);
+        bus.loopback = true;
+
+        let mut inbox = HostQueue::<4>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 4>::new();
+        let id_remap = IdRemap::<1>::new();
+
+        exchange(&mut port, &mut inbox, &mut outbox, &id_remap, 0);
+        assert_eq!(outbox.len(), 2);
+
+        let sent = transmit(&mut bus, &mut outbox, &mut inbox, false, 10);
+        assert!(sent == TxOutcome::Sent);
+        let sent = transmit(&mut bus, &mut outbox, &mut inbox, false, 11);
+        assert!(sent == TxOutcome::Sent);
+        assert!(transmit(&mut bus, &mut outbox, &mut inbox, false, 12) == TxOutcome::Idle);
+        assert_eq!(sent_ids(&bus), [0x100, 0x200]);
+
+        let mut now = 20;
+
+        while let Ok(Some(frame)) = bus.receive() {
+            let frame = frame.to_host_frame(0);
+            inbox.deliver_received(frame, now, RxOverflowPolicy::OverwriteOldest);
+            now += 1;
+        }
+
+        for _ in 0..8 {
+            exchange(&mut port, &mut inbox, &mut outbox, &id_remap, 0);
+        }
+
+        let received = u32::MAX;
+        let echoes_then_received = [
+            (1, 0x100, false),
+            (2, 0x200, false),
+            (received, 0x100, false),
+            (received, 0x200, false),
+        ];
+        assert_eq!(port.to_host, echoes_then_received);
+    }
+
+    #[test]
+    fn frame_waits_for_a_free_transmit_buffer() {
+        let mut port = MockPort::new(vec![host_frame(1, 0x100, HostFrameFlags::new(&[]))]);
+        let mut bus = MockBus::new(0);
+
+        let mut inbox = HostQueue::<4>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 4>::new();
+        let id_remap = IdRemap::<1>::new();
+
+        exchange(&mut port, &mut inbox, &mut outbox, &id_remap, 0);
+        assert!(transmit(&mut bus, &mut outbox, &mut inbox, false, 10) == TxOutcome::Busy);
+        assert_eq!(outbox.len(), 1);
+        assert!(inbox.peek().is_none());
+
+        bus.free = 1;
+        assert!(transmit(&mut bus, &mut outbox, &mut inbox, false, 20) == TxOutcome::Sent);
+
+        exchange(&mut port, &mut inbox, &mut outbox, &id_remap, 0);
+        assert_eq!(port.to_host, [(1, 0x100, false)]);
+    }
+
+    #[test]
+    fn frame_the_controller_cant_send_is_echoed_as_failed() {
+        let fd = HostFrameFlags::new(&[HostFrameFlagsBits::Fd]);
+        let mut port = MockPort::new(vec![host_frame(1, 0x100, fd)]);
+        let mut bus = MockBus::new(3);
+
+        let mut inbox = HostQueue::<4>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 4>::new();
+        let id_remap = IdRemap::<1>::new();
+
+        exchange(&mut port, &mut inbox, &mut outbox, &id_remap, 0);
+        let outcome = transmit(&mut bus, &mut outbox, &mut inbox, false, 10);
+        assert!(outcome == TxOutcome::Dropped(FrameConvertError::UnsupportedFlags));
+        assert!(bus.sent.is_empty());
+
+        exchange(&mut port, &mut inbox, &mut outbox, &id_remap, 0);
+        assert_eq!(port.to_host, [(1, 0x100, true)]);
+    }
+
+    #[test]
+    fn tracked_frame_stays_queued_until_the_caller_echoes_it() {
+        let mut port = MockPort::new(vec![host_frame(1, 0x100, HostFrameFlags::new(&[]))]);
+        let mut bus = MockBus::new(3);
+
+        let mut inbox = HostQueue::<4>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 4>::new();
+        let id_remap = IdRemap::<1>::new();
+
+        exchange(&mut port, &mut inbox, &mut outbox, &id_remap, 0);
+        assert!(transmit(&mut bus, &mut outbox, &mut inbox, true, 10) == TxOutcome::Loaded(2));
+        assert_eq!(outbox.len(), 1);
+        assert!(inbox.peek().is_none());
+    }
+
+    #[test]
+    fn nothing_moves_before_the_host_configured_the_device() {
+        let mut port = MockPort::new(vec![host_frame(1, 0x100, HostFrameFlags::new(&[]))]);
+        port.configured = false;
+
+        let mut inbox = HostQueue::<4>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 4>::new();
+        inbox.push_echo(host_frame(2, 0x200, HostFrameFlags::new(&[])), 0);
+
+        exchange(&mut port, &mut inbox, &mut outbox, &IdRemap::<1>::new(), 0);
+
+        assert!(outbox.is_empty());
+        assert!(port.to_host.is_empty());
+    }
+
+    #[test]
+    fn hold_when_full_leaves_frames_with_the_host() {
+        let frames = (1..=3)
+            .map(|id| host_frame(id, 0x100 + id, HostFrameFlags::new(&[])))
+            .collect();
+        let mut port = MockPort::new(frames);
+
+        let mut inbox = HostQueue::<4>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 2>::new();
+
+        exchange(&mut port, &mut inbox, &mut outbox, &IdRemap::<1>::new(), 0);
+
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(port.from_host.len(), 1);
+        assert!(inbox.peek().is_none());
+    }
+
+    #[test]
+    fn always_accept_echoes_the_frame_it_drops_as_failed() {
+        let frames = (1..=3)
+            .map(|id| host_frame(id, 0x100 + id, HostFrameFlags::new(&[])))
+            .collect();
+        let mut port = MockPort::new(frames);
+        port.flow_control = FlowControl::AlwaysAccept;
+
+        let mut inbox = HostQueue::<4>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 2>::new();
+
+        exchange(&mut port, &mut inbox, &mut outbox, &IdRemap::<1>::new(), 0);
+
+        assert!(port.from_host.is_empty());
+        assert_eq!(port.stats.tx_busy_drops, 1);
+        let queued: Vec<u32> = outbox.iter().map(|frame| frame.echo_id).collect();
+        assert_eq!(queued, [2, 3]);
+        assert_eq!(port.to_host, [(1, 0x101, true)]);
+    }
+
+    #[test]
+    fn no_echo_is_lost_while_the_host_stops_reading() {
+        let frames = (1..=10)
+            .map(|id| host_frame(id, 0x100, HostFrameFlags::new(&[])))
+            .collect();
+        let mut port = MockPort::new(frames);
+        port.flow_control = FlowControl::AlwaysAccept;
+        port.reading = false;
+        let mut bus = MockBus::new(3);
+
+        let mut inbox = HostQueue::<2>::new();
+        let mut outbox = ConstGenericRingBuffer::<HostFrame, 4>::new();
+        let id_remap = IdRemap::<1>::new();
+
+        for now in 0..20 {
+            exchange(&mut port, &mut inbox, &mut outbox, &id_remap, now);
+            transmit(&mut bus, &mut outbox, &mut inbox, false, now);
+        }
+
+        // Two frames were taken and echoed, the rest waits with the host.
+        assert_eq!(bus.sent.len(), 2);
+        assert_eq!(port.from_host.len(), 8);
+        assert_eq!(port.stats.tx_busy_drops, 0);
+
+        port.reading = true;
+
+        for now in 20..60 {
+            exchange(&mut port, &mut inbox, &mut outbox, &id_remap, now);
+            transmit(&mut bus, &mut outbox, &mut inbox, false, now);
+        }
+
+        let echoes: Vec<(u32, u32, bool)> = (1..=10).map(|id| (id, 0x100, false)).collect();
+        assert_eq!(port.to_host, echoes);
+    }
+
+    #[test]
+    fn frame_with_an_invalid_dlc_is_counted_as_a_dlc_mismatch() {
+        let mut stats = Stats::default();
+        let frame = CanFrameData {
+            id: Id::Standard(StandardId::new(0x123).unwrap()),
+            remote: false,
+            dlc: 2,
+            data: [0; 8],
+        }
+        .to_frame();
+
+        assert!(count_received::<(), ()>(&mut stats, Ok(frame)).is_some());
+        assert!(count_received::<(), ()>(&mut stats, Ok(None)).is_none());
+        assert!(count_received::<(), ()>(&mut stats, Err(Error::InvalidDlc)).is_none());
+        assert!(count_received::<(), ()>(&mut stats, Err(Error::InvalidFrameId)).is_none());
+
+        assert_eq!(stats.rx_frames, 1);
+        assert_eq!(stats.dlc_mismatches, 1);
+    }
+}
@@ -0,0 +1,253 @@
+use super::usbd_gs::{HostFrame, HostFrameFlagsBits, RxOverflowPolicy};
+use ringbuffer::*;
+
+/// Frames waiting to be sent to the host. Frames received from the bus and echoes of transmitted
+/// frames are kept in separate buffers, so a burst of one can't evict the other.
+///
+/// Every frame carries the timer value at which it was captured: when it was read from the
+/// controller, or when its transmission was confirmed. When both buffers have frames pending,
+/// the earlier capture goes first, so the host sees the frames in bus order as far as the main
+/// loop could tell. On equal timestamps the received frame goes first.
+pub struct HostQueue<const N: usize> {
+    received: ConstGenericRingBuffer<(u64, HostFrame), N>,
+    echoes: ConstGenericRingBuffer<(u64, HostFrame), N>,
+    echo: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Received,
+    Echo,
+}
+
+impl<const N: usize> HostQueue<N> {
+    pub fn new() -> Self {
+        HostQueue {
+            received: ConstGenericRingBuffer::new(),
+            echoes: ConstGenericRingBuffer::new(),
+            echo: true,
+        }
+    }
+
+    /// Switches echoing of transmitted frames, failed ones included. Echoes already queued are
+    /// still delivered.
+    pub fn set_echo(&mut self, on: bool) {
+        self.echo = on;
+    }
+
+    pub fn received_full(&self) -> bool {
+        self.received.is_full()
+    }
+
+    /// Queues a received frame, dropping the oldest one if the queue is full.
+    pub fn push_received(&mut self, frame: HostFrame, captured_at: u64) {
+        self.received.push((captured_at, frame));
+    }
+
+    /// Queues a received frame under the host's overflow policy. Once the queue is full the
+    /// frame is either dropped or replaces the oldest one, flagged as an overflow. StopReading
+    /// relies on the caller leaving frames in the controller while `received_full`, a frame
+    /// arriving anyway replaces the oldest one. Returns whether a frame was lost.
+    pub fn deliver_received(
+        &mut self,
+        mut frame: HostFrame,
+        captured_at: u64,
+        policy: RxOverflowPolicy,
+    ) -> bool {
+        let full = self.received_full();
+
+        match policy {
+            RxOverflowPolicy::DropNewest if full => {}
+            _ => {
+                if full {
+                    frame.flags.set(HostFrameFlagsBits::Overflow);
+                }
+
+                self.push_received(frame, captured_at);
+            }
+        }
+
+        full
+    }
+
+    /// Number of echoes that can still be queued. Every frame taken from the host is echoed once,
+    /// so no more frames are taken than there is room for their echoes.
+    pub fn echo_room(&self) -> usize {
+        N - self.echoes.len()
+    }
+
+    /// Queues the echo of a transmitted frame, dropped while echoing is off. The caller keeps
+    /// room for it, see `echo_room`.
+    pub fn push_echo(&mut self, frame: HostFrame, captured_at: u64) {
+        if self.echo {
+            debug_assert!(!self.echoes.is_full(), "echo queued without room for it");
+            self.echoes.push((captured_at, frame));
+        }
+    }
+
+    pub fn peek(&self) -> Option<&HostFrame> {
+        let (_, frame) = match self.next_source()? {
+            Source::Received => self.received.peek(),
+            Source::Echo => self.echoes.peek(),
+        }?;
+
+        Some(frame)
+    }
+
+    pub fn skip(&mut self) {
+        match self.next_source() {
+            Some(Source::Received) => self.received.skip(),
+            Some(Source::Echo) => self.echoes.skip(),
+            None => {}
+        }
+    }
+
+    fn next_source(&self) -> Option<Source> {
+        match (self.received.peek(), self.echoes.peek()) {
+            (None, None) => None,
+            (Some(_), None) => Some(Source::Received),
+            (None, Some(_)) => Some(Source::Echo),
+            (Some((received_at, _)), Some((echoed_at, _))) if echoed_at < received_at => {
+                Some(Source::Echo)
+            }
+            (Some(_), Some(_)) => Some(Source::Received),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbd_gs::{HostCanId, HostFrameFlags};
+
+    fn frame(id: u32) -> HostFrame {
+        let can_id = HostCanId::new(id, &[]).unwrap();
+        HostFrame::new(None, can_id, 0, 0, HostFrameFlags::new(&[]), [0; 64])
+    }
+
+    fn drain<const N: usize>(queue: &mut HostQueue<N>) -> Vec<u32> {
+        let mut ids = Vec::new();
+
+        while let Some(frame) = queue.peek() {
+            ids.push(frame.can_id.id());
+            queue.skip();
+        }
+
+        ids
+    }
+
+    #[test]
+    fn echoes_dont_evict_received_frames() {
+        let mut queue = HostQueue::<2>::new();
+        queue.push_received(frame(1), 10);
+        queue.push_received(frame(2), 20);
+
+        for id in 100..102 {
+            queue.push_echo(frame(id), 30);
+        }
+
+        assert_eq!(queue.echo_room(), 0);
+        assert_eq!(drain(&mut queue), [1, 2, 100, 101]);
+        assert_eq!(queue.echo_room(), 2);
+    }
+
+    #[test]
+    fn received_frames_dont_evict_echoes() {
+        let mut queue = HostQueue::<2>::new();
+        queue.push_echo(frame(100), 10);
+
+        for id in 1..10 {
+            queue.push_received(frame(id), 20);
+        }
+
+        assert_eq!(drain(&mut queue), [100, 8, 9]);
+    }
+
+    #[test]
+    fn frames_leave_in_capture_order() {
+        let mut queue = HostQueue::<4>::new();
+        queue.push_received(frame(1), 10);
+        queue.push_echo(frame(100), 5);
+        queue.push_received(frame(2), 30);
+        queue.push_echo(frame(101), 20);
+
+        assert_eq!(drain(&mut queue), [100, 1, 101, 2]);
+    }
+
+    #[test]
+    fn received_frame_goes_first_on_equal_timestamps() {
+        let mut queue = HostQueue::<4>::new();
+        queue.push_echo(frame(100), 10);
+        queue.push_received(frame(1), 10);
+
+        assert_eq!(drain(&mut queue), [1, 100]);
+    }
+
+    /// Delivers frames 1 to `count` to a host that never reads, returning the lost count.
+    fn flood<const N: usize>(
+        queue: &mut HostQueue<N>,
+        count: u32,
+        policy: RxOverflowPolicy,
+    ) -> u32 {
+        (1..=count)
+            .map(|id| queue.deliver_received(frame(id), id as u64, policy) as u32)
+            .sum()
+    }
+
+    fn overflowed<const N: usize>(queue: &HostQueue<N>) -> bool {
+        queue
+            .peek()
+            .is_some_and(|frame| frame.flags.is_set(HostFrameFlagsBits::Overflow))
+    }
+
+    #[test]
+    fn overwrite_oldest_keeps_the_newest_frames_flagged() {
+        let mut queue = HostQueue::<2>::new();
+
+        assert_eq!(flood(&mut queue, 5, RxOverflowPolicy::OverwriteOldest), 3);
+        assert!(overflowed(&queue));
+        assert_eq!(drain(&mut queue), [4, 5]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_oldest_frames() {
+        let mut queue = HostQueue::<2>::new();
+
+        assert_eq!(flood(&mut queue, 5, RxOverflowPolicy::DropNewest), 3);
+        assert!(!overflowed(&queue));
+        assert_eq!(drain(&mut queue), [1, 2]);
+    }
+
+    #[test]
+    fn stop_reading_loses_nothing_while_the_caller_holds_back() {
+        let mut queue = HostQueue::<2>::new();
+        let policy = RxOverflowPolicy::StopReading;
+
+        for id in 1..=5 {
+            if !queue.received_full() {
+                assert!(!queue.deliver_received(frame(id), id as u64, policy));
+            }
+        }
+
+        assert_eq!(drain(&mut queue), [1, 2]);
+    }
+
+    #[test]
+    fn nothing_is_lost_while_the_queue_has_room() {
+        let mut queue = HostQueue::<4>::new();
+
+        assert_eq!(flood(&mut queue, 4, RxOverflowPolicy::DropNewest), 0);
+        assert!(!overflowed(&queue));
+    }
+
+    #[test]
+    fn echoes_are_dropped_while_echo_is_off() {
+        let mut queue = HostQueue::<4>::new();
+        queue.push_echo(frame(100), 10);
+        queue.set_echo(false);
+        queue.push_echo(frame(101), 20);
+        queue.push_received(frame(1), 30);
+
+        assert_eq!(drain(&mut queue), [100, 1]);
+    }
+}
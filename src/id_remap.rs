@@ -0,0 +1,109 @@
+use super::usbd_gs::{HostCanId, IdRemapEntry};
+
+/// Translation table for CAN ids of frames sent by the host. Ids without a matching entry pass
+/// through unchanged. Standard and extended ids are distinct, so 0x123 and the extended 0x123
+/// can be mapped independently.
+pub struct IdRemap<const N: usize> {
+    entries: [Option<(HostCanId, HostCanId)>; N],
+}
+
+impl<const N: usize> IdRemap<N> {
+    pub fn new() -> Self {
+        IdRemap { entries: [None; N] }
+    }
+
+    /// Sets or clears an entry. Returns false if the index is out of range.
+    pub fn set(&mut self, entry: &IdRemapEntry) -> bool {
+        match self.entries.get_mut(entry.index as usize) {
+            Some(slot) if entry.from.same_id(&entry.to) => {
+                *slot = None;
+                true
+            }
+            Some(slot) => {
+                *slot = Some((entry.from, entry.to));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn translate(&self, can_id: HostCanId) -> HostCanId {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|(from, _)| from.same_id(&can_id))
+            .map(|(_, to)| can_id.with_id_of(to))
+            .unwrap_or(can_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbd_gs::HostCanIdBits;
+
+    fn standard(id: u32) -> HostCanId {
+        HostCanId::new(id, &[]).unwrap()
+    }
+
+    fn extended(id: u32) -> HostCanId {
+        HostCanId::new(id, &[HostCanIdBits::ExtendedId]).unwrap()
+    }
+
+    fn entry(index: u32, from: HostCanId, to: HostCanId) -> IdRemapEntry {
+        IdRemapEntry { index, from, to }
+    }
+
+    #[test]
+    fn unmapped_ids_pass_through() {
+        let remap = IdRemap::<4>::new();
+        assert!(remap.translate(standard(0x123)) == standard(0x123));
+        assert!(remap.translate(extended(0x123)) == extended(0x123));
+    }
+
+    #[test]
+    fn standard_id_is_remapped() {
+        let mut remap = IdRemap::<4>::new();
+        assert!(remap.set(&entry(0, standard(0x123), standard(0x456))));
+
+        assert!(remap.translate(standard(0x123)) == standard(0x456));
+        assert!(remap.translate(extended(0x123)) == extended(0x123));
+    }
+
+    #[test]
+    fn extended_ids_are_remapped_across_widths() {
+        let mut remap = IdRemap::<4>::new();
+        remap.set(&entry(0, extended(0x1234567), extended(0x1abcdef)));
+        remap.set(&entry(1, standard(0x7ff), extended(0x18daf110)));
+
+        assert!(remap.translate(extended(0x1234567)) == extended(0x1abcdef));
+        assert!(remap.translate(standard(0x7ff)) == extended(0x18daf110));
+    }
+
+    #[test]
+    fn remote_flag_survives_remapping() {
+        let mut remap = IdRemap::<4>::new();
+        remap.set(&entry(0, standard(0x100), standard(0x200)));
+
+        let remote = HostCanId::new(0x100, &[HostCanIdBits::RemoteFrame]).unwrap();
+        let translated = remap.translate(remote);
+
+        assert_eq!(translated.id(), 0x200);
+        assert!(translated.is_set(HostCanIdBits::RemoteFrame));
+    }
+
+    #[test]
+    fn entry_mapping_an_id_to_itself_clears_it() {
+        let mut remap = IdRemap::<4>::new();
+        remap.set(&entry(0, standard(0x100), standard(0x200)));
+        remap.set(&entry(0, standard(0x100), standard(0x100)));
+
+        assert!(remap.translate(standard(0x100)) == standard(0x100));
+    }
+
+    #[test]
+    fn out_of_range_index_is_refused() {
+        let mut remap = IdRemap::<4>::new();
+        assert!(!remap.set(&entry(4, standard(0x100), standard(0x200))));
+    }
+}
@@ -0,0 +1,139 @@
+//! Per-section timing of the main loop for performance tuning, enabled with the `loop-metrics`
+//! feature and reported over RTT once a second.
+
+/// Time between two reports, in microseconds.
+const REPORT_INTERVAL_US: u64 = 1_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Section {
+    /// Moving frames and events in and out of the USB port. The port itself is polled from the
+    /// interrupt, that time shows up as gaps in the others.
+    Usb,
+    /// Reading a received frame from the controller.
+    Rx,
+    /// Loading and confirming frames to send.
+    Tx,
+}
+
+/// Durations of one section in microseconds since the last report.
+#[derive(Clone, Copy)]
+pub struct Metric {
+    pub min: u64,
+    pub max: u64,
+    total: u64,
+    count: u32,
+}
+
+impl Metric {
+    const fn new() -> Self {
+        Metric {
+            min: u64::MAX,
+            max: 0,
+            total: 0,
+            count: 0,
+        }
+    }
+
+    pub fn record(&mut self, duration: u64) {
+        self.min = core::cmp::min(self.min, duration);
+        self.max = core::cmp::max(self.max, duration);
+        self.total = self.total.saturating_add(duration);
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// Mean duration, None before anything was recorded.
+    pub fn average(&self) -> Option<u64> {
+        match self.count {
+            0 => None,
+            count => Some(self.total / count as u64),
+        }
+    }
+}
+
+pub struct LoopMetrics {
+    sections: [Metric; 3],
+    next_report: u64,
+}
+
+impl LoopMetrics {
+    pub fn new() -> Self {
+        LoopMetrics {
+            sections: [Metric::new(); 3],
+            next_report: REPORT_INTERVAL_US,
+        }
+    }
+
+    pub fn record(&mut self, section: Section, duration: u64) {
+        self.sections[section as usize].record(duration);
+    }
+
+    /// Logs min/avg/max of every section once per interval and starts over.
+    pub fn report(&mut self, now: u64) {
+        if now < self.next_report {
+            return;
+        }
+
+        for section in [Section::Usb, Section::Rx, Section::Tx] {
+            let metric = &self.sections[section as usize];
+
+            if let Some(average) = metric.average() {
+                defmt::info!(
+                    "{}: min {} us, avg {} us, max {} us over {} iterations",
+                    section,
+                    metric.min,
+                    average,
+                    metric.max,
+                    metric.count
+                );
+            }
+        }
+
+        self.sections = [Metric::new(); 3];
+        self.next_report = now + REPORT_INTERVAL_US;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_keeps_min_max_and_average() {
+        let mut metric = Metric::new();
+        assert_eq!(metric.average(), None);
+
+        for duration in [30, 10, 50] {
+            metric.record(duration);
+        }
+
+        assert_eq!((metric.min, metric.max), (10, 50));
+        assert_eq!(metric.average(), Some(30));
+    }
+
+    #[test]
+    fn sections_are_kept_apart() {
+        let mut metrics = LoopMetrics::new();
+        metrics.record(Section::Usb, 5);
+        metrics.record(Section::Tx, 100);
+
+        assert_eq!(metrics.sections[Section::Usb as usize].average(), Some(5));
+        assert_eq!(metrics.sections[Section::Rx as usize].average(), None);
+        assert_eq!(metrics.sections[Section::Tx as usize].average(), Some(100));
+    }
+
+    #[test]
+    fn report_starts_over_once_per_interval() {
+        let mut metrics = LoopMetrics::new();
+        metrics.record(Section::Rx, 7);
+
+        metrics.report(REPORT_INTERVAL_US - 1);
+        assert_eq!(metrics.sections[Section::Rx as usize].average(), Some(7));
+
+        metrics.report(REPORT_INTERVAL_US);
+        assert_eq!(metrics.sections[Section::Rx as usize].average(), None);
+
+        metrics.record(Section::Rx, 9);
+        metrics.report(2 * REPORT_INTERVAL_US - 1);
+        assert_eq!(metrics.sections[Section::Rx as usize].average(), Some(9));
+    }
+}
@@ -0,0 +1,77 @@
+/// Longest gap between two main loop iterations before it counts as a stall. The MCP2515 only
+/// buffers two received frames and frames from the host wait for the loop. Times are in
+/// microseconds.
+pub const MAX_LOOP_PERIOD_US: u64 = 10_000;
+
+/// Checks that the main loop keeps iterating at a minimum rate, to catch things like long SPI
+/// transfers blocking it.
+pub struct LoopMonitor {
+    max_period: u64,
+    last_tick: Option<u64>,
+    stalls: u32,
+}
+
+impl LoopMonitor {
+    pub fn new(max_period: u64) -> Self {
+        LoopMonitor {
+            max_period,
+            last_tick: None,
+            stalls: 0,
+        }
+    }
+
+    /// Called once per iteration. Returns how long the loop was gone when that exceeded the
+    /// maximum period. The first call only starts the measurement.
+    pub fn tick(&mut self, now: u64) -> Option<u64> {
+        let last_tick = self.last_tick.replace(now)?;
+        let period = now.saturating_sub(last_tick);
+
+        match period > self.max_period {
+            true => {
+                self.stalls = self.stalls.wrapping_add(1);
+                Some(period)
+            }
+            false => None,
+        }
+    }
+
+    /// Number of stalls seen since startup.
+    #[allow(dead_code)]
+    pub fn stalls(&self) -> u32 {
+        self.stalls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_tick_only_starts_the_measurement() {
+        let mut monitor = LoopMonitor::new(MAX_LOOP_PERIOD_US);
+
+        assert_eq!(monitor.tick(1_000_000), None);
+        assert_eq!(monitor.stalls(), 0);
+    }
+
+    #[test]
+    fn gap_longer_than_the_maximum_is_a_stall() {
+        let mut monitor = LoopMonitor::new(100);
+        monitor.tick(1_000);
+
+        assert_eq!(monitor.tick(1_100), None);
+        assert_eq!(monitor.tick(1_201), Some(101));
+        assert_eq!(monitor.tick(1_250), None);
+        assert_eq!(monitor.stalls(), 1);
+    }
+
+    #[test]
+    fn timer_going_backwards_is_no_stall() {
+        let mut monitor = LoopMonitor::new(100);
+        monitor.tick(1_000);
+
+        assert_eq!(monitor.tick(0), None);
+        assert_eq!(monitor.tick(50), None);
+        assert_eq!(monitor.stalls(), 0);
+    }
+}
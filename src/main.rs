@@ -1,27 +1,130 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Host tests only build the logic around the main loop, not the loop itself.
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
+#[cfg(not(test))]
+mod board;
+mod burst;
+mod bus_off;
+mod bus_mode;
+mod can_timing;
+mod controller;
+mod error_counters;
+mod fault;
 mod frame_ext;
+mod heartbeat;
+mod host_link;
+mod host_queue;
+mod id_remap;
+#[cfg(any(test, feature = "loop-metrics"))]
+mod loop_metrics;
+mod loop_monitor;
+mod manual_tx;
+mod mcp_ext;
+mod mode_retry;
+mod rx_filter;
+mod snapshot;
+#[allow(dead_code)]
+mod temperature;
+mod timestamp;
+mod torture;
+#[cfg(not(test))]
+mod usb_irq;
 mod usbd_gs;
 
+#[cfg(not(test))]
+use board::Board;
+use burst::Burst;
+use bus_off::BusOffRecovery;
+use bus_mode::{BusMode, StopMode};
+use controller::{CanBus, Controller};
+#[cfg(not(test))]
 use cortex_m_rt::entry;
+#[cfg(not(test))]
 use defmt_rtt as _;
 use embedded_time::rate::*;
+use error_counters::ErrorCounters;
 use frame_ext::*;
-use mcp2515::{frame::CanFrame, regs::OpMode, *};
+use heartbeat::Heartbeat;
+use host_link::TxOutcome;
+use host_queue::HostQueue;
+use embedded_hal::can::Frame;
+use id_remap::IdRemap;
+use loop_monitor::{LoopMonitor, MAX_LOOP_PERIOD_US};
+use manual_tx::ManualTx;
+use snapshot::{Debouncer, Snapshot};
+use temperature::TemperatureSampler;
+use timestamp::TimestampPoint;
+use torture::Torture;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::digital::v2::InputPin;
+use embedded_hal::adc::OneShot;
+use mcp_ext::{Mcp2515Ext, TxPriority, TxStatus};
+use mode_retry::{ModeOutcome, ModeRetry};
+use rx_filter::RxFilter;
+use mcp2515::{frame::CanFrame, *};
+#[cfg(not(test))]
 use panic_probe as _;
 use ringbuffer::*;
+#[cfg(not(test))]
 use rp_pico::hal::{
+    adc::Adc,
     clocks,
     clocks::Clock,
-    gpio::{FunctionSpi, Pins},
+    gpio::Pins,
     pac,
     spi::Spi,
-    usb, Sio, Watchdog,
+    usb, Sio, Timer, Watchdog,
 };
 use usb_device::{class_prelude::*, prelude::*};
 use usbd_gs::*;
 
+#[cfg(any(
+    all(feature = "filter-hit", feature = "tx-depth-hint"),
+    all(feature = "filter-hit", feature = "rx-buffer-hit"),
+    all(feature = "tx-depth-hint", feature = "rx-buffer-hit"),
+))]
+compile_error!(
+    "filter-hit, tx-depth-hint and rx-buffer-hit all use the reserved byte of host frames"
+);
+
+/// Mode the controller falls back to when the USB bus is reset, so it doesn't keep taking part
+/// in bus traffic with nobody listening.
+const HOST_RESET_MODE: BusMode = BusMode::Off;
+
+/// Controller mode of a stopped channel, see `StopMode`. Only the low-power alternate setting
+/// puts the controller to sleep otherwise.
+const CHANNEL_STOP_MODE: StopMode = StopMode::Configuration;
+
+/// Index of the gs_usb channel served by the MCP2515. Received frames are tagged with it, the
+/// host drops frames for channels it didn't enumerate.
+///
+/// The board carries a single MCP2515, so there is no second channel to bridge frames to.
+/// Bridging would need a second controller on its own chip select, with frames received on one
+/// channel queued for the other and never bridged back.
+const MCP2515_CHANNEL: u8 = 0;
+
+/// Whether frames from the host still waiting to be sent are dropped when the bit rate changes,
+/// echoed to the host as failed. Otherwise they're kept and go out at the new rate.
+const FLUSH_ON_BIT_RATE_CHANGE: bool = true;
+
+/// Point of a received frame its capture timestamp refers to, see `timestamp`.
+const RX_TIMESTAMP_POINT: TimestampPoint = TimestampPoint::EndOfFrame;
+
+/// Share of the datasheet bus-off recovery time waited before transmitting again, in percent.
+const BUS_OFF_RECOVERY_PERCENT: u64 = 100;
+
+/// Attempts after the first before a frame that keeps failing is reported to the host as a TX
+/// error, for instance when nobody acknowledges it. The controller then runs in one-shot mode and
+/// the firmware repeats the transmission. None leaves retrying to the controller, which never
+/// gives up. Frames sent in host-requested one-shot mode are never retried.
+const TX_RETRIES: Option<u8> = None;
+
+/// Whether a stalled main loop resets the device instead of only logging a warning.
+const RESET_ON_LOOP_STALL: bool = false;
+
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
@@ -40,6 +143,7 @@ fn main() -> ! {
     .unwrap();
 
     let delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().integer());
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
     let sio = Sio::new(pac.SIO);
     let pins = Pins::new(
         pac.IO_BANK0,
@@ -48,11 +152,15 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    let _spi_sclk = pins.gpio2.into_mode::<FunctionSpi>();
-    let _spi_mosi = pins.gpio3.into_mode::<FunctionSpi>();
-    let _spi_miso = pins.gpio4.into_mode::<FunctionSpi>();
+    let Board {
+        spi_sclk: _spi_sclk,
+        spi_mosi: _spi_mosi,
+        spi_miso: _spi_miso,
+        mcp2515_cs,
+        mut status_led,
+        snapshot_button,
+    } = Board::new(pins);
 
-    let mcp2515_cs = pins.gpio9.into_push_pull_output();
     let mcp2515_spi = Spi::<_, _, 8>::new(pac.SPI0).init(
         &mut pac.RESETS,
         clocks.peripheral_clock.freq(),
@@ -60,19 +168,31 @@ fn main() -> ! {
         &embedded_hal::spi::MODE_0,
     );
 
-    let usb_bus = UsbBusAllocator::new(usb::UsbBus::new(
-        pac.USBCTRL_REGS,
-        pac.USBCTRL_DPRAM,
-        clocks.usb_clock,
-        true,
-        &mut pac.RESETS,
-    ));
+    // The interrupt handler owns the device, so the bus has to outlive main.
+    let usb_bus = cortex_m::singleton!(: UsbBusAllocator<usb::UsbBus> = UsbBusAllocator::new(
+        usb::UsbBus::new(
+            pac.USBCTRL_REGS,
+            pac.USBCTRL_DPRAM,
+            clocks.usb_clock,
+            true,
+            &mut pac.RESETS,
+        )
+    ))
+    .unwrap();
+
+    let mut mcp2515 = MCP2515::new(mcp2515_spi, mcp2515_cs, delay);
+    let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
+    let mut temperature_sensor = adc.enable_temp_sensor();
 
     let channels = [Channel {
         features: ChannelFeatures::new(&[
             ChannelFeaturesBit::ListenOnly,
             ChannelFeaturesBit::Loopback,
-        ]),
+            ChannelFeaturesBit::OneShot,
+            ChannelFeaturesBit::BerrReporting,
+            ChannelFeaturesBit::NoEcho,
+        ])
+        .restrict(mcp2515.capabilities()),
         fclk_can: 8000000,
         constraints: ChannelConstraints {
             tseg1_min: 3,
@@ -87,110 +207,728 @@ fn main() -> ! {
         data_constraints: None,
     }];
 
-    let mut gs_port = GsUsbPort::new(&usb_bus, 64, channels, 2, 1);
-    let mut mcp2515 = MCP2515::new(mcp2515_spi, mcp2515_cs, delay);
+    let mut gs_port = GsUsbPort::new(usb_bus, 64, channels, 2, 1);
 
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x2323))
+    let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x1209, 0x2323))
         .manufacturer("Longan Labs")
         .product("CANBED Dual")
         .serial_number("TBD")
         .device_class(0)
         .build();
 
-    let mut inbox = ConstGenericRingBuffer::<HostFrame, 8>::new();
+    let mut inbox = HostQueue::<8>::new();
     let mut outbox = ConstGenericRingBuffer::<HostFrame, 8>::new();
+    let mut id_remap = IdRemap::<8>::new();
+    let mut rx_filter = RxFilter::new();
+    let mut heartbeat = Heartbeat::new();
+    let mut channel_on = false;
+    let mut active_mode = BusMode::Off;
+    let mut bit_rate = board::DEFAULT_BIT_RATE.unwrap_or(0);
+    // Mode the host asked for, applied once the interface leaves the low-power setting.
+    let mut requested_mode = BusMode::Off;
+    let mut low_power = false;
+    // The host suspended the bus, which takes the controller off it like the low-power setting.
+    let mut suspended = false;
+    // One-shot mode is requested with the channel mode and applied along with it.
+    let mut one_shot_requested = false;
+    let mut one_shot = false;
+    // Buffer holding the frame at the head of the outbox while a one-shot transmission is in
+    // flight. The echo waits for the outcome, a failed frame is only retried up to TX_RETRIES.
+    let mut one_shot_buffer: Option<usize> = None;
+    let mut retries_left = 0;
+    let mut transmit_only = false;
+    let mut drop_remote = false;
+    let mut berr_reporting = false;
+    let mut error_counters = ErrorCounters::new();
+    let mut snapshot_debouncer = Debouncer::new();
+    let mut snapshot = Snapshot::<32>::new();
+    let mut burst = Burst::new();
+    let mut torture = Torture::new();
+    let mut manual_tx = ManualTx::new();
+    let mut mode_retry = ModeRetry::new();
+    let mut bus_off_recovery = BusOffRecovery::new(BUS_OFF_RECOVERY_PERCENT);
+    let mut temperature_sampler = TemperatureSampler::new();
 
-    assert_eq!(mcp2515.init(Settings::default()), Ok(()));
+    while timer.get_counter() < board::STARTUP_DELAY_US {}
+
+    if !fault::wait_for_oscillator(
+        || mcp2515.oscillator_running().unwrap_or(false),
+        || timer.get_counter(),
+        fault::INIT_TIMEOUT_US,
+    ) {
+        defmt::warn!("CAN controller still in reset, trying init anyway");
+    }
+
+    // A controller that never comes up leaves the device enumerated in the fault state rather
+    // than stuck in init.
+    let fault = fault::init_with_timeout(
+        mcp_ext::opmod(initial_settings().mode),
+        || {
+            let result = mcp2515.init(initial_settings());
+            (result, mcp2515.canstat().ok())
+        },
+        || timer.get_counter(),
+        fault::INIT_TIMEOUT_US,
+    );
+
+    if let Some(fault) = fault {
+        defmt::error!("CAN controller fault: {}", fault);
+        gs_port.set_fault(fault as u8);
+    }
+
+    // Read back rather than derived from the settings, so the host sees what the controller
+    // actually runs with.
+    if let Ok(cnf) = mcp2515.read_cnf() {
+        gs_port.set_cnf_registers(MCP2515_CHANNEL as usize, cnf);
+    }
+
+    gs_port.set_bit_rate(MCP2515_CHANNEL as usize, bit_rate);
+
+    usb_irq::start(usb_dev, gs_port);
+
+    let mut loop_monitor = LoopMonitor::new(MAX_LOOP_PERIOD_US);
+    #[cfg(feature = "loop-metrics")]
+    let mut loop_metrics = loop_metrics::LoopMetrics::new();
 
     loop {
-        if usb_dev.poll(&mut [&mut gs_port]) {
-            if let Some(event) = gs_port.read_control_event() {
-                match event {
-                    ChannelEvent::BitTiming(timing, ch) => {
-                        assert_eq!(
-                            mcp2515.set_bitrate(
-                                can_speed_from_bit_rate(timing.bit_rate(&channels[ch])),
-                                McpSpeed::MHz16,
-                                false,
-                            ),
-                            Ok(())
-                        );
-                    }
-                    ChannelEvent::DataBitTiming(_, _) => {}
-                    ChannelEvent::ChannelMode(mode, _) => {
-                        let mut mcp_mode: OpMode = OpMode::Normal;
+        if let Some(period) = loop_monitor.tick(timer.get_counter()) {
+            defmt::warn!("main loop stalled for {} us", period);
+
+            if RESET_ON_LOOP_STALL {
+                if mcp2515.quiesce().is_err() {
+                    defmt::error!("could not quiesce the controller before the reset");
+                }
+
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+
+        // Sampled in the fault state as well, overheating may be what went wrong.
+        if temperature_sampler.due(timer.get_counter()) {
+            let reading: Option<u16> = adc.read(&mut temperature_sensor).ok();
 
-                        if mode.flags.is_set(ChannelFlagsBit::Loopback) {
-                            mcp_mode = OpMode::Loopback;
+            if let Some(raw) = reading {
+                let millicelsius = temperature::millicelsius_from_adc(raw);
+                usb_irq::with_port(|gs_port| gs_port.set_temperature(millicelsius));
+            }
+        }
+
+        // USB keeps running from the interrupt so the host can read the fault, but leave the
+        // controller alone.
+        if let Some(fault) = fault {
+            match fault::led_on(fault, timer.get_counter()) {
+                true => status_led.set_high().ok(),
+                false => status_led.set_low().ok(),
+            };
+            continue;
+        }
+
+        while let Some(event) = usb_irq::with_port(|gs_port| gs_port.read_control_event()) {
+            match event {
+                ChannelEvent::BitTiming(timing, ch) => {
+                    if let Some(channel) = channels.get(ch) {
+                        let can_speed = can_speed_from_bit_rate(timing.bit_rate(channel));
+                        let cnf = can_timing::cnf_registers(&can_speed).map(|(_, cnf)| cnf);
+
+                        if FLUSH_ON_BIT_RATE_CHANGE {
+                            burst.cancel();
+                            one_shot_buffer = None;
+
+                            while let Some(mut err_frame) = outbox.dequeue() {
+                                err_frame.flags.set(HostFrameFlagsBits::Overflow);
+                                inbox.push_echo(err_frame, timer.get_counter());
+                            }
                         }
 
-                        if mode.flags.is_set(ChannelFlagsBit::ListenOnly) {
-                            mcp_mode = OpMode::ListenOnly;
+                        // Timing and mode are applied together and rolled back together, the
+                        // reported bit rate only changes once the controller runs at it.
+                        let mode = active_mode
+                            .controller_mode(mcp2515.has_hw_loopback(), CHANNEL_STOP_MODE);
+
+                        match cnf.map(|cnf| (cnf, mcp2515.apply_cnf(cnf, mode))) {
+                            Some((cnf, Ok(()))) => {
+                                let achieved = can_timing::bit_timing_from_cnf(&cnf);
+                                bit_rate = achieved.bit_rate(channel);
+                                usb_irq::with_port(|gs_port| gs_port.set_bit_rate(ch, bit_rate));
+                            }
+                            _ => defmt::error!("couldn't program the bit rate"),
                         }
 
-                        if !mode.is_on() {
-                            mcp_mode = OpMode::Sleep;
+                        if let Ok(cnf) = mcp2515.read_cnf() {
+                            usb_irq::with_port(|gs_port| gs_port.set_cnf_registers(ch, cnf));
                         }
+                    }
+                }
+                // Only decoded for FD channels, so the MCP2515 never gets here. There are no
+                // data-phase registers to compute the achieved rate from, the requested one is
+                // reported.
+                ChannelEvent::DataBitTiming(timing, ch) => {
+                    if let Some(channel) = channels.get(ch) {
+                        let data_bit_rate = timing.bit_rate(channel);
+                        usb_irq::with_port(|gs_port| gs_port.set_data_bit_rate(ch, data_bit_rate));
+                    }
+                }
+                ChannelEvent::ChannelMode(mode, _)
+                    if mode.flags.is_set(ChannelFlagsBit::Fd) && !mcp2515.supports_fd() =>
+                {
+                    defmt::error!("FD mode requested on a classic controller");
+                }
+                // Without a default bit rate the controller has no timing the host chose yet,
+                // it stays in listen-only mode rather than joining the bus at an arbitrary rate.
+                ChannelEvent::ChannelMode(mode, _) if mode.is_on() && bit_rate == 0 => {
+                    defmt::error!("channel started before its bit timing was set");
+                }
+                ChannelEvent::ChannelMode(mode, _) => {
+                    one_shot_requested = mode.flags.is_set(ChannelFlagsBit::OneShot);
+                    berr_reporting = mode.flags.is_set(ChannelFlagsBit::BerrReporting);
+                    inbox.set_echo(!mode.flags.is_set(ChannelFlagsBit::NoEcho));
+                    error_counters = ErrorCounters::new();
+                    requested_mode = BusMode::from_channel_mode(&mode);
+                    mode_retry.request(
+                        BusMode::for_power_state(requested_mode, low_power || suspended),
+                        timer.get_counter(),
+                    );
+                }
+                ChannelEvent::Identify(_, _) => {}
+                ChannelEvent::IdRemap(entry, _) => {
+                    id_remap.set(&entry);
+                }
+                // A mode relying on a feature the host just turned off falls back to off.
+                ChannelEvent::Features(features, _) => {
+                    if !features.is_set(ChannelFeaturesBit::OneShot) {
+                        one_shot_requested = false;
+                    }
+
+                    if !features.is_set(ChannelFeaturesBit::BerrReporting) {
+                        berr_reporting = false;
+                    }
+
+                    let supported = match requested_mode {
+                        BusMode::Monitor => features.is_set(ChannelFeaturesBit::ListenOnly),
+                        BusMode::Loopback => features.is_set(ChannelFeaturesBit::Loopback),
+                        BusMode::Off | BusMode::Sleep | BusMode::Normal => true,
+                    };
+
+                    if !supported {
+                        requested_mode = BusMode::Off;
+                    }
 
-                        assert_eq!(mcp2515.set_mode(mcp_mode), Ok(()));
+                    mode_retry.request(
+                        BusMode::for_power_state(requested_mode, low_power || suspended),
+                        timer.get_counter(),
+                    );
+                }
+                ChannelEvent::RxFilter(program, _) => {
+                    if !rx_filter.set(program.bytes()) {
+                        defmt::warn!("malformed receive filter program");
                     }
-                    ChannelEvent::Identify(_, _) => {}
-                };
+                }
+                ChannelEvent::Heartbeat(config, ch) => {
+                    heartbeat.configure(config, ch as u8, timer.get_counter());
+                }
+                ChannelEvent::TransmitOnly(switch, _) => {
+                    transmit_only = switch.is_on();
+                }
+                ChannelEvent::DropRemote(switch, _) => {
+                    drop_remote = switch.is_on();
+                }
+                // None of the channels advertise termination control, the class rejects it.
+                ChannelEvent::Termination(_, _) => {}
+                ChannelEvent::Burst(request, _) => {
+                    burst.start(request.count as usize);
+                }
+                ChannelEvent::Torture(request, ch) => {
+                    torture.start(request, ch as u8, timer.get_counter());
+                }
+                // Fails right away while the previous frame is still in flight, or in listen-only
+                // mode.
+                ChannelEvent::SendFrame(host_frame, ch) => {
+                    let buffer = CanFrame::from_host_frame(&host_frame)
+                        .ok()
+                        .filter(|_| manual_tx.is_idle() && active_mode != BusMode::Monitor)
+                        .and_then(|mcp_frame| {
+                            let priority = TxPriority::from_id(&mcp_frame.id());
+                            mcp2515.send_with_priority(&mcp_frame, priority).ok()
+                        });
+
+                    match buffer {
+                        Some(buffer) => manual_tx.start(ch, buffer, timer.get_counter()),
+                        None => usb_irq::with_port(|gs_port| {
+                            gs_port.set_send_result(ch, SendResult::Failed)
+                        }),
+                    }
+                }
+                #[cfg(feature = "frame-injection")]
+                ChannelEvent::InjectFrame(frame, _) => {
+                    inbox.push_received(frame, timer.get_counter());
+                }
+                ChannelEvent::LowPower(on) => {
+                    low_power = on;
+
+                    if mcp2515.set_wake_up(on).is_err() {
+                        defmt::warn!("couldn't switch wake-up on bus activity");
+                    }
+
+                    mode_retry.request(
+                        BusMode::for_power_state(requested_mode, low_power || suspended),
+                        timer.get_counter(),
+                    );
+                }
+                ChannelEvent::HostReset => {
+                    // Leave the bus before falling back to the reset mode, the frames still
+                    // pending belong to the host that went away.
+                    if mcp2515.quiesce().is_err() {
+                        defmt::error!("could not quiesce the controller on host reset");
+                    }
+
+                    // Nobody is waiting for their echoes any more.
+                    outbox.clear();
+                    burst.cancel();
+                    one_shot_buffer = None;
+                    manual_tx.cancel();
+                    one_shot_requested = false;
+                    berr_reporting = false;
+                    inbox.set_echo(true);
+                    requested_mode = HOST_RESET_MODE;
+                    low_power = false;
+                    mode_retry.request(HOST_RESET_MODE, timer.get_counter());
+                }
+            };
+        }
+
+        // Bus activity woke the sleeping controller into listen-only mode. It's recorded like any
+        // other mode change, so the running state and one-shot setting follow, and the host is
+        // told with an error frame on its way right away. The controller stays listening: waking
+        // it again on every frame would only repeat the notification.
+        if low_power && mcp2515.take_wake_up().unwrap_or(false) {
+            defmt::info!("controller woken up by bus activity");
+            mcp2515.set_wake_up(false).ok();
+            mode_retry.request(BusMode::Monitor, timer.get_counter());
+            inbox.push_received(
+                frame_ext::controller_active_frame(MCP2515_CHANNEL),
+                timer.get_counter(),
+            );
+        }
+
+        if let Some(bus_mode) = mode_retry.due(timer.get_counter()) {
+            let result = mcp2515.set_mode(
+                bus_mode.controller_mode(mcp2515.has_hw_loopback(), CHANNEL_STOP_MODE),
+            );
+            let transient = matches!(result, Err(mcp2515::error::Error::NewModeTimeout));
+
+            match mode_retry.record(result.is_ok(), transient, timer.get_counter()) {
+                ModeOutcome::Applied(bus_mode) => {
+                    active_mode = bus_mode;
+                    channel_on = bus_mode.is_running();
+                    usb_irq::with_port(|gs_port| {
+                        gs_port.set_running(MCP2515_CHANNEL as usize, channel_on)
+                    });
+                    one_shot = channel_on && (one_shot_requested || TX_RETRIES.is_some());
+
+                    if mcp2515.set_one_shot(one_shot).is_err() {
+                        defmt::error!("couldn't switch one-shot mode");
+                    }
+                }
+                ModeOutcome::Retrying => {}
+                ModeOutcome::Failed(_) => {
+                    defmt::error!("controller didn't change mode");
+
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.mode_change_failures = stats.mode_change_failures.wrapping_add(1);
+                    });
+                }
+            }
+        }
+
+        #[cfg(feature = "loop-metrics")]
+        let section_start = timer.get_counter();
+
+        let usb_suspended =
+            usb_irq::with_port(|gs_port| gs_port.usb_state()) == UsbDeviceState::Suspend;
+
+        if usb_suspended != suspended {
+            defmt::info!("USB suspended: {}", usb_suspended);
+            suspended = usb_suspended;
+            mode_retry.request(
+                BusMode::for_power_state(requested_mode, low_power || suspended),
+                timer.get_counter(),
+            );
+        }
+
+        // The port is polled from the USB interrupt, moving frames in and out of it is cheap
+        // enough to do on every iteration.
+        let now = timer.get_counter();
+        usb_irq::with_port(|gs_port| {
+            host_link::exchange(gs_port, &mut inbox, &mut outbox, &id_remap, now);
+        });
+
+        #[cfg(feature = "loop-metrics")]
+        loop_metrics.record(loop_metrics::Section::Usb, timer.get_counter() - section_start);
+
+        // The button is active low.
+        if snapshot_debouncer.update(snapshot_button.is_low().unwrap_or(false), timer.get_counter())
+        {
+            defmt::info!("snapshot armed");
+            snapshot.arm();
+        }
+
+        #[cfg(feature = "loop-metrics")]
+        let section_start = timer.get_counter();
+
+        // In transmit-only mode, or while a full queue holds reception, the receive buffers are
+        // left to overflow. TX errors are still echoed to the host, they don't depend on the
+        // receive path.
+        let rx_overflow_policy = usb_irq::with_port(|gs_port| gs_port.rx_overflow_policy());
+        let hold_rx = rx_overflow_policy == RxOverflowPolicy::StopReading && inbox.received_full();
+
+        if !transmit_only && !hold_rx {
+            #[cfg(feature = "filter-hit")]
+            let filter = mcp2515.next_filter_hit().ok().flatten();
+            #[cfg(feature = "rx-buffer-hit")]
+            let rx_buffer = mcp2515.next_rx_buffer().ok().flatten();
+
+            let received = mcp2515.receive();
+            let received = usb_irq::with_port(|gs_port| {
+                host_link::count_received(gs_port.stats_mut(), received)
+            });
+
+            if let Some(mcp_frame) = received {
+                let captured_at = timestamp::capture(
+                    RX_TIMESTAMP_POINT,
+                    timer.get_counter(),
+                    &mcp_frame,
+                    bit_rate,
+                );
+
+                snapshot.record(&mcp_frame, captured_at);
+                #[cfg_attr(
+                    not(any(feature = "filter-hit", feature = "rx-buffer-hit")),
+                    allow(unused_mut)
+                )]
+                let mut host_frame = mcp_frame.to_host_frame(MCP2515_CHANNEL);
+
+                // 0 means unknown, otherwise the number of the matching filter plus one.
+                #[cfg(feature = "filter-hit")]
+                host_frame.set_reserved(filter.map_or(0, |filter| filter + 1));
+
+                // 0 means unknown, 1 RXB0 and 2 RXB1.
+                #[cfg(feature = "rx-buffer-hit")]
+                host_frame.set_reserved(rx_buffer.map_or(0, |buffer| buffer as u8 + 1));
+
+                // Counted and recorded either way, the filter only decides what the host sees.
+                let dropped_remote = drop_remote && mcp_frame.is_remote_frame();
+
+                if !dropped_remote && rx_filter.matches(&host_frame.can_id) {
+                    deliver_received(&mut inbox, host_frame, captured_at, rx_overflow_policy);
+                } else {
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.rx_filtered = stats.rx_filtered.wrapping_add(1);
+                    });
+                }
+
+                // Only both receive buffers being full overruns them, so a frame has always been
+                // read by the time one is reported.
+                if mcp2515.take_rx_overrun().unwrap_or(false) {
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.rx_overruns = stats.rx_overruns.wrapping_add(1);
+                    });
+
+                    let now = timer.get_counter();
+                    let overrun_frame = frame_ext::rx_overrun_frame(MCP2515_CHANNEL);
+                    deliver_received(&mut inbox, overrun_frame, now, rx_overflow_policy);
+                }
             }
+        }
 
-            if let Ok(host_frame) = gs_port.read_frame() {
-                outbox.push(host_frame);
+        // Generated frames skip the filter but share the overflow handling they're there to
+        // exercise.
+        if !hold_rx {
+            if let Some(host_frame) = torture.poll(timer.get_counter()) {
+                let now = timer.get_counter();
+                deliver_received(&mut inbox, host_frame, now, rx_overflow_policy);
             }
+        }
+
+        #[cfg(feature = "loop-metrics")]
+        loop_metrics.record(loop_metrics::Section::Rx, timer.get_counter() - section_start);
+
+        if let Ok(bus_off) = mcp2515.is_bus_off() {
+            bus_off_recovery.update(bus_off, timer.get_counter(), bit_rate);
+        }
+
+        let tx_held = bus_off_recovery.holds_tx();
 
-            if let Some(host_frame) = inbox.peek() {
-                match gs_port.write_frame(host_frame) {
-                    Ok(_) => inbox.skip(),
-                    Err(UsbError::WouldBlock) => {}
-                    Err(_) => inbox.skip(),
-                };
+        // The counters are only read while the host asked for bus error reporting, changes of
+        // the error state are streamed to it as error frames.
+        if channel_on && berr_reporting {
+            if let Ok((tec, rec)) = mcp2515.error_counters() {
+                if let Some(state) = error_counters.update(tec, rec) {
+                    let now = timer.get_counter();
+                    let host_frame =
+                        frame_ext::error_counter_frame(MCP2515_CHANNEL, state, tec, rec);
+                    deliver_received(&mut inbox, host_frame, now, rx_overflow_policy);
+                }
             }
         }
 
-        if let Ok(mcp_frame) = mcp2515.read_message() {
-            inbox.push(mcp_frame.to_host_frame(1));
+        #[cfg(feature = "loop-metrics")]
+        let section_start = timer.get_counter();
+
+        let may_transmit = channel_on && !tx_held && active_mode != BusMode::Monitor;
+
+        // The heartbeat takes whichever transmit buffer is free, so it waits while a burst, a
+        // manual send or a tracked one-shot frame counts on the buffers it loaded.
+        let heartbeat_may_send = may_transmit
+            && !burst.is_active()
+            && manual_tx.is_idle()
+            && one_shot_buffer.is_none();
+
+        if let Some(host_frame) = heartbeat.poll(timer.get_counter(), heartbeat_may_send) {
+            if let Ok(mcp_frame) = CanFrame::from_host_frame(&host_frame) {
+                mcp2515.send_message(mcp_frame).ok();
+            }
         }
 
-        if let Some(host_frame) = outbox.peek() {
-            if let Some(mcp_frame) = CanFrame::from_host_frame(host_frame) {
-                match mcp2515.send_message(mcp_frame) {
-                    Ok(_) => {
-                        inbox.push(outbox.dequeue().unwrap());
+        if let Some(buffer) = manual_tx.buffer() {
+            if let Ok(status) = mcp2515.tx_status(buffer) {
+                if let Some((ch, result)) = manual_tx.update(status, timer.get_counter()) {
+                    if status == TxStatus::Pending {
+                        mcp2515.abort_tx(buffer).ok();
+                    }
+
+                    usb_irq::with_port(|gs_port| gs_port.set_send_result(ch, result));
+                }
+            }
+        }
+
+        // Listen-only never drives the bus. Frames the host sends anyway are echoed as failed
+        // right away instead of waiting for a mode that can send them.
+        if active_mode == BusMode::Monitor && one_shot_buffer.is_none() {
+            burst.cancel();
+
+            if let Some(mut err_frame) = outbox.dequeue() {
+                usb_irq::with_port(|gs_port| {
+                    let stats = gs_port.stats_mut();
+                    stats.listen_only_drops = stats.listen_only_drops.wrapping_add(1);
+                });
+
+                err_frame.flags.set(HostFrameFlagsBits::Overflow);
+                inbox.push_echo(err_frame, timer.get_counter());
+            }
+        } else if let Some(count) = burst
+            .collecting()
+            .filter(|_| !tx_held && one_shot_buffer.is_none())
+        {
+            // A frame tracked in one-shot mode keeps its buffer and its place at the head of the
+            // outbox until it's echoed, so the burst starts after it.
+            if outbox.len() >= count && mcp2515.tx_pending() == Ok(0) {
+                // Each frame is requested as soon as it's loaded, with a lower priority than the
+                // one before, so the controller keeps them in the order the host sent them even
+                // though it could pick a later buffer first.
+                let priorities = [TxPriority::Highest, TxPriority::High, TxPriority::Low];
+                let loaded = (0..count).try_fold(0, |mask, index| {
+                    let host_frame = outbox.get(index as isize).ok_or(())?;
+                    let mcp_frame = CanFrame::from_host_frame(host_frame).map_err(|_| ())?;
+
+                    mcp2515
+                        .send_via_buffer(index, &mcp_frame, priorities[index])
+                        .map(|_| mask | 1 << index)
+                        .map_err(|_| ())
+                });
+
+                match loaded {
+                    Ok(mask) => burst.launched(mask),
+                    Err(()) => burst.cancel(),
+                }
+            }
+        } else if burst.is_active() && one_shot_buffer.is_none() {
+            if let Some(count) = mcp2515.tx_pending().ok().and_then(|p| burst.update(p)) {
+                let now = timer.get_counter();
+
+                // Buffer n held the nth frame of the outbox. A frame that failed in one-shot mode
+                // or was aborted is no longer pending either, it's echoed as failed.
+                for buffer in 0..count {
+                    let mut host_frame = outbox.dequeue().unwrap();
+                    let sent = mcp2515.tx_status(buffer) == Ok(TxStatus::Sent);
+
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+
+                        match sent {
+                            true => stats.tx_frames = stats.tx_frames.wrapping_add(1),
+                            false => stats.tx_errors = stats.tx_errors.wrapping_add(1),
+                        }
+                    });
+
+                    if !sent {
+                        host_frame.flags.set(HostFrameFlagsBits::Overflow);
+                    }
+
+                    inbox.push_echo(host_frame, now);
+                }
+            }
+        } else if active_mode == BusMode::Loopback && !mcp2515.has_hw_loopback() {
+            if let Some(host_frame) = outbox.dequeue() {
+                let now = timer.get_counter();
+
+                match CanFrame::from_host_frame(&host_frame) {
+                    Ok(mcp_frame) => {
+                        usb_irq::with_port(|gs_port| {
+                            let stats = gs_port.stats_mut();
+                            stats.tx_frames = stats.tx_frames.wrapping_add(1);
+                        });
+
+                        inbox.push_received(mcp_frame.to_host_frame(MCP2515_CHANNEL), now);
+                        inbox.push_echo(host_frame, now);
                     }
-                    Err(mcp2515::error::Error::TxBusy) => {}
-                    Err(mcp2515::error::Error::NewModeTimeout) => {}
                     Err(_) => {
-                        let mut err_frame = outbox.dequeue().unwrap();
+                        usb_irq::with_port(|gs_port| {
+                            let stats = gs_port.stats_mut();
+                            stats.host_frames_dropped = stats.host_frames_dropped.wrapping_add(1);
+                        });
+
+                        let mut err_frame = host_frame;
                         err_frame.flags.set(HostFrameFlagsBits::Overflow);
-                        inbox.push(err_frame);
+                        inbox.push_echo(err_frame, now);
                     }
                 }
-            } else {
-                outbox.skip();
+            }
+        } else if let Some(buffer) = one_shot_buffer {
+            match mcp2515.tx_status(buffer) {
+                Ok(TxStatus::Pending) => {}
+                Ok(TxStatus::Sent) => {
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.tx_frames = stats.tx_frames.wrapping_add(1);
+                    });
+
+                    one_shot_buffer = None;
+                    inbox.push_echo(outbox.dequeue().unwrap(), timer.get_counter());
+                }
+                Ok(TxStatus::Failed) if retries_left > 0 => {
+                    retries_left -= 1;
+
+                    if mcp2515.request_to_send(1 << buffer).is_err() {
+                        retries_left = 0;
+                    }
+                }
+                Ok(TxStatus::Failed) | Err(_) => {
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.tx_errors = stats.tx_errors.wrapping_add(1);
+
+                        if tx_held {
+                            stats.bus_off_drops = stats.bus_off_drops.wrapping_add(1);
+                        }
+                    });
+
+                    one_shot_buffer = None;
+                    let mut err_frame = outbox.dequeue().unwrap();
+                    err_frame.flags.set(HostFrameFlagsBits::Overflow);
+                    inbox.push_echo(err_frame, timer.get_counter());
+                }
+            }
+        } else if !tx_held {
+            let now = timer.get_counter();
+
+            match host_link::transmit(&mut mcp2515, &mut outbox, &mut inbox, one_shot, now) {
+                TxOutcome::Idle | TxOutcome::Busy => {}
+                TxOutcome::Loaded(buffer) => {
+                    one_shot_buffer = Some(buffer);
+                    retries_left = match one_shot_requested {
+                        true => 0,
+                        false => TX_RETRIES.unwrap_or(0),
+                    };
+                }
+                TxOutcome::Sent => {
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.tx_frames = stats.tx_frames.wrapping_add(1);
+                    });
+                }
+                TxOutcome::Failed => {
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.tx_errors = stats.tx_errors.wrapping_add(1);
+                    });
+
+                    #[cfg(feature = "debug-endpoint")]
+                    usb_irq::with_port(|gs_port| gs_port.write_debug(b"tx error"));
+                }
+                TxOutcome::Dropped(error) => {
+                    defmt::warn!("dropping frame from host: {}", error);
+
+                    usb_irq::with_port(|gs_port| {
+                        let stats = gs_port.stats_mut();
+                        stats.host_frames_dropped = stats.host_frames_dropped.wrapping_add(1);
+                    });
+
+                    #[cfg(feature = "debug-endpoint")]
+                    usb_irq::with_port(|gs_port| gs_port.write_debug(b"tx frame not convertible"));
+                }
             }
         }
+
+        #[cfg(feature = "loop-metrics")]
+        {
+            loop_metrics.record(loop_metrics::Section::Tx, timer.get_counter() - section_start);
+            loop_metrics.report(timer.get_counter());
+        }
+    }
+}
+
+/// Queues a frame for the host as received, counting frames lost to the overflow policy.
+#[cfg(not(test))]
+fn deliver_received<const N: usize>(
+    inbox: &mut HostQueue<N>,
+    host_frame: HostFrame,
+    captured_at: u64,
+    policy: RxOverflowPolicy,
+) {
+    if inbox.deliver_received(host_frame, captured_at, policy) {
+        usb_irq::with_port(|gs_port| {
+            let stats = gs_port.stats_mut();
+            stats.rx_frames_dropped = stats.rx_frames_dropped.wrapping_add(1);
+        });
+    }
+}
+
+/// Settings the controller starts with, before the host programs the bit timing: the board's
+/// default bit rate, or listen-only mode without one. Like the board, only built for the
+/// firmware.
+#[cfg(not(test))]
+fn initial_settings() -> Settings {
+    match board::DEFAULT_BIT_RATE {
+        Some(bit_rate) => Settings {
+            mode: regs::OpMode::Normal,
+            can_speed: can_speed_from_bit_rate(bit_rate),
+            ..Settings::default()
+        },
+        None => Settings {
+            mode: regs::OpMode::ListenOnly,
+            ..Settings::default()
+        },
     }
 }
 
 fn can_speed_from_bit_rate(bit_rate: u32) -> CanSpeed {
-    match bit_rate / 1000 {
-        0..=5000 => CanSpeed::Kbps5,
-        5001..=10000 => CanSpeed::Kbps10,
-        10001..=20000 => CanSpeed::Kbps20,
-        20001..=31250 => CanSpeed::Kbps31_25,
-        31251..=33300 => CanSpeed::Kbps33_3,
-        33301..=40000 => CanSpeed::Kbps40,
-        40001..=50000 => CanSpeed::Kbps50,
-        50001..=80000 => CanSpeed::Kbps80,
-        80001..=100000 => CanSpeed::Kbps100,
-        100001..=125000 => CanSpeed::Kbps125,
-        125001..=200000 => CanSpeed::Kbps200,
-        200001..=250000 => CanSpeed::Kbps250,
-        250001..=500000 => CanSpeed::Kbps500,
+    match bit_rate {
+        0..=5_000 => CanSpeed::Kbps5,
+        5_001..=10_000 => CanSpeed::Kbps10,
+        10_001..=20_000 => CanSpeed::Kbps20,
+        20_001..=31_250 => CanSpeed::Kbps31_25,
+        31_251..=33_300 => CanSpeed::Kbps33_3,
+        33_301..=40_000 => CanSpeed::Kbps40,
+        40_001..=50_000 => CanSpeed::Kbps50,
+        50_001..=80_000 => CanSpeed::Kbps80,
+        80_001..=100_000 => CanSpeed::Kbps100,
+        100_001..=125_000 => CanSpeed::Kbps125,
+        125_001..=200_000 => CanSpeed::Kbps200,
+        200_001..=250_000 => CanSpeed::Kbps250,
+        250_001..=500_000 => CanSpeed::Kbps500,
         _ => CanSpeed::Kbps1000,
     }
 }
@@ -0,0 +1,65 @@
+use super::mcp_ext::TxStatus;
+use super::usbd_gs::SendResult;
+
+/// How long a frame sent with the SendFrame request may stay pending before it's withdrawn and
+/// reported as failed, in microseconds. Outside one-shot mode the controller retries a frame
+/// nobody acknowledges forever.
+pub const SEND_TIMEOUT_US: u64 = 100_000;
+
+/// Tracks the frame sent with the SendFrame request. It takes a transmit buffer of its own next
+/// to the queued frames, one at a time.
+pub struct ManualTx {
+    in_flight: Option<InFlight>,
+}
+
+struct InFlight {
+    channel: usize,
+    buffer: usize,
+    deadline: u64,
+}
+
+impl ManualTx {
+    pub fn new() -> Self {
+        ManualTx { in_flight: None }
+    }
+
+    /// Whether a new frame can be sent, the previous one having completed.
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_none()
+    }
+
+    /// Records the frame just loaded into `buffer` for `channel`.
+    pub fn start(&mut self, channel: usize, buffer: usize, now: u64) {
+        self.in_flight = Some(InFlight {
+            channel,
+            buffer,
+            deadline: now + SEND_TIMEOUT_US,
+        });
+    }
+
+    /// The transmit buffer holding the frame, None if nothing is in flight.
+    pub fn buffer(&self) -> Option<usize> {
+        self.in_flight.as_ref().map(|in_flight| in_flight.buffer)
+    }
+
+    /// Updates the frame with the status of its buffer. Returns the channel and the outcome once
+    /// it's final, a frame still pending at the deadline failed and has to be withdrawn.
+    pub fn update(&mut self, status: TxStatus, now: u64) -> Option<(usize, SendResult)> {
+        let in_flight = self.in_flight.as_ref()?;
+
+        let result = match status {
+            TxStatus::Pending if now < in_flight.deadline => return None,
+            TxStatus::Pending | TxStatus::Failed => SendResult::Failed,
+            TxStatus::Sent => SendResult::Sent,
+        };
+
+        let channel = in_flight.channel;
+        self.in_flight = None;
+        Some((channel, result))
+    }
+
+    /// Forgets the frame, for when the controller's buffers were cleared.
+    pub fn cancel(&mut self) {
+        self.in_flight = None;
+    }
+}
@@ -0,0 +1,673 @@
+#[cfg(test)]
+pub mod fake_chip;
+
+use core::fmt::Debug;
+use embedded_hal::blocking::{delay::DelayMs, spi::Transfer};
+use embedded_hal::can::Id;
+use embedded_hal::digital::v2::OutputPin;
+use mcp2515::buffer::TxBuf;
+use mcp2515::regs::{CanIntf, CanStat, Cnf1, Cnf2, Cnf3, OpMode, Reg, Register, Rxb0Ctrl, Rxb1Ctrl};
+use mcp2515::{error::Error, frame::CanFrame, MCP2515};
+
+/// Registers the driver has no register type for, read as plain bytes through its typed
+/// `read_register`. The rest are written and modified by address.
+///
+/// There is no device id or silicon revision register to read at init: the register map has
+/// nothing identifying the part, and the errata describe the silicon revisions by date code only.
+/// Revision quirks have to be keyed on the board definition instead.
+pub mod reg {
+    use mcp2515::regs::{Reg, Register};
+
+    macro_rules! byte_registers {
+        ($($name:ident => $register:ident),*) => {
+            $(
+                #[derive(Clone, Copy)]
+                pub struct $name(pub u8);
+
+                impl Reg<1> for $name {
+                    const ADDRESSES: [Register; 1] = [Register::$register];
+
+                    fn read(content: [u8; 1]) -> Self {
+                        $name(content[0])
+                    }
+
+                    fn write(self) -> [u8; 1] {
+                        [self.0]
+                    }
+                }
+            )*
+        };
+    }
+
+    byte_registers! {
+        Tec => TEC,
+        Rec => REC,
+        Eflg => EFLG,
+        Txb0Ctrl => TXB0CTRL,
+        Txb1Ctrl => TXB1CTRL,
+        Txb2Ctrl => TXB2CTRL
+    }
+}
+
+/// TXP in TXBnCTRL.
+const TXP_MASK: u8 = 0b11;
+const TXREQ: u8 = 1 << 3;
+const TXERR: u8 = 1 << 4;
+const MLOA: u8 = 1 << 5;
+const ABTF: u8 = 1 << 6;
+const OSM: u8 = 1 << 3;
+const ABAT: u8 = 1 << 4;
+/// REQOP in CANCTRL and OPMOD in CANSTAT.
+pub const OPMOD_MASK: u8 = 0b111 << 5;
+const CONFIGURATION_OPMOD: u8 = 0b100 << 5;
+/// CANSTAT reads before a mode change counts as timed out. A read takes a couple of
+/// microseconds, and the controller only switches once the frame on the bus is done, up to
+/// 26 ms at 5 kbit/s.
+const MODE_CHANGE_POLLS: u32 = 20_000;
+
+/// Transmit buffer priority (TXBnCTRL.TXP). When several buffers are pending, the controller
+/// sends the one with the highest priority first, whatever the CAN ids.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TxPriority {
+    Lowest = 0,
+    Low = 1,
+    High = 2,
+    Highest = 3,
+}
+
+impl TxPriority {
+    /// Derives the priority from the two most significant id bits, so buffers are drained in
+    /// roughly the order the frames would win arbitration on the bus.
+    pub fn from_id(id: &Id) -> TxPriority {
+        let top_bits = match id {
+            Id::Standard(id) => id.as_raw() >> 9,
+            Id::Extended(id) => (id.as_raw() >> 27) as u16,
+        };
+
+        match top_bits {
+            0 => TxPriority::Highest,
+            1 => TxPriority::High,
+            2 => TxPriority::Low,
+            _ => TxPriority::Lowest,
+        }
+    }
+}
+
+const TXBO: u8 = 1 << 5;
+const RX0OVR: u8 = 1 << 6;
+const RX1OVR: u8 = 1 << 7;
+const RX0IF: u8 = 1 << 0;
+const RX1IF: u8 = 1 << 1;
+const WAKIF: u8 = 1 << 6;
+const WAKIE: u8 = 1 << 6;
+
+/// Decodes RXBnCTRL.FILHIT into the number of the acceptance filter that matched. RXB0 only
+/// has filters 0 and 1, RXB1 has all six since frames roll over into it.
+pub fn filter_hit(buffer: usize, ctrl: u8) -> u8 {
+    match buffer {
+        0 => ctrl & 0b001,
+        _ => ctrl & 0b111,
+    }
+}
+
+/// State of a transmit buffer after its transmission was requested.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TxStatus {
+    Pending,
+    Sent,
+    /// Lost arbitration, hit a bus error or was aborted. Only final in one-shot mode, otherwise
+    /// the controller retries and the buffer stays pending.
+    Failed,
+}
+
+/// Register-level operations the MCP2515 driver doesn't provide.
+pub trait Mcp2515Ext {
+    type Error;
+
+    /// Loads the frame into the first free transmit buffer with the given priority, requests its
+    /// transmission and returns the buffer used. Fails with TxBusy if all three buffers are
+    /// pending.
+    fn send_with_priority(
+        &mut self,
+        frame: &CanFrame,
+        priority: TxPriority,
+    ) -> Result<usize, Self::Error>;
+
+    /// Loads the frame into transmit buffer 0, 1 or 2 with the given priority and requests its
+    /// transmission. The buffer mustn't be pending.
+    fn send_via_buffer(
+        &mut self,
+        buffer: usize,
+        frame: &CanFrame,
+        priority: TxPriority,
+    ) -> Result<(), Self::Error>;
+
+    /// Requests transmission of the buffers in `mask` again, bit n selecting TXBn.
+    fn request_to_send(&mut self, mask: u8) -> Result<(), Self::Error>;
+
+    /// Withdraws the transmission requested from buffer 0, 1 or 2 (TXBnCTRL.TXREQ). A frame
+    /// already on the bus still completes.
+    fn abort_tx(&mut self, buffer: usize) -> Result<(), Self::Error>;
+
+    /// Returns the buffers whose transmission is still pending, bit n selecting TXBn.
+    fn tx_pending(&mut self) -> Result<u8, Self::Error>;
+
+    /// Returns the outcome of the last transmission requested from buffer 0, 1 or 2.
+    fn tx_status(&mut self, buffer: usize) -> Result<TxStatus, Self::Error>;
+
+    /// Reads CANSTAT, the operation mode in OPMOD and the interrupt code in ICOD.
+    fn canstat(&mut self) -> Result<u8, Self::Error>;
+
+    /// Whether the controller is bus-off (EFLG.TXBO).
+    fn is_bus_off(&mut self) -> Result<bool, Self::Error>;
+
+    /// Reads the transmit and receive error counters, TEC and REC.
+    fn error_counters(&mut self) -> Result<(u8, u8), Self::Error>;
+
+    /// Whether a frame arrived while both receive buffers were full since the last call, clearing
+    /// EFLG.RX0OVR and RX1OVR. The frame is lost in the controller, before the firmware saw it.
+    fn take_rx_overrun(&mut self) -> Result<bool, Self::Error>;
+
+    /// Returns the receive buffer, 0 or 1, holding the frame `read_message` returns next, None if
+    /// no frame is waiting. Frames only land in RXB1 once RXB0 is full, so seeing it means the
+    /// firmware fell behind the bus.
+    fn next_rx_buffer(&mut self) -> Result<Option<usize>, Self::Error>;
+
+    /// Frees receive buffer 0 or 1 without reading it, clearing CANINTF.RXnIF.
+    fn discard_rx(&mut self, buffer: usize) -> Result<(), Self::Error>;
+
+    /// Returns the filter that matched the frame `read_message` returns next, None if no frame
+    /// is waiting. Like the driver, RXB0 is read before RXB1.
+    fn next_filter_hit(&mut self) -> Result<Option<u8>, Self::Error>;
+
+    /// Switches to configuration mode, the only mode in which the bit timing, filter and mask
+    /// registers can be written. Fails with NewModeTimeout if the controller doesn't follow.
+    fn enter_config_mode(&mut self) -> Result<(), Self::Error>;
+
+    /// Leaves configuration mode for `mode`, with the same timeout as entering it.
+    fn exit_config_mode(&mut self, mode: OpMode) -> Result<(), Self::Error>;
+
+    /// Switches one-shot mode (CANCTRL.OSM), in which a failed transmission isn't retried.
+    fn set_one_shot(&mut self, on: bool) -> Result<(), Self::Error>;
+
+    /// Lets bus activity wake the controller from sleep (CANINTE.WAKIE). It wakes up in
+    /// listen-only mode.
+    fn set_wake_up(&mut self, on: bool) -> Result<(), Self::Error>;
+
+    /// Whether bus activity woke the controller since the last call, clearing CANINTF.WAKIF.
+    fn take_wake_up(&mut self) -> Result<bool, Self::Error>;
+
+    /// Whether the oscillator runs. The controller is held in reset until its oscillator
+    /// start-up timer expires and then comes up in configuration mode, the mode it stays in until
+    /// init leaves it.
+    fn oscillator_running(&mut self) -> Result<bool, Self::Error>;
+
+    /// Reads back the bit timing registers as programmed, in the order CNF1, CNF2, CNF3.
+    fn read_cnf(&mut self) -> Result<[u8; 3], Self::Error>;
+
+    /// Programs CNF1..3 and switches to `mode` within one stay in configuration mode. If any step
+    /// fails, the previous bit timing and mode are restored as far as the controller lets them
+    /// be, so a failed apply doesn't leave new timing in a stopped controller.
+    fn apply_cnf(&mut self, cnf: [u8; 3], mode: OpMode) -> Result<(), Self::Error>;
+
+    /// Takes the controller off the bus cleanly before a reset: aborts every pending
+    /// transmission, waits for a frame already on the bus to complete and only then enters
+    /// configuration mode, so the reset never cuts a frame short.
+    fn quiesce(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Byte-wide register access on top of the driver, which only reads registers through register
+/// types and writes other registers by address.
+trait RegisterBytes {
+    type Error;
+
+    fn read_byte<R: Reg<1>>(&mut self) -> Result<u8, Self::Error>;
+
+    fn write_byte(&mut self, register: Register, value: u8) -> Result<(), Self::Error>;
+
+    /// Changes the bits of `register` set in `mask` to those of `value`.
+    fn modify_byte(&mut self, register: Register, mask: u8, value: u8) -> Result<(), Self::Error>;
+}
+
+impl<SPI, CS, D, SPIE, CSE> RegisterBytes for MCP2515<SPI, CS, D>
+where
+    SPI: Transfer<u8, Error = SPIE>,
+    CS: OutputPin<Error = CSE>,
+    D: DelayMs<u8>,
+    SPIE: Debug,
+    CSE: Debug,
+{
+    type Error = Error<SPIE, CSE>;
+
+    fn read_byte<R: Reg<1>>(&mut self) -> Result<u8, Self::Error> {
+        Ok(self.read_register::<1, R>()?.write()[0])
+    }
+
+    fn write_byte(&mut self, register: Register, value: u8) -> Result<(), Self::Error> {
+        self.write_register_addr(&[register], &[value]).map(|_| ())
+    }
+
+    fn modify_byte(&mut self, register: Register, mask: u8, value: u8) -> Result<(), Self::Error> {
+        self.modify_register_addr(&[register], &[value], &[mask])
+            .map(|_| ())
+    }
+}
+
+impl<SPI, CS, D, SPIE, CSE> Mcp2515Ext for MCP2515<SPI, CS, D>
+where
+    SPI: Transfer<u8, Error = SPIE>,
+    CS: OutputPin<Error = CSE>,
+    D: DelayMs<u8>,
+    SPIE: Debug,
+    CSE: Debug,
+{
+    type Error = Error<SPIE, CSE>;
+
+    fn send_with_priority(
+        &mut self,
+        frame: &CanFrame,
+        priority: TxPriority,
+    ) -> Result<usize, Self::Error> {
+        let pending = self.tx_pending()?;
+        let buffer = (0..TxBuf::ALL.len())
+            .find(|buffer| pending & (1 << buffer) == 0)
+            .ok_or(Error::TxBusy)?;
+
+        self.send_via_buffer(buffer, frame, priority)?;
+        Ok(buffer)
+    }
+
+    fn send_via_buffer(
+        &mut self,
+        buffer: usize,
+        frame: &CanFrame,
+        priority: TxPriority,
+    ) -> Result<(), Self::Error> {
+        // TXP can only be written while the buffer isn't pending, so it goes in before the
+        // driver loads the frame and sets TXREQ.
+        self.modify_byte(TxBuf::ALL[buffer].ctrl(), TXP_MASK, priority as u8)?;
+        self.send_message_via_buffer(TxBuf::ALL[buffer], *frame)
+    }
+
+    fn request_to_send(&mut self, mask: u8) -> Result<(), Self::Error> {
+        for (buffer, tx_buf) in TxBuf::ALL.iter().enumerate() {
+            if mask & (1 << buffer) != 0 {
+                self.modify_byte(tx_buf.ctrl(), TXREQ, TXREQ)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn abort_tx(&mut self, buffer: usize) -> Result<(), Self::Error> {
+        self.modify_byte(TxBuf::ALL[buffer].ctrl(), TXREQ, 0)
+    }
+
+    fn tx_pending(&mut self) -> Result<u8, Self::Error> {
+        let mut pending = 0;
+
+        for buffer in 0..TxBuf::ALL.len() {
+            if txb_ctrl(self, buffer)? & TXREQ != 0 {
+                pending |= 1 << buffer;
+            }
+        }
+
+        Ok(pending)
+    }
+
+    fn tx_status(&mut self, buffer: usize) -> Result<TxStatus, Self::Error> {
+        let ctrl = txb_ctrl(self, buffer)?;
+
+        Ok(if ctrl & TXREQ != 0 {
+            TxStatus::Pending
+        } else if ctrl & (ABTF | MLOA | TXERR) != 0 {
+            TxStatus::Failed
+        } else {
+            TxStatus::Sent
+        })
+    }
+
+    fn canstat(&mut self) -> Result<u8, Self::Error> {
+        self.read_byte::<CanStat>()
+    }
+
+    fn set_one_shot(&mut self, on: bool) -> Result<(), Self::Error> {
+        self.modify_byte(Register::CANCTRL, OSM, if on { OSM } else { 0 })
+    }
+
+    fn is_bus_off(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.read_byte::<reg::Eflg>()? & TXBO != 0)
+    }
+
+    fn error_counters(&mut self) -> Result<(u8, u8), Self::Error> {
+        Ok((self.read_byte::<reg::Tec>()?, self.read_byte::<reg::Rec>()?))
+    }
+
+    fn take_rx_overrun(&mut self) -> Result<bool, Self::Error> {
+        if self.read_byte::<reg::Eflg>()? & (RX0OVR | RX1OVR) == 0 {
+            return Ok(false);
+        }
+
+        self.modify_byte(Register::EFLG, RX0OVR | RX1OVR, 0)?;
+        Ok(true)
+    }
+
+    fn next_rx_buffer(&mut self) -> Result<Option<usize>, Self::Error> {
+        let flags = self.read_byte::<CanIntf>()?;
+
+        if flags & RX0IF != 0 {
+            Ok(Some(0))
+        } else if flags & RX1IF != 0 {
+            Ok(Some(1))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn discard_rx(&mut self, buffer: usize) -> Result<(), Self::Error> {
+        let flag = match buffer {
+            0 => RX0IF,
+            _ => RX1IF,
+        };
+
+        self.modify_byte(Register::CANINTF, flag, 0)
+    }
+
+    fn next_filter_hit(&mut self) -> Result<Option<u8>, Self::Error> {
+        match self.next_rx_buffer()? {
+            Some(0) => Ok(Some(filter_hit(0, self.read_byte::<Rxb0Ctrl>()?))),
+            Some(buffer) => Ok(Some(filter_hit(buffer, self.read_byte::<Rxb1Ctrl>()?))),
+            None => Ok(None),
+        }
+    }
+
+    fn enter_config_mode(&mut self) -> Result<(), Self::Error> {
+        change_opmod(self, CONFIGURATION_OPMOD)
+    }
+
+    fn exit_config_mode(&mut self, mode: OpMode) -> Result<(), Self::Error> {
+        change_opmod(self, opmod(mode))
+    }
+
+    fn set_wake_up(&mut self, on: bool) -> Result<(), Self::Error> {
+        self.modify_byte(Register::CANINTE, WAKIE, if on { WAKIE } else { 0 })
+    }
+
+    fn take_wake_up(&mut self) -> Result<bool, Self::Error> {
+        if self.read_byte::<CanIntf>()? & WAKIF == 0 {
+            return Ok(false);
+        }
+
+        self.modify_byte(Register::CANINTF, WAKIF, 0)?;
+        Ok(true)
+    }
+
+    fn oscillator_running(&mut self) -> Result<bool, Self::Error> {
+        let canstat = self.canstat()?;
+        Ok(canstat != 0xff && canstat & OPMOD_MASK == CONFIGURATION_OPMOD)
+    }
+
+    fn read_cnf(&mut self) -> Result<[u8; 3], Self::Error> {
+        Ok([
+            self.read_byte::<Cnf1>()?,
+            self.read_byte::<Cnf2>()?,
+            self.read_byte::<Cnf3>()?,
+        ])
+    }
+
+    fn apply_cnf(&mut self, cnf: [u8; 3], mode: OpMode) -> Result<(), Self::Error> {
+        let previous_opmod = self.canstat()? & OPMOD_MASK;
+        let previous_cnf = self.read_cnf()?;
+
+        let result = self
+            .enter_config_mode()
+            .and_then(|_| write_cnf(self, &cnf))
+            .and_then(|_| self.exit_config_mode(mode));
+
+        // The error returned is the one that made the apply fail, not one from rolling back.
+        if result.is_err() {
+            self.enter_config_mode()
+                .and_then(|_| write_cnf(self, &previous_cnf))
+                .and_then(|_| change_opmod(self, previous_opmod))
+                .ok();
+        }
+
+        result
+    }
+
+    fn quiesce(&mut self) -> Result<(), Self::Error> {
+        // ABAT clears TXREQ of every buffer not yet on the bus, one being transmitted finishes
+        // first.
+        self.modify_byte(Register::CANCTRL, ABAT, ABAT)?;
+
+        let mut aborted = false;
+        for _ in 0..MODE_CHANGE_POLLS {
+            if self.tx_pending()? == 0 {
+                aborted = true;
+                break;
+            }
+        }
+
+        // ABAT stays set until cleared and would abort everything requested after the reset.
+        self.modify_byte(Register::CANCTRL, ABAT, 0)?;
+
+        if !aborted {
+            return Err(Error::NewModeTimeout);
+        }
+
+        self.enter_config_mode()
+    }
+}
+
+/// CANCTRL.REQOP and CANSTAT.OPMOD value of `mode`, shifted into place.
+pub fn opmod(mode: OpMode) -> u8 {
+    (match mode {
+        OpMode::Normal => 0b000,
+        OpMode::Sleep => 0b001,
+        OpMode::Loopback => 0b010,
+        OpMode::ListenOnly => 0b011,
+        OpMode::Configuration => 0b100,
+    }) << 5
+}
+
+/// Reads TXBnCTRL of buffer 0, 1 or 2.
+fn txb_ctrl<M: RegisterBytes>(mcp2515: &mut M, buffer: usize) -> Result<u8, M::Error> {
+    match buffer {
+        0 => mcp2515.read_byte::<reg::Txb0Ctrl>(),
+        1 => mcp2515.read_byte::<reg::Txb1Ctrl>(),
+        _ => mcp2515.read_byte::<reg::Txb2Ctrl>(),
+    }
+}
+
+/// Requests CANCTRL.REQOP `opmod`, already shifted into place, and waits for CANSTAT.OPMOD to
+/// follow.
+fn change_opmod<M, SPIE, CSE>(mcp2515: &mut M, opmod: u8) -> Result<(), Error<SPIE, CSE>>
+where
+    M: RegisterBytes<Error = Error<SPIE, CSE>>,
+    SPIE: Debug,
+    CSE: Debug,
+{
+    mcp2515.modify_byte(Register::CANCTRL, OPMOD_MASK, opmod)?;
+
+    for _ in 0..MODE_CHANGE_POLLS {
+        if mcp2515.read_byte::<CanStat>()? & OPMOD_MASK == opmod {
+            return Ok(());
+        }
+    }
+
+    Err(Error::NewModeTimeout)
+}
+
+/// Writes CNF1..3, only possible in configuration mode.
+fn write_cnf<M: RegisterBytes>(mcp2515: &mut M, cnf: &[u8; 3]) -> Result<(), M::Error> {
+    mcp2515.write_byte(Register::CNF1, cnf[0])?;
+    mcp2515.write_byte(Register::CNF2, cnf[1])?;
+    mcp2515.write_byte(Register::CNF3, cnf[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::can::{ExtendedId, Frame, StandardId};
+
+    fn standard(id: u16) -> Id {
+        Id::Standard(StandardId::new(id).unwrap())
+    }
+
+    fn extended(id: u32) -> Id {
+        Id::Extended(ExtendedId::new(id).unwrap())
+    }
+
+    #[test]
+    fn priority_follows_the_two_most_significant_id_bits() {
+        assert!(TxPriority::from_id(&standard(0x000)) == TxPriority::Highest);
+        assert!(TxPriority::from_id(&standard(0x1ff)) == TxPriority::Highest);
+        assert!(TxPriority::from_id(&standard(0x200)) == TxPriority::High);
+        assert!(TxPriority::from_id(&standard(0x400)) == TxPriority::Low);
+        assert!(TxPriority::from_id(&standard(0x600)) == TxPriority::Lowest);
+        assert!(TxPriority::from_id(&standard(0x7ff)) == TxPriority::Lowest);
+
+        assert!(TxPriority::from_id(&extended(0x07ff_ffff)) == TxPriority::Highest);
+        assert!(TxPriority::from_id(&extended(0x0800_0000)) == TxPriority::High);
+        assert!(TxPriority::from_id(&extended(0x1000_0000)) == TxPriority::Low);
+        assert!(TxPriority::from_id(&extended(0x1fff_ffff)) == TxPriority::Lowest);
+    }
+
+    #[test]
+    fn frame_goes_to_the_first_free_buffer_with_its_priority() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        let frame = CanFrame::new(standard(0x123), &[1, 2]).unwrap();
+
+        chip.borrow_mut().set(Register::TXB0CTRL, TXREQ);
+
+        let buffer = mcp2515.send_with_priority(&frame, TxPriority::High).ok();
+        assert_eq!(buffer, Some(1));
+
+        let chip = chip.borrow();
+        assert_eq!(chip.get(Register::TXB1CTRL), TXREQ | TxPriority::High as u8);
+        assert_eq!(chip.get(Register::TXB1SIDH), (0x123 >> 3) as u8);
+        assert_eq!(chip.get(Register::TXB1DLC), 2);
+        assert_eq!(chip.get(Register::TXB1DATA), 1);
+    }
+
+    #[test]
+    fn all_buffers_pending_is_tx_busy() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        let frame = CanFrame::new(standard(0x123), &[]).unwrap();
+
+        for register in [Register::TXB0CTRL, Register::TXB1CTRL, Register::TXB2CTRL] {
+            chip.borrow_mut().set(register, TXREQ);
+        }
+
+        let result = mcp2515.send_with_priority(&frame, TxPriority::Low);
+        assert!(matches!(result, Err(Error::TxBusy)));
+        assert_eq!(mcp2515.tx_pending().ok(), Some(0b111));
+    }
+
+    #[test]
+    fn tx_status_decodes_the_buffer_control_register() {
+        let (mut mcp2515, chip) = fake_chip::new();
+
+        chip.borrow_mut().set(Register::TXB2CTRL, TXREQ);
+        assert!(mcp2515.tx_status(2).ok() == Some(TxStatus::Pending));
+
+        chip.borrow_mut().set(Register::TXB2CTRL, MLOA);
+        assert!(mcp2515.tx_status(2).ok() == Some(TxStatus::Failed));
+
+        chip.borrow_mut().set(Register::TXB2CTRL, TxPriority::Highest as u8);
+        assert!(mcp2515.tx_status(2).ok() == Some(TxStatus::Sent));
+    }
+
+    #[test]
+    fn filter_hit_ignores_the_rollover_bits() {
+        // RXB0CTRL keeps BUKT and its read-only copy next to FILHIT0.
+        assert_eq!(filter_hit(0, 0b0000_0110), 0);
+        assert_eq!(filter_hit(0, 0b0110_0111), 1);
+
+        assert_eq!(filter_hit(1, 0b0000_0000), 0);
+        assert_eq!(filter_hit(1, 0b0110_1101), 5);
+    }
+
+    #[test]
+    fn filter_hit_comes_from_the_buffer_read_next() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        assert_eq!(mcp2515.next_filter_hit().ok(), Some(None));
+
+        chip.borrow_mut().set(Register::RXB0CTRL, 0b0000_0001);
+        chip.borrow_mut().set(Register::RXB1CTRL, 0b0000_0100);
+
+        chip.borrow_mut().set(Register::CANINTF, RX1IF);
+        assert_eq!(mcp2515.next_filter_hit().ok(), Some(Some(4)));
+
+        chip.borrow_mut().set(Register::CANINTF, RX0IF | RX1IF);
+        assert_eq!(mcp2515.next_filter_hit().ok(), Some(Some(1)));
+    }
+
+    #[test]
+    fn quiesce_aborts_pending_frames_and_enters_configuration_mode() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        assert!(mcp2515.exit_config_mode(OpMode::Normal).is_ok());
+        chip.borrow_mut().set(Register::TXB0CTRL, TXREQ);
+        chip.borrow_mut().set(Register::TXB2CTRL, TXREQ);
+
+        assert!(mcp2515.quiesce().is_ok());
+
+        let chip = chip.borrow();
+        assert_eq!(chip.get(Register::TXB0CTRL), ABTF);
+        assert_eq!(chip.get(Register::TXB2CTRL), ABTF);
+        assert_eq!(chip.get(Register::CANCTRL) & ABAT, 0);
+        assert_eq!(chip.opmod(), CONFIGURATION_OPMOD);
+    }
+
+    #[test]
+    fn quiesce_times_out_on_a_frame_that_never_leaves() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        assert!(mcp2515.exit_config_mode(OpMode::Normal).is_ok());
+        chip.borrow_mut().set(Register::TXB1CTRL, TXREQ);
+        chip.borrow_mut().on_the_bus = Some(1);
+
+        assert!(matches!(mcp2515.quiesce(), Err(Error::NewModeTimeout)));
+
+        // ABAT is cleared either way, and the controller stays on the bus.
+        let chip = chip.borrow();
+        assert_eq!(chip.get(Register::TXB1CTRL), TXREQ);
+        assert_eq!(chip.get(Register::CANCTRL) & ABAT, 0);
+        assert_eq!(chip.opmod(), opmod(OpMode::Normal));
+    }
+
+    #[test]
+    fn apply_cnf_programs_the_timing_and_the_mode() {
+        let (mut mcp2515, chip) = fake_chip::new();
+
+        assert!(mcp2515.apply_cnf([0x03, 0xf0, 0x86], OpMode::Normal).is_ok());
+        assert_eq!(mcp2515.read_cnf().ok(), Some([0x03, 0xf0, 0x86]));
+        assert_eq!(chip.borrow().opmod(), opmod(OpMode::Normal));
+    }
+
+    #[test]
+    fn failed_apply_cnf_restores_the_previous_timing_and_mode() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        assert!(mcp2515.apply_cnf([0x00, 0xd0, 0x82], OpMode::ListenOnly).is_ok());
+        chip.borrow_mut().refused_opmods.push(opmod(OpMode::Normal));
+
+        let result = mcp2515.apply_cnf([0x03, 0xf0, 0x86], OpMode::Normal);
+
+        assert!(matches!(result, Err(Error::NewModeTimeout)));
+        assert_eq!(mcp2515.read_cnf().ok(), Some([0x00, 0xd0, 0x82]));
+        assert_eq!(chip.borrow().opmod(), opmod(OpMode::ListenOnly));
+    }
+
+    #[test]
+    fn timing_isnt_touched_when_configuration_mode_is_refused() {
+        let (mut mcp2515, chip) = fake_chip::new();
+        assert!(mcp2515.apply_cnf([0x00, 0xd0, 0x82], OpMode::Normal).is_ok());
+        chip.borrow_mut().refused_opmods.push(CONFIGURATION_OPMOD);
+
+        let result = mcp2515.apply_cnf([0x03, 0xf0, 0x86], OpMode::ListenOnly);
+
+        assert!(matches!(result, Err(Error::NewModeTimeout)));
+        assert_eq!(mcp2515.read_cnf().ok(), Some([0x00, 0xd0, 0x82]));
+        assert_eq!(chip.borrow().opmod(), opmod(OpMode::Normal));
+    }
+}
@@ -0,0 +1,199 @@
+//! A register-level stand-in for the MCP2515 on the other end of the SPI bus, so the driver and
+//! the extensions built on it run unchanged in host tests. It answers the SPI instructions the
+//! driver uses and models what the firmware relies on: the operation mode following REQOP, bit
+//! timing only being writable in configuration mode, ABAT aborting pending transmissions and
+//! the receive flags being cleared by bit modify.
+
+use core::convert::Infallible;
+use embedded_hal::blocking::{delay::DelayMs, spi::Transfer};
+use embedded_hal::digital::v2::OutputPin;
+use mcp2515::regs::Register;
+use mcp2515::MCP2515;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const WRITE: u8 = 0x02;
+const READ: u8 = 0x03;
+const BIT_MODIFY: u8 = 0x05;
+const READ_STATUS: u8 = 0xa0;
+const RESET: u8 = 0xc0;
+
+const CONFIGURATION_OPMOD: u8 = 0b100 << 5;
+const TXB_CTRL: [Register; 3] = [Register::TXB0CTRL, Register::TXB1CTRL, Register::TXB2CTRL];
+const TXREQ: u8 = 1 << 3;
+const ABTF: u8 = 1 << 6;
+const ABAT: u8 = 1 << 4;
+
+pub struct Chip {
+    pub registers: [u8; 128],
+    /// Operation modes the chip never enters, REQOP stays requested and OPMOD unchanged.
+    pub refused_opmods: Vec<u8>,
+    /// Transmit buffer whose frame is on the bus, ABAT can't abort it.
+    pub on_the_bus: Option<usize>,
+    /// Bytes clocked in since chip select went low.
+    transaction: Vec<u8>,
+}
+
+impl Chip {
+    pub fn get(&self, register: Register) -> u8 {
+        self.registers[register as usize]
+    }
+
+    pub fn set(&mut self, register: Register, value: u8) {
+        self.registers[register as usize] = value;
+    }
+
+    pub fn opmod(&self) -> u8 {
+        self.get(Register::CANSTAT) & 0b1110_0000
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; 128];
+        self.set(Register::CANSTAT, CONFIGURATION_OPMOD);
+        self.set(Register::CANCTRL, 0x87);
+    }
+
+    /// Takes the next byte clocked in and returns the one clocked out.
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.transaction.push(byte);
+        let position = self.transaction.len() - 1;
+
+        match self.transaction[0] {
+            READ if position >= 2 => {
+                let address = self.transaction[1] as usize + position - 2;
+                self.registers[address & 0x7f]
+            }
+            WRITE if position >= 2 => {
+                let address = self.transaction[1] as usize + position - 2;
+                self.write(address & 0x7f, byte);
+                0
+            }
+            BIT_MODIFY if position == 3 => {
+                let address = self.transaction[1] as usize;
+                let mask = self.transaction[2];
+                let value = (self.registers[address] & !mask) | (byte & mask);
+                self.write(address, value);
+                0
+            }
+            READ_STATUS if position >= 1 => self.status(),
+            RESET if position == 0 => {
+                self.reset();
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u8) {
+        let cnf = [Register::CNF3, Register::CNF2, Register::CNF1];
+        let configuring = self.opmod() == CONFIGURATION_OPMOD;
+
+        if cnf.iter().any(|r| *r as usize == address) && !configuring {
+            return;
+        }
+
+        // Status bits of CANSTAT, TXBnCTRL and EFLG can't be set from outside.
+        if address == Register::CANSTAT as usize {
+            return;
+        }
+
+        self.registers[address] = value;
+
+        if address == Register::CANCTRL as usize {
+            let reqop = value & 0b1110_0000;
+
+            if !self.refused_opmods.contains(&reqop) {
+                let icod = self.get(Register::CANSTAT) & 0b0000_1110;
+                self.set(Register::CANSTAT, reqop | icod);
+            }
+
+            if value & ABAT != 0 {
+                self.abort_all();
+            }
+        }
+    }
+
+    fn abort_all(&mut self) {
+        for (buffer, ctrl) in TXB_CTRL.iter().enumerate() {
+            let value = self.get(*ctrl);
+
+            if value & TXREQ != 0 && self.on_the_bus != Some(buffer) {
+                self.set(*ctrl, (value & !TXREQ) | ABTF);
+            }
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let intf = self.get(Register::CANINTF);
+        let mut status = intf & 0b11;
+
+        for (buffer, ctrl) in TXB_CTRL.iter().enumerate() {
+            if self.get(*ctrl) & TXREQ != 0 {
+                status |= 1 << (2 + 2 * buffer);
+            }
+
+            if intf & (1 << (2 + buffer)) != 0 {
+                status |= 1 << (3 + 2 * buffer);
+            }
+        }
+
+        status
+    }
+}
+
+pub type SharedChip = Rc<RefCell<Chip>>;
+
+pub struct Spi(SharedChip);
+
+impl Transfer<u8> for Spi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        let mut chip = self.0.borrow_mut();
+
+        for word in words.iter_mut() {
+            *word = chip.exchange(*word);
+        }
+
+        Ok(words)
+    }
+}
+
+pub struct ChipSelect(SharedChip);
+
+impl OutputPin for ChipSelect {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().transaction.clear();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+pub struct NoDelay;
+
+impl DelayMs<u8> for NoDelay {
+    fn delay_ms(&mut self, _ms: u8) {}
+}
+
+pub type FakeMcp2515 = MCP2515<Spi, ChipSelect, NoDelay>;
+
+/// A driver talking to a freshly reset chip, and the chip to inspect and set up the test with.
+pub fn new() -> (FakeMcp2515, SharedChip) {
+    let mut chip = Chip {
+        registers: [0; 128],
+        refused_opmods: Vec::new(),
+        on_the_bus: None,
+        transaction: Vec::new(),
+    };
+    chip.reset();
+
+    let chip = Rc::new(RefCell::new(chip));
+    let mcp2515 = MCP2515::new(Spi(chip.clone()), ChipSelect(chip.clone()), NoDelay);
+
+    (mcp2515, chip)
+}
@@ -0,0 +1,136 @@
+use super::bus_mode::BusMode;
+
+/// Attempts before a mode change is reported as failed.
+const MAX_ATTEMPTS: u8 = 5;
+/// Delay before the first retry, doubled for every further one. Times are in microseconds.
+const INITIAL_BACKOFF_US: u64 = 1_000;
+
+/// Retries mode changes that time out, with exponential backoff driven by the main loop's timer
+/// instead of blocking it.
+pub struct ModeRetry {
+    target: Option<BusMode>,
+    attempts: u8,
+    next_attempt: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ModeOutcome {
+    Applied(BusMode),
+    Retrying,
+    Failed(BusMode),
+}
+
+impl ModeRetry {
+    pub fn new() -> Self {
+        ModeRetry {
+            target: None,
+            attempts: 0,
+            next_attempt: 0,
+        }
+    }
+
+    /// Replaces any change still being retried. The first attempt is due right away.
+    pub fn request(&mut self, mode: BusMode, now: u64) {
+        self.target = Some(mode);
+        self.attempts = 0;
+        self.next_attempt = now;
+    }
+
+    /// Returns the mode to try if an attempt is due.
+    pub fn due(&self, now: u64) -> Option<BusMode> {
+        match self.target {
+            Some(mode) if now >= self.next_attempt => Some(mode),
+            _ => None,
+        }
+    }
+
+    /// Records the result of an attempt. Only transient failures are retried.
+    pub fn record(&mut self, applied: bool, transient: bool, now: u64) -> ModeOutcome {
+        let mode = match self.target {
+            Some(mode) => mode,
+            None => return ModeOutcome::Retrying,
+        };
+
+        self.attempts += 1;
+
+        if applied {
+            self.target = None;
+            return ModeOutcome::Applied(mode);
+        }
+
+        if !transient || self.attempts >= MAX_ATTEMPTS {
+            self.target = None;
+            return ModeOutcome::Failed(mode);
+        }
+
+        self.next_attempt = now + (INITIAL_BACKOFF_US << (self.attempts - 1));
+        ModeOutcome::Retrying
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_attempt_is_due_right_away() {
+        let mut retry = ModeRetry::new();
+        assert!(retry.due(0).is_none());
+
+        retry.request(BusMode::Normal, 100);
+        assert!(retry.due(100) == Some(BusMode::Normal));
+    }
+
+    #[test]
+    fn applied_mode_ends_the_retries() {
+        let mut retry = ModeRetry::new();
+        retry.request(BusMode::Normal, 0);
+
+        assert!(retry.record(true, false, 0) == ModeOutcome::Applied(BusMode::Normal));
+        assert!(retry.due(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn transient_failures_back_off_exponentially() {
+        let mut retry = ModeRetry::new();
+        retry.request(BusMode::Loopback, 0);
+
+        let mut now = 0;
+        let mut backoff = INITIAL_BACKOFF_US;
+
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(retry.record(false, true, now) == ModeOutcome::Retrying);
+            assert!(retry.due(now + backoff - 1).is_none());
+            assert!(retry.due(now + backoff) == Some(BusMode::Loopback));
+
+            now += backoff;
+            backoff *= 2;
+        }
+
+        assert!(retry.record(false, true, now) == ModeOutcome::Failed(BusMode::Loopback));
+        assert!(retry.due(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn other_failures_are_not_retried() {
+        let mut retry = ModeRetry::new();
+        retry.request(BusMode::Monitor, 0);
+
+        assert!(retry.record(false, false, 0) == ModeOutcome::Failed(BusMode::Monitor));
+    }
+
+    #[test]
+    fn new_request_replaces_the_one_being_retried() {
+        let mut retry = ModeRetry::new();
+        retry.request(BusMode::Normal, 0);
+        retry.record(false, true, 0);
+
+        retry.request(BusMode::Off, 10);
+        assert!(retry.due(10) == Some(BusMode::Off));
+
+        // The attempts start over.
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(retry.record(false, true, 10) == ModeOutcome::Retrying);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Receive filter rules beyond what the MCP2515's masks can express, programmed by the host with
+//! the SetRxFilter request and evaluated on every received frame.
+//!
+//! A program is a sequence of rules, a frame passes if any rule matches and an empty program
+//! passes everything. Ids are gs_usb can_id values, little endian, with the ExtendedId bit
+//! selecting the width. Remote and error bits are ignored.
+//!
+//! - `0x01 first last`: ids from `first` to `last` inclusive, both of the same width.
+//! - `0x02 n id0 .. idn-1`: any of the `n` listed ids.
+
+use super::usbd_gs::{HostCanId, RxFilterProgram};
+use scroll::{Pread, LE};
+
+/// Largest program the device stores.
+pub const MAX_PROGRAM_SIZE: usize = RxFilterProgram::MAX_SIZE;
+
+const RULE_RANGE: u8 = 0x01;
+const RULE_LIST: u8 = 0x02;
+
+/// The program is stored as received and parsed again for every frame, it's validated once
+/// when set.
+pub struct RxFilter {
+    program: [u8; MAX_PROGRAM_SIZE],
+    length: usize,
+}
+
+enum Rule<'a> {
+    Range(HostCanId, HostCanId),
+    List(&'a [u8]),
+}
+
+impl RxFilter {
+    pub fn new() -> Self {
+        RxFilter {
+            program: [0; MAX_PROGRAM_SIZE],
+            length: 0,
+        }
+    }
+
+    /// Replaces the program. A malformed or oversized program is refused and the previous one
+    /// stays in place.
+    pub fn set(&mut self, program: &[u8]) -> bool {
+        if program.len() > MAX_PROGRAM_SIZE || !is_valid(program) {
+            return false;
+        }
+
+        self.program[..program.len()].copy_from_slice(program);
+        self.length = program.len();
+        true
+    }
+
+    pub fn matches(&self, id: &HostCanId) -> bool {
+        let mut rest = &self.program[..self.length];
+
+        if rest.is_empty() {
+            return true;
+        }
+
+        while let Some((rule, next)) = next_rule(rest) {
+            let matched = match rule {
+                Rule::Range(first, last) => {
+                    first.same_width(id) && (first.id()..=last.id()).contains(&id.id())
+                }
+                Rule::List(ids) => ids
+                    .chunks_exact(4)
+                    .filter_map(|raw| raw.pread_with::<HostCanId>(0, LE).ok())
+                    .any(|listed| listed.same_id(id)),
+            };
+
+            if matched {
+                return true;
+            }
+
+            rest = next;
+        }
+
+        false
+    }
+}
+
+fn is_valid(program: &[u8]) -> bool {
+    let mut rest = program;
+
+    while !rest.is_empty() {
+        match next_rule(rest) {
+            Some((Rule::Range(first, last), _)) if !first.same_width(&last) => return false,
+            Some((_, next)) => rest = next,
+            None => return false,
+        }
+    }
+
+    true
+}
+
+/// Splits the first rule off `program`, None if it's truncated or of an unknown kind.
+fn next_rule(program: &[u8]) -> Option<(Rule<'_>, &[u8])> {
+    let (kind, rest) = program.split_first()?;
+
+    match *kind {
+        RULE_RANGE if rest.len() >= 8 => {
+            let first = rest.pread_with(0, LE).ok()?;
+            let last = rest.pread_with(4, LE).ok()?;
+            Some((Rule::Range(first, last), &rest[8..]))
+        }
+        RULE_LIST => {
+            let (count, rest) = rest.split_first()?;
+            let size = *count as usize * 4;
+
+            match rest.len() >= size {
+                true => Some((Rule::List(&rest[..size]), &rest[size..])),
+                false => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbd_gs::HostCanIdBits;
+
+    const EXTENDED: u32 = HostCanIdBits::ExtendedId as u32;
+
+    fn standard(id: u32) -> HostCanId {
+        HostCanId::new(id, &[]).unwrap()
+    }
+
+    fn extended(id: u32) -> HostCanId {
+        HostCanId::new(id, &[HostCanIdBits::ExtendedId]).unwrap()
+    }
+
+    /// Writes `rule` followed by `ids` as little-endian words, returning the program length.
+    fn program(buffer: &mut [u8], rule: &[u8], ids: &[u32]) -> usize {
+        buffer[..rule.len()].copy_from_slice(rule);
+
+        for (index, id) in ids.iter().enumerate() {
+            buffer[rule.len() + 4 * index..][..4].copy_from_slice(&id.to_le_bytes());
+        }
+
+        rule.len() + 4 * ids.len()
+    }
+
+    fn filter(rule: &[u8], ids: &[u32]) -> RxFilter {
+        let mut buffer = [0; MAX_PROGRAM_SIZE];
+        let length = program(&mut buffer, rule, ids);
+
+        let mut filter = RxFilter::new();
+        assert!(filter.set(&buffer[..length]));
+        filter
+    }
+
+    #[test]
+    fn empty_program_passes_everything() {
+        let filter = RxFilter::new();
+
+        assert!(filter.matches(&standard(0x123)));
+        assert!(filter.matches(&extended(0x123)));
+    }
+
+    #[test]
+    fn range_is_inclusive() {
+        let filter = filter(&[RULE_RANGE], &[0x100, 0x1ff]);
+
+        assert!(!filter.matches(&standard(0x0ff)));
+        assert!(filter.matches(&standard(0x100)));
+        assert!(filter.matches(&standard(0x1ff)));
+        assert!(!filter.matches(&standard(0x200)));
+    }
+
+    #[test]
+    fn range_only_matches_its_width() {
+        let filter = filter(&[RULE_RANGE], &[0x100 | EXTENDED, 0x1ff | EXTENDED]);
+
+        assert!(filter.matches(&extended(0x150)));
+        assert!(!filter.matches(&standard(0x150)));
+    }
+
+    #[test]
+    fn list_matches_any_listed_id() {
+        let filter = filter(&[RULE_LIST, 3], &[0x123, 0x456, 0x123 | EXTENDED]);
+
+        assert!(filter.matches(&standard(0x123)));
+        assert!(filter.matches(&standard(0x456)));
+        assert!(filter.matches(&extended(0x123)));
+        assert!(!filter.matches(&standard(0x124)));
+        assert!(!filter.matches(&extended(0x456)));
+    }
+
+    #[test]
+    fn list_ignores_the_remote_flag() {
+        let filter = filter(&[RULE_LIST, 1], &[0x123]);
+        let remote = HostCanId::new(0x123, &[HostCanIdBits::RemoteFrame]).unwrap();
+
+        assert!(filter.matches(&remote));
+    }
+
+    #[test]
+    fn frame_passes_if_any_rule_matches() {
+        let mut buffer = [0; MAX_PROGRAM_SIZE];
+        let range = program(&mut buffer, &[RULE_RANGE], &[0x100, 0x10f]);
+        let list = program(&mut buffer[range..], &[RULE_LIST, 1], &[0x700]);
+
+        let mut filter = RxFilter::new();
+        assert!(filter.set(&buffer[..range + list]));
+
+        assert!(filter.matches(&standard(0x105)));
+        assert!(filter.matches(&standard(0x700)));
+        assert!(!filter.matches(&standard(0x200)));
+    }
+
+    #[test]
+    fn malformed_program_keeps_the_previous_one() {
+        let mut filter = filter(&[RULE_LIST, 1], &[0x123]);
+
+        // Mixed widths, truncated list, unknown rule.
+        assert!(!filter.set(&[RULE_RANGE, 0, 1, 0, 0, 0xff, 1, 0, 0x80]));
+        assert!(!filter.set(&[RULE_LIST, 2, 0x23, 0x01, 0, 0]));
+        assert!(!filter.set(&[0x03]));
+
+        assert!(filter.matches(&standard(0x123)));
+        assert!(!filter.matches(&standard(0x124)));
+    }
+}
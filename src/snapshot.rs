@@ -0,0 +1,155 @@
+use embedded_hal::can::{Frame, Id};
+use mcp2515::frame::CanFrame;
+use ringbuffer::*;
+
+/// Time the button level has to stay put before a change counts, in microseconds.
+const DEBOUNCE_US: u64 = 20_000;
+
+/// Debounces a push button sampled from the main loop without blocking it.
+pub struct Debouncer {
+    stable: bool,
+    candidate: bool,
+    since: u64,
+}
+
+impl Debouncer {
+    pub fn new() -> Self {
+        Debouncer {
+            stable: false,
+            candidate: false,
+            since: 0,
+        }
+    }
+
+    /// Feeds the level sampled at `now`. Returns true once per press, after the button has been
+    /// held down for the debounce time.
+    pub fn update(&mut self, pressed: bool, now: u64) -> bool {
+        if pressed != self.candidate {
+            self.candidate = pressed;
+            self.since = now;
+            return false;
+        }
+
+        if self.candidate == self.stable || now - self.since < DEBOUNCE_US {
+            return false;
+        }
+
+        self.stable = self.candidate;
+        self.stable
+    }
+}
+
+struct TraceEntry {
+    timestamp: u64,
+    id: u32,
+    extended: bool,
+    remote: bool,
+    dlc: u8,
+    data: [u8; 8],
+}
+
+/// Captures the next frames received after being armed and dumps them over RTT, for field
+/// debugging without a host.
+pub struct Snapshot<const N: usize> {
+    trace: ConstGenericRingBuffer<TraceEntry, N>,
+    remaining: usize,
+}
+
+impl<const N: usize> Snapshot<N> {
+    pub fn new() -> Self {
+        Snapshot {
+            trace: ConstGenericRingBuffer::new(),
+            remaining: 0,
+        }
+    }
+
+    /// Starts capturing the next N frames, discarding any earlier capture.
+    pub fn arm(&mut self) {
+        self.trace.clear();
+        self.remaining = N;
+    }
+
+    pub fn record(&mut self, frame: &CanFrame, now: u64) {
+        if self.remaining == 0 {
+            return;
+        }
+
+        let (id, extended) = match frame.id() {
+            Id::Standard(id) => (id.as_raw() as u32, false),
+            Id::Extended(id) => (id.as_raw(), true),
+        };
+
+        let mut data: [u8; 8] = [0; 8];
+        data[..frame.data().len()].copy_from_slice(frame.data());
+
+        self.trace.push(TraceEntry {
+            timestamp: now,
+            id,
+            extended,
+            remote: frame.is_remote_frame(),
+            dlc: frame.dlc() as u8,
+            data,
+        });
+
+        self.remaining -= 1;
+
+        if self.remaining == 0 {
+            self.dump();
+        }
+    }
+
+    fn dump(&self) {
+        defmt::info!("snapshot of {} frames", self.trace.len());
+
+        for entry in self.trace.iter() {
+            defmt::info!(
+                "{=u64} {=u32:x} ext={=bool} rtr={=bool} [{=u8}] {:x}",
+                entry.timestamp,
+                entry.id,
+                entry.extended,
+                entry.remote,
+                entry.dlc,
+                &entry.data[..core::cmp::min(entry.dlc as usize, 8)]
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn press_counts_once_held_for_the_debounce_time() {
+        let mut button = Debouncer::new();
+
+        assert!(!button.update(true, 1_000));
+        assert!(!button.update(true, 1_000 + DEBOUNCE_US - 1));
+        assert!(button.update(true, 1_000 + DEBOUNCE_US));
+        assert!(!button.update(true, 1_000 + 2 * DEBOUNCE_US));
+    }
+
+    #[test]
+    fn bounce_restarts_the_debounce_time() {
+        let mut button = Debouncer::new();
+
+        assert!(!button.update(true, 0));
+        assert!(!button.update(false, 5_000));
+        assert!(!button.update(true, 10_000));
+        assert!(!button.update(true, DEBOUNCE_US));
+        assert!(button.update(true, 10_000 + DEBOUNCE_US));
+    }
+
+    #[test]
+    fn release_is_debounced_but_not_reported() {
+        let mut button = Debouncer::new();
+        button.update(true, 0);
+        assert!(button.update(true, DEBOUNCE_US));
+
+        assert!(!button.update(false, 100_000));
+        assert!(!button.update(false, 100_000 + DEBOUNCE_US));
+
+        assert!(!button.update(true, 200_000));
+        assert!(button.update(true, 200_000 + DEBOUNCE_US));
+    }
+}
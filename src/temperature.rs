@@ -0,0 +1,75 @@
+//! Die temperature of the RP2040 from its internal sensor on ADC channel 4, a stand-in for the
+//! board temperature on boards without a thermistor of their own.
+
+/// Time between two readings, in microseconds. A conversion takes 2 us, but the temperature
+/// changes slowly and the host only polls it.
+const SAMPLE_INTERVAL_US: u64 = 1_000_000;
+
+/// ADC reference voltage in microvolts and full scale of the 12 bit conversion.
+const ADC_VREF_UV: i64 = 3_300_000;
+const ADC_FULL_SCALE: i64 = 4096;
+
+/// Sensor voltage at 27 °C and its slope, from the RP2040 datasheet section 4.9.5.
+const SENSOR_UV_AT_27C: i64 = 706_000;
+const SENSOR_UV_PER_C: i64 = 1721;
+
+/// Converts a raw reading of the temperature sensor to thousandths of a degree Celsius. The
+/// products overflow an i32 for readings above a fifth of full scale, so it's done in i64.
+pub fn millicelsius_from_adc(raw: u16) -> i32 {
+    let microvolts = raw as i64 * ADC_VREF_UV / ADC_FULL_SCALE;
+    (27_000 - (microvolts - SENSOR_UV_AT_27C) * 1000 / SENSOR_UV_PER_C) as i32
+}
+
+/// Spaces the readings out. Times are timer ticks in microseconds.
+pub struct TemperatureSampler {
+    next_sample: u64,
+}
+
+impl TemperatureSampler {
+    pub fn new() -> Self {
+        TemperatureSampler { next_sample: 0 }
+    }
+
+    /// Whether a new reading is due, the first one right away.
+    pub fn due(&mut self, now: u64) -> bool {
+        if now < self.next_sample {
+            return false;
+        }
+
+        self.next_sample = now + SAMPLE_INTERVAL_US;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ADC reading closest to `microvolts`.
+    fn raw(microvolts: i64) -> u16 {
+        ((microvolts * ADC_FULL_SCALE + ADC_VREF_UV / 2) / ADC_VREF_UV) as u16
+    }
+
+    /// One ADC step is 806 uV, about 0.47 °C.
+    fn assert_close(millicelsius: i32, expected: i32) {
+        assert!((millicelsius - expected).abs() <= 500, "{} vs {}", millicelsius, expected);
+    }
+
+    #[test]
+    fn datasheet_point_is_27_degrees() {
+        assert_close(millicelsius_from_adc(raw(706_000)), 27_000);
+    }
+
+    #[test]
+    fn voltage_falls_1721_uv_per_degree() {
+        assert_close(millicelsius_from_adc(raw(706_000 - 1721 * 30)), 57_000);
+        assert_close(millicelsius_from_adc(raw(706_000 + 1721 * 20)), 7_000);
+    }
+
+    #[test]
+    fn full_scale_does_not_overflow() {
+        // 3299194 uV, far outside the sensor's range but still converted without wrapping.
+        assert_eq!(millicelsius_from_adc(4095), -1_479_794);
+        assert_eq!(millicelsius_from_adc(0), 437_226);
+    }
+}
@@ -0,0 +1,78 @@
+use embedded_hal::can::{Frame, Id};
+
+/// Where in a received frame its timestamp is taken.
+///
+/// The MCP2515 doesn't timestamp frames, the timer is read when the main loop picks a frame up,
+/// after its end of frame plus however long the loop took to get there. The start of frame is
+/// estimated from that by subtracting the frame's length on the bus without stuff bits, so it
+/// can be late by up to a fifth of the frame.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPoint {
+    EndOfFrame,
+    StartOfFrame,
+}
+
+/// Timestamp of a frame read at `read_at` (microseconds) on a bus running at `bit_rate`.
+pub fn capture<F: Frame>(point: TimestampPoint, read_at: u64, frame: &F, bit_rate: u32) -> u64 {
+    match point {
+        TimestampPoint::StartOfFrame if bit_rate > 0 => {
+            read_at.saturating_sub(frame_bits(frame) * 1_000_000 / bit_rate as u64)
+        }
+        _ => read_at,
+    }
+}
+
+/// Bits from SOF to the end of EOF, without stuff bits and the interframe space.
+fn frame_bits<F: Frame>(frame: &F) -> u64 {
+    let overhead = match frame.id() {
+        Id::Standard(_) => 44,
+        Id::Extended(_) => 64,
+    };
+
+    match frame.is_remote_frame() {
+        true => overhead,
+        false => overhead + 8 * frame.dlc() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::can::{ExtendedId, StandardId};
+    use mcp2515::frame::CanFrame;
+
+    fn standard(dlc: usize) -> CanFrame {
+        Frame::new(StandardId::new(0x123).unwrap(), &[0; 8][..dlc]).unwrap()
+    }
+
+    #[test]
+    fn end_of_frame_is_the_read_time() {
+        assert_eq!(capture(TimestampPoint::EndOfFrame, 10_000, &standard(8), 500_000), 10_000);
+    }
+
+    #[test]
+    fn start_of_frame_subtracts_the_frame_length() {
+        // 44 + 64 bits at 2 us each.
+        assert_eq!(capture(TimestampPoint::StartOfFrame, 10_000, &standard(8), 500_000), 9_784);
+        // 44 bits at 8 us each.
+        assert_eq!(capture(TimestampPoint::StartOfFrame, 10_000, &standard(0), 125_000), 9_648);
+    }
+
+    #[test]
+    fn remote_frame_has_no_data_bits() {
+        let frame: CanFrame = Frame::new_remote(ExtendedId::new(0x1234).unwrap(), 8).unwrap();
+
+        // 64 bits at 8 us each, whatever the DLC.
+        assert_eq!(capture(TimestampPoint::StartOfFrame, 10_000, &frame, 125_000), 9_488);
+    }
+
+    #[test]
+    fn start_of_frame_without_a_bit_rate_is_the_read_time() {
+        assert_eq!(capture(TimestampPoint::StartOfFrame, 10_000, &standard(8), 0), 10_000);
+    }
+
+    #[test]
+    fn start_of_frame_does_not_go_below_zero() {
+        assert_eq!(capture(TimestampPoint::StartOfFrame, 100, &standard(8), 10_000), 0);
+    }
+}
@@ -0,0 +1,128 @@
+use super::usbd_gs::{HostCanId, HostFrame, HostFrameFlags, TortureRequest};
+
+/// Id of the generated frames, the lowest priority standard id so they stand out in a capture.
+const TORTURE_ID: u32 = 0x7ff;
+
+/// Generates frames for the host as fast as the main loop runs, to stress the host and the
+/// overflow handling without a bus partner. Times are timer ticks in microseconds.
+pub struct Torture {
+    remaining: u32,
+    deadline: Option<u64>,
+    sequence: u32,
+    channel: u8,
+}
+
+impl Torture {
+    pub fn new() -> Self {
+        Torture {
+            remaining: 0,
+            deadline: None,
+            sequence: 0,
+            channel: 0,
+        }
+    }
+
+    /// Starts generating `count` frames, stopping early once `duration_ms` has passed unless
+    /// it's zero. A count of zero stops a run under way.
+    pub fn start(&mut self, request: TortureRequest, channel: u8, now: u64) {
+        self.remaining = request.count;
+        self.deadline = match request.duration_ms {
+            0 => None,
+            duration_ms => Some(now + duration_ms as u64 * 1000),
+        };
+        self.sequence = 0;
+        self.channel = channel;
+    }
+
+    /// Returns the next frame while the run lasts, one per call. The first four data bytes hold
+    /// a little-endian sequence number, so the host can tell which frames it lost.
+    pub fn poll(&mut self, now: u64) -> Option<HostFrame> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        if self.deadline.is_some_and(|deadline| now >= deadline) {
+            defmt::info!("torture run timed out after {} frames", self.sequence);
+            self.remaining = 0;
+            return None;
+        }
+
+        let mut bytes: [u8; 64] = [0; 64];
+        bytes[..4].copy_from_slice(&self.sequence.to_le_bytes());
+
+        self.remaining -= 1;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let can_id = HostCanId::new(TORTURE_ID, &[]).expect("the torture id is a standard id");
+
+        Some(HostFrame::new(
+            None,
+            can_id,
+            8,
+            self.channel,
+            HostFrameFlags::new(&[]),
+            bytes,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(frame: &HostFrame) -> u32 {
+        u32::from_le_bytes(frame.bytes[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn run_numbers_its_frames_and_stops_after_the_count() {
+        let mut torture = Torture::new();
+        let request = TortureRequest {
+            count: 3,
+            duration_ms: 0,
+        };
+        torture.start(request, 1, 0);
+
+        let frames: Vec<HostFrame> = (0..5).filter_map(|_| torture.poll(u64::MAX)).collect();
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames.iter().map(sequence).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(frames.iter().all(|frame| frame.can_id.id() == TORTURE_ID));
+        assert!(frames.iter().all(|frame| frame.channel == 1));
+        assert!(frames.iter().all(|frame| frame.echo_id == u32::MAX));
+    }
+
+    #[test]
+    fn run_stops_at_the_deadline() {
+        let mut torture = Torture::new();
+        let request = TortureRequest {
+            count: 100,
+            duration_ms: 2,
+        };
+        torture.start(request, 0, 1_000);
+
+        assert!(torture.poll(1_000).is_some());
+        assert!(torture.poll(2_999).is_some());
+        assert!(torture.poll(3_000).is_none());
+        // The run is over, not just paused until the clock goes back.
+        assert!(torture.poll(1_000).is_none());
+    }
+
+    #[test]
+    fn count_of_zero_stops_a_run() {
+        let mut torture = Torture::new();
+        let start = TortureRequest {
+            count: 100,
+            duration_ms: 0,
+        };
+        let stop = TortureRequest {
+            count: 0,
+            duration_ms: 0,
+        };
+        torture.start(start, 0, 0);
+        torture.poll(0);
+        torture.start(stop, 0, 0);
+
+        assert!(torture.poll(0).is_none());
+    }
+}
@@ -0,0 +1,58 @@
+//! USB servicing from the USBCTRL interrupt, so the host gets answers while the main loop is
+//! busy talking to the controller. The device and the port live in a critical-section mutex
+//! shared with the main loop, which borrows the port through `with_port` for short accesses and
+//! never across an SPI transfer.
+
+use super::usbd_gs::GsUsbPort;
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+use rp_pico::hal::{pac, pac::interrupt, usb::UsbBus};
+use usb_device::device::UsbDevice;
+
+pub type Port = GsUsbPort<'static, UsbBus, 1>;
+
+struct Usb {
+    device: UsbDevice<'static, UsbBus>,
+    port: Port,
+}
+
+static USB: Mutex<RefCell<Option<Usb>>> = Mutex::new(RefCell::new(None));
+
+/// Hands the device and port over to the interrupt handler and enables it.
+pub fn start(device: UsbDevice<'static, UsbBus>, port: Port) {
+    cortex_m::interrupt::free(|cs| {
+        USB.borrow(cs).replace(Some(Usb { device, port }));
+    });
+
+    // Safety: the handler only touches state behind the mutex.
+    unsafe { pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ) };
+}
+
+/// Runs `f` on the port with the USB interrupt held off. The handler can't run in the middle of
+/// `f`, so the port never sees a poll while the main loop is halfway through reading an event.
+/// Calling `with_port` from inside `f` panics on the RefCell rather than aliasing the port. The
+/// host is waiting meanwhile, so `f` shouldn't do more than move data in or out of the port.
+///
+/// The port only moves packets when polled, and the controller raises the interrupt for bus
+/// events alone. A frame written while the endpoints are idle would never be sent, and a full
+/// read buffer emptied by `f` would never take the next packet, so the interrupt is pended to
+/// poll the port once `f` returns.
+pub fn with_port<R>(f: impl FnOnce(&mut Port) -> R) -> R {
+    let result = cortex_m::interrupt::free(|cs| {
+        let mut usb = USB.borrow(cs).borrow_mut();
+        f(&mut usb.as_mut().expect("USB started before the main loop").port)
+    });
+
+    pac::NVIC::pend(pac::Interrupt::USBCTRL_IRQ);
+    result
+}
+
+#[interrupt]
+fn USBCTRL_IRQ() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(usb) = USB.borrow(cs).borrow_mut().as_mut() {
+            usb.device.poll(&mut [&mut usb.port]);
+            usb.port.set_usb_state(usb.device.state());
+        }
+    });
+}
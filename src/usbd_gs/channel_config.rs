@@ -1,4 +1,4 @@
-use scroll::Pwrite;
+use scroll::{Pread, Pwrite};
 
 #[derive(Clone, Copy)]
 pub struct Channel {
@@ -8,17 +8,82 @@ pub struct Channel {
     pub data_constraints: Option<ChannelConstraints>,
 }
 
-#[derive(Pwrite, Clone, Copy)]
+/// Why a board's channel definition doesn't hold together.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ChannelConfigError {
+    /// FD is advertised without data-phase constraints, BtConstExt would have to make them up.
+    MissingDataPhase,
+    /// Data-phase constraints are given for a channel that doesn't advertise FD, nothing would
+    /// report them.
+    UnusedDataPhase,
+    /// A minimum is above its maximum, or fclk_can, sjw_max or brp_inc is zero.
+    InvalidRange,
+}
+
+impl Channel {
+    /// Checks that the features and constraints agree with each other.
+    pub fn validate(&self) -> Result<(), ChannelConfigError> {
+        let fd = self.features.is_set(ChannelFeaturesBit::Fd);
+
+        match &self.data_constraints {
+            None if fd => Err(ChannelConfigError::MissingDataPhase),
+            Some(_) if !fd => Err(ChannelConfigError::UnusedDataPhase),
+            _ if self.fclk_can == 0 => Err(ChannelConfigError::InvalidRange),
+            data_constraints => {
+                match core::iter::once(&self.constraints)
+                    .chain(data_constraints)
+                    .all(ChannelConstraints::is_valid)
+                {
+                    true => Ok(()),
+                    false => Err(ChannelConfigError::InvalidRange),
+                }
+            }
+        }
+    }
+}
+
+/// Features that are never advertised, whatever the board definition asks for.
+///
+/// ReqUsbQuirkLpc546xx tells the host to switch to the request numbering of the LPC546xx based
+/// CANtact Pro firmware, where DataBitTiming overlaps GetUserId. The RP2040 has no such quirk
+/// and this firmware uses the standard numbering, so advertising it would break FD setup.
+const UNSUPPORTED_FEATURES: u32 = ChannelFeaturesBit::ReqUsbQuirkLpc546xx as u32;
+
+#[derive(Pread, Pwrite, Clone, Copy)]
 pub struct ChannelFeatures(u32);
 
 impl ChannelFeatures {
     pub fn new(bits: &[ChannelFeaturesBit]) -> Self {
-        ChannelFeatures(bits.iter().fold(0, |l, r| l | (*r as u32)))
+        let requested = bits.iter().fold(0, |l, r| l | (*r as u32));
+
+        debug_assert!(
+            requested & UNSUPPORTED_FEATURES == 0,
+            "channel requests a feature this hardware can't provide"
+        );
+
+        ChannelFeatures(requested & !UNSUPPORTED_FEATURES)
     }
 
     pub fn is_set(&self, bit: ChannelFeaturesBit) -> bool {
         self.0 & bit as u32 != 0
     }
+
+    /// Whether every feature of `other` is set here as well.
+    pub fn contains(&self, other: ChannelFeatures) -> bool {
+        other.0 & !self.0 == 0
+    }
+
+    /// Drops the features `capable` doesn't include, so a board definition can't advertise
+    /// something the controller behind the channel can't do.
+    pub fn restrict(&self, capable: ChannelFeatures) -> ChannelFeatures {
+        let dropped = self.0 & !capable.0;
+
+        if dropped != 0 {
+            defmt::warn!("not advertising unsupported channel features {=u32:x}", dropped);
+        }
+
+        ChannelFeatures(self.0 & capable.0)
+    }
 }
 
 #[repr(u32)]
@@ -35,6 +100,11 @@ pub enum ChannelFeaturesBit {
     Fd = 1 << 8,
     ReqUsbQuirkLpc546xx = 1 << 9,
     BtConstExt = 1 << 10,
+    Termination = 1 << 11,
+    BerrReporting = 1 << 12,
+    /// Not a kernel feature but a vendor extension: the channel accepts the NoEcho mode flag.
+    /// The Linux driver ignores feature bits it doesn't know.
+    NoEcho = 1 << 31,
 }
 
 #[derive(Pwrite, Clone, Copy)]
@@ -48,3 +118,136 @@ pub struct ChannelConstraints {
     pub brp_max: u32,
     pub brp_inc: u32,
 }
+
+impl ChannelConstraints {
+    fn is_valid(&self) -> bool {
+        self.tseg1_min <= self.tseg1_max
+            && self.tseg2_min <= self.tseg2_max
+            && self.brp_min <= self.brp_max
+            && self.sjw_max > 0
+            && self.brp_inc > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constraints() -> ChannelConstraints {
+        ChannelConstraints {
+            tseg1_min: 3,
+            tseg1_max: 8,
+            tseg2_min: 2,
+            tseg2_max: 8,
+            sjw_max: 4,
+            brp_min: 1,
+            brp_max: 64,
+            brp_inc: 1,
+        }
+    }
+
+    fn channel(features: &[ChannelFeaturesBit]) -> Channel {
+        Channel {
+            features: ChannelFeatures::new(features),
+            fclk_can: 8_000_000,
+            constraints: constraints(),
+            data_constraints: None,
+        }
+    }
+
+    #[test]
+    fn classic_channel_is_valid() {
+        assert!(channel(&[ChannelFeaturesBit::ListenOnly]).validate() == Ok(()));
+    }
+
+    #[test]
+    fn fd_needs_data_constraints_and_only_fd_uses_them() {
+        let mut fd = channel(&[ChannelFeaturesBit::Fd]);
+        assert!(fd.validate() == Err(ChannelConfigError::MissingDataPhase));
+
+        fd.data_constraints = Some(constraints());
+        assert!(fd.validate() == Ok(()));
+
+        let mut classic = channel(&[]);
+        classic.data_constraints = Some(constraints());
+        assert!(classic.validate() == Err(ChannelConfigError::UnusedDataPhase));
+    }
+
+    #[test]
+    fn inverted_or_zero_constraints_are_invalid() {
+        let mut zero_clock = channel(&[]);
+        zero_clock.fclk_can = 0;
+        assert!(zero_clock.validate() == Err(ChannelConfigError::InvalidRange));
+
+        let invalid = [
+            ChannelConstraints {
+                tseg1_min: 9,
+                ..constraints()
+            },
+            ChannelConstraints {
+                tseg2_max: 1,
+                ..constraints()
+            },
+            ChannelConstraints {
+                brp_min: 65,
+                ..constraints()
+            },
+            ChannelConstraints {
+                sjw_max: 0,
+                ..constraints()
+            },
+            ChannelConstraints {
+                brp_inc: 0,
+                ..constraints()
+            },
+        ];
+
+        for invalid in invalid {
+            let mut nominal = channel(&[]);
+            nominal.constraints = invalid;
+            assert!(nominal.validate() == Err(ChannelConfigError::InvalidRange));
+
+            let mut data = channel(&[ChannelFeaturesBit::Fd]);
+            data.data_constraints = Some(invalid);
+            assert!(data.validate() == Err(ChannelConfigError::InvalidRange));
+        }
+    }
+
+    // Debug builds catch the board definition asking for it, release builds drop it.
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        should_panic(expected = "channel requests a feature this hardware can't provide")
+    )]
+    fn usb_quirk_is_never_advertised() {
+        let features = ChannelFeatures::new(&[
+            ChannelFeaturesBit::ListenOnly,
+            ChannelFeaturesBit::ReqUsbQuirkLpc546xx,
+        ]);
+
+        assert!(!features.is_set(ChannelFeaturesBit::ReqUsbQuirkLpc546xx));
+        assert!(features.is_set(ChannelFeaturesBit::ListenOnly));
+    }
+
+    #[test]
+    fn restrict_keeps_only_what_the_controller_can_do() {
+        let requested = ChannelFeatures::new(&[
+            ChannelFeaturesBit::ListenOnly,
+            ChannelFeaturesBit::Fd,
+            ChannelFeaturesBit::Termination,
+        ]);
+        let capable = ChannelFeatures::new(&[
+            ChannelFeaturesBit::ListenOnly,
+            ChannelFeaturesBit::Loopback,
+        ]);
+
+        let advertised = requested.restrict(capable);
+
+        assert!(advertised.is_set(ChannelFeaturesBit::ListenOnly));
+        assert!(!advertised.is_set(ChannelFeaturesBit::Fd));
+        assert!(!advertised.is_set(ChannelFeaturesBit::Termination));
+        // Restricting never adds a feature nobody asked for.
+        assert!(!advertised.is_set(ChannelFeaturesBit::Loopback));
+        assert!(requested.contains(advertised) && capable.contains(advertised));
+    }
+}
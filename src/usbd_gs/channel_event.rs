@@ -1,11 +1,70 @@
-use crate::Channel;
+use crate::{Channel, ChannelFeatures, HostCanId};
+use ringbuffer::*;
 use scroll::Pread;
 
+/// A control request from the host. The channel index is checked against the class's channel
+/// count before the event is created, but consumers should still use `get` rather than indexing.
 pub enum ChannelEvent {
     BitTiming(BitTiming, usize),
     DataBitTiming(BitTiming, usize),
     ChannelMode(ChannelMode, usize),
     Identify(ChannelIdentify, usize),
+    IdRemap(IdRemapEntry, usize),
+    Heartbeat(HeartbeatConfig, usize),
+    TransmitOnly(ChannelSwitch, usize),
+    /// Whether received remote frames are dropped instead of delivered to the host.
+    DropRemote(ChannelSwitch, usize),
+    Burst(BurstRequest, usize),
+    Torture(TortureRequest, usize),
+    Termination(TerminationState, usize),
+    RxFilter(RxFilterProgram, usize),
+    /// The host switched the channel's features, already applied to what the class accepts.
+    Features(ChannelFeatures, usize),
+    /// A frame to transmit right away, bypassing the transmit queue.
+    SendFrame(crate::HostFrame, usize),
+    /// A synthetic frame to deliver to the host as if it was received on the channel.
+    #[cfg(feature = "frame-injection")]
+    InjectFrame(crate::HostFrame, usize),
+    /// The host switched the interface to the low-power alternate setting (true) or back.
+    LowPower(bool),
+    /// The USB bus was reset, so whatever the host configured no longer applies.
+    HostReset,
+}
+
+/// Control events waiting for the main loop, oldest first. Unlike the ring buffer underneath,
+/// a full queue refuses new events rather than dropping the oldest one.
+pub struct ControlEvents {
+    events: ConstGenericRingBuffer<ChannelEvent, 4>,
+}
+
+impl ControlEvents {
+    pub fn new() -> Self {
+        ControlEvents {
+            events: ConstGenericRingBuffer::new(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.events.is_full()
+    }
+
+    /// Fails, leaving the queue unchanged, while it's full.
+    pub fn push(&mut self, event: ChannelEvent) -> bool {
+        if self.events.is_full() {
+            return false;
+        }
+
+        self.events.push(event);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<ChannelEvent> {
+        self.events.dequeue()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
 }
 
 #[derive(Pread)]
@@ -45,6 +104,10 @@ impl ChannelFlags {
     pub fn is_set(&self, bit: ChannelFlagsBit) -> bool {
         self.0 & bit as u32 != 0
     }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
 }
 
 #[repr(u32)]
@@ -57,14 +120,176 @@ pub enum ChannelFlagsBit {
     HwTimestamp = 1 << 4,
     PadPktsToMaxPktSize = 1 << 7,
     Fd = 1 << 8,
+    BerrReporting = 1 << 12,
+    /// Not a kernel flag but a vendor extension: transmitted frames aren't echoed. Only accepted
+    /// on channels advertising ChannelFeaturesBit::NoEcho, for hosts that track their frames
+    /// themselves. The Linux driver releases its transmit slots on the echo and never sets it.
+    NoEcho = 1 << 31,
 }
 
 #[derive(Pread)]
 pub struct ChannelIdentify(u32);
 
 impl ChannelIdentify {
-    #[allow(dead_code)]
     pub fn is_on(&self) -> bool {
         self.0 != 0
     }
 }
+
+/// One entry of the id translation table applied to frames sent by the host. Setting an entry
+/// whose `from` and `to` refer to the same id clears it.
+#[derive(Pread)]
+pub struct IdRemapEntry {
+    pub index: u32,
+    pub from: HostCanId,
+    pub to: HostCanId,
+}
+
+/// A frame the device sends on its own every `interval_ms` while the channel is started. An
+/// interval of zero disables it.
+#[derive(Pread)]
+pub struct HeartbeatConfig {
+    pub can_id: HostCanId,
+    pub interval_ms: u32,
+    pub can_dlc: u8,
+    _reserved: [u8; 3],
+    pub data: [u8; 8],
+}
+
+/// Payload of vendor requests turning a channel option on or off.
+#[derive(Pread)]
+pub struct ChannelSwitch(u32);
+
+impl ChannelSwitch {
+    pub fn is_on(&self) -> bool {
+        self.0 != 0
+    }
+}
+
+/// Asks for the next `count` frames from the host to be sent back to back.
+#[derive(Pread)]
+pub struct BurstRequest {
+    pub count: u32,
+}
+
+/// Asks for `count` generated frames delivered to the host as if received, for at most
+/// `duration_ms` milliseconds unless it's zero.
+#[derive(Pread)]
+pub struct TortureRequest {
+    pub count: u32,
+    pub duration_ms: u32,
+}
+
+/// gs_device_termination_state: a little-endian u32, GS_CAN_TERMINATION_STATE_OFF (0) or
+/// GS_CAN_TERMINATION_STATE_ON (1).
+#[derive(Pread, Clone, Copy)]
+pub struct TerminationState(u32);
+
+impl TerminationState {
+    pub const OFF: TerminationState = TerminationState(0);
+
+    pub fn is_on(&self) -> bool {
+        self.0 != 0
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 4] {
+        self.0.to_le_bytes()
+    }
+}
+
+/// Receive filter program as sent by the host, see the SetRxFilter request.
+pub struct RxFilterProgram {
+    pub length: usize,
+    pub bytes: [u8; RxFilterProgram::MAX_SIZE],
+}
+
+impl RxFilterProgram {
+    pub const MAX_SIZE: usize = 64;
+
+    /// Fails for programs longer than MAX_SIZE.
+    pub fn new(data: &[u8]) -> Option<Self> {
+        let mut bytes = [0; RxFilterProgram::MAX_SIZE];
+        bytes.get_mut(..data.len())?.copy_from_slice(data);
+
+        Some(RxFilterProgram {
+            length: data.len(),
+            bytes,
+        })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scroll::LE;
+
+    fn bit_timing() -> ChannelEvent {
+        let timing = [[1, 0, 0, 0], [7, 0, 0, 0], [6, 0, 0, 0], [1, 0, 0, 0], [2, 0, 0, 0]];
+        ChannelEvent::BitTiming(timing.concat().pread_with(0, LE).unwrap(), 0)
+    }
+
+    fn start() -> ChannelEvent {
+        ChannelEvent::ChannelMode([1, 0, 0, 0, 0, 0, 0, 0].pread_with(0, LE).unwrap(), 0)
+    }
+
+    #[test]
+    fn bit_timing_and_mode_are_drained_in_one_go() {
+        let mut events = ControlEvents::new();
+        assert!(events.push(bit_timing()));
+        assert!(events.push(start()));
+
+        let mut drained = 0;
+
+        while let Some(event) = events.pop() {
+            match (drained, event) {
+                (0, ChannelEvent::BitTiming(timing, 0)) => assert_eq!(timing.brp, 2),
+                (1, ChannelEvent::ChannelMode(mode, 0)) => assert!(mode.is_on()),
+                _ => panic!("event {} out of order", drained),
+            }
+
+            drained += 1;
+        }
+
+        assert_eq!(drained, 2);
+    }
+
+    #[test]
+    fn full_queue_refuses_events_and_keeps_the_oldest() {
+        let mut events = ControlEvents::new();
+        assert!(events.push(bit_timing()));
+
+        while !events.is_full() {
+            assert!(events.push(ChannelEvent::LowPower(false)));
+        }
+
+        assert!(!events.push(start()));
+        assert!(matches!(events.pop(), Some(ChannelEvent::BitTiming(..))));
+    }
+
+    #[test]
+    fn cleared_queue_is_empty() {
+        let mut events = ControlEvents::new();
+        events.push(bit_timing());
+        events.push(start());
+        events.clear();
+
+        assert!(events.pop().is_none());
+    }
+
+    #[test]
+    fn termination_state_is_a_little_endian_u32() {
+        assert_eq!(core::mem::size_of::<TerminationState>(), 4);
+
+        let on: TerminationState = [1, 0, 0, 0].pread_with(0, LE).unwrap();
+        assert!(on.is_on());
+        assert_eq!(on.to_le_bytes(), [1, 0, 0, 0]);
+
+        let off: TerminationState = [0, 0, 0, 0].pread_with(0, LE).unwrap();
+        assert!(!off.is_on());
+        assert_eq!(TerminationState::OFF.to_le_bytes(), [0, 0, 0, 0]);
+    }
+}
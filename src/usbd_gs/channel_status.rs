@@ -0,0 +1,34 @@
+use scroll::{Pwrite, LE};
+
+/// Everything known about a channel in one place, read by the host with the GetChannelStatus
+/// request. The traffic counters are the device-wide ones from Stats. Filters and user ids
+/// aren't supported, so they have no fields here.
+#[derive(Pwrite, Clone, Copy, Default)]
+pub struct ChannelStatus {
+    /// 1 when the controller runs in a mode that takes part in bus traffic.
+    pub running: u8,
+    pub identify: u8,
+    pub termination: u8,
+    pub _reserved: u8,
+    /// Flags of the last mode the host started the channel with.
+    pub mode_flags: u32,
+    /// Nominal bit rate, and the data-phase bit rate of FD channels. Both are the rates the
+    /// programmed timing achieves, zero before it was set or for a channel without FD.
+    pub bit_rate: u32,
+    pub rx_frames: u32,
+    pub tx_frames: u32,
+    pub tx_errors: u32,
+    pub data_bit_rate: u32,
+}
+
+impl ChannelStatus {
+    pub const fn size() -> usize {
+        core::mem::size_of::<Self>()
+    }
+
+    pub fn packed(&self) -> Result<[u8; ChannelStatus::size()], scroll::Error> {
+        let mut ret_value: [u8; ChannelStatus::size()] = [0; ChannelStatus::size()];
+        ret_value.pwrite_with(self, 0, LE)?;
+        Ok(ret_value)
+    }
+}
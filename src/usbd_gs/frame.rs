@@ -1,6 +1,6 @@
-use scroll::Pread;
+use scroll::{Pread, Pwrite};
 
-#[derive(Pread)]
+#[derive(Pread, Pwrite)]
 pub struct HostFrame {
     pub echo_id: u32,
     pub can_id: HostCanId,
@@ -30,17 +30,39 @@ impl HostFrame {
             bytes,
         }
     }
+
+    /// Sets the byte gs_usb reserves after the flags. Hosts ignore it, so it can only carry
+    /// information for tooling that knows where to look.
+    #[allow(dead_code)]
+    pub fn set_reserved(&mut self, value: u8) {
+        self._reserved = value;
+    }
 }
 
-#[derive(Pread)]
+#[derive(Pread, Pwrite, Clone, Copy, PartialEq, Eq)]
 pub struct HostCanId(u32);
 
+const STANDARD_ID_MASK: u32 = 0x7ff;
+
 impl HostCanId {
-    pub fn new(raw_id: u32, bits: &[HostCanIdBits]) -> Self {
-        HostCanId(
+    /// Fails if `raw_id` doesn't fit in 11 bits, or in 29 bits when ExtendedId is set, rather
+    /// than masking it into a different id.
+    pub fn new(raw_id: u32, bits: &[HostCanIdBits]) -> Option<Self> {
+        let can_id = HostCanId(
             bits.iter()
                 .fold(raw_id & 0x1fffffff, |l, r| l | (*r as u32)),
-        )
+        );
+
+        match can_id.id() == raw_id && can_id.has_valid_width() {
+            true => Some(can_id),
+            false => None,
+        }
+    }
+
+    /// Whether the id fits the width selected by the ExtendedId flag. Ids read from the host
+    /// aren't checked, a standard id may have bits above bit 10 set.
+    pub fn has_valid_width(&self) -> bool {
+        self.is_set(HostCanIdBits::ExtendedId) || self.id() <= STANDARD_ID_MASK
     }
 
     pub fn id(&self) -> u32 {
@@ -50,18 +72,37 @@ impl HostCanId {
     pub fn is_set(&self, bit: HostCanIdBits) -> bool {
         self.0 & (bit as u32) != 0
     }
+
+    /// Whether both are standard or both are extended ids.
+    pub fn same_width(&self, other: &HostCanId) -> bool {
+        self.is_set(HostCanIdBits::ExtendedId) == other.is_set(HostCanIdBits::ExtendedId)
+    }
+
+    /// Whether both refer to the same CAN identifier, ignoring the remote and error flags.
+    pub fn same_id(&self, other: &HostCanId) -> bool {
+        let mask = 0x1fffffff | HostCanIdBits::ExtendedId as u32;
+        self.0 & mask == other.0 & mask
+    }
+
+    /// Returns a copy with the identifier (and its width) taken from `other`, keeping the remote
+    /// and error flags of `self`.
+    pub fn with_id_of(&self, other: &HostCanId) -> HostCanId {
+        let mask = 0x1fffffff | HostCanIdBits::ExtendedId as u32;
+        HostCanId((self.0 & !mask) | (other.0 & mask))
+    }
 }
 
 #[repr(u32)]
 #[derive(Clone, Copy)]
 pub enum HostCanIdBits {
-    #[allow(dead_code)]
     ErrorFrame = 1 << 29,
     RemoteFrame = 1 << 30,
     ExtendedId = 1 << 31,
 }
 
-#[derive(Pread)]
+/// The flags byte is kept whole, so bits without a HostFrameFlagsBits value still survive
+/// reading a frame from the host and writing it back as an echo.
+#[derive(Pread, Pwrite)]
 pub struct HostFrameFlags(u8);
 
 impl HostFrameFlags {
@@ -69,7 +110,6 @@ impl HostFrameFlags {
         HostFrameFlags(bits.iter().fold(0, |l, r| l | (*r as u8)))
     }
 
-    #[allow(dead_code)]
     pub fn is_set(&self, bit: HostFrameFlagsBits) -> bool {
         self.0 & (bit as u8) != 0
     }
@@ -79,6 +119,9 @@ impl HostFrameFlags {
     }
 }
 
+/// GS_CAN_FLAG_* from the kernel's gs_usb driver, which defines no others. FD with and without
+/// bit rate switching is Fd alone or Fd with Brs, local echo is marked by the echo id rather
+/// than a flag.
 #[repr(u8)]
 #[derive(Clone, Copy)]
 #[allow(dead_code)]
@@ -88,3 +131,120 @@ pub enum HostFrameFlagsBits {
     Brs = 1 << 2,
     Esi = 1 << 3,
 }
+
+/// Data lengths of CAN FD frames with DLC codes 9 to 15.
+const FD_LENGTHS: [usize; 7] = [12, 16, 20, 24, 32, 48, 64];
+
+/// DLC code of an FD frame carrying `len` bytes, the inverse of the FD length table. A length
+/// between two FD lengths rounds up to the next one, the data is then padded with zeros to it.
+/// Lengths above 64 get the largest code. Lengths up to 8 are their own code, so classic frames
+/// go through it as well.
+pub fn len_to_dlc(len: usize) -> u8 {
+    match len {
+        0..=8 => len as u8,
+        _ => FD_LENGTHS
+            .iter()
+            .position(|fd_len| len <= *fd_len)
+            .map_or(15, |index| 9 + index as u8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scroll::LE;
+
+    #[test]
+    fn classic_lengths_are_their_own_dlc() {
+        for len in 0..=8 {
+            assert_eq!(len_to_dlc(len), len as u8);
+        }
+    }
+
+    #[test]
+    fn fd_lengths_map_to_their_dlc() {
+        let lengths = [12, 16, 20, 24, 32, 48, 64];
+
+        for (len, dlc) in lengths.into_iter().zip(9..) {
+            assert_eq!(len_to_dlc(len), dlc);
+        }
+    }
+
+    #[test]
+    fn lengths_between_fd_lengths_round_up() {
+        assert_eq!(len_to_dlc(9), 9);
+        assert_eq!(len_to_dlc(11), 9);
+        assert_eq!(len_to_dlc(13), 10);
+        assert_eq!(len_to_dlc(17), 11);
+        assert_eq!(len_to_dlc(21), 12);
+        assert_eq!(len_to_dlc(25), 13);
+        assert_eq!(len_to_dlc(33), 14);
+        assert_eq!(len_to_dlc(49), 15);
+        assert_eq!(len_to_dlc(63), 15);
+    }
+
+    #[test]
+    fn lengths_above_64_get_the_largest_dlc() {
+        assert_eq!(len_to_dlc(65), 15);
+        assert_eq!(len_to_dlc(usize::MAX), 15);
+    }
+
+    #[test]
+    fn standard_id_fits_11_bits() {
+        assert!(HostCanId::new(0x7ff, &[]).is_some());
+        assert!(HostCanId::new(0x800, &[]).is_none());
+    }
+
+    #[test]
+    fn extended_id_fits_29_bits() {
+        let can_id = HostCanId::new(0x1fffffff, &[HostCanIdBits::ExtendedId]).unwrap();
+
+        assert_eq!(can_id.id(), 0x1fffffff);
+        assert!(HostCanId::new(0x20000000, &[HostCanIdBits::ExtendedId]).is_none());
+    }
+
+    #[test]
+    fn id_is_not_masked_into_another_one() {
+        assert!(HostCanId::new(0x80000123, &[HostCanIdBits::ExtendedId]).is_none());
+        assert!(HostCanId::new(0x40000123, &[]).is_none());
+    }
+
+    #[test]
+    fn width_of_an_id_from_the_host_is_checked() {
+        let standard: HostCanId = 0x800u32.to_le_bytes().pread_with(0, LE).unwrap();
+        let extended: HostCanId = 0x80000800u32.to_le_bytes().pread_with(0, LE).unwrap();
+
+        assert!(!standard.has_valid_width());
+        assert!(extended.has_valid_width());
+    }
+
+    #[test]
+    fn flags_use_the_kernel_bits() {
+        assert_eq!(HostFrameFlagsBits::Overflow as u8, 0x01);
+        assert_eq!(HostFrameFlagsBits::Fd as u8, 0x02);
+        assert_eq!(HostFrameFlagsBits::Brs as u8, 0x04);
+        assert_eq!(HostFrameFlagsBits::Esi as u8, 0x08);
+    }
+
+    #[test]
+    fn every_flag_survives_a_round_trip() {
+        let mut bytes = [0; 76];
+        bytes[8] = 15;
+        // Every known flag and a bit no kernel defines yet.
+        bytes[10] = 0x0f | 0x80;
+
+        let frame: HostFrame = bytes.pread_with(0, LE).unwrap();
+        for bit in [
+            HostFrameFlagsBits::Overflow,
+            HostFrameFlagsBits::Fd,
+            HostFrameFlagsBits::Brs,
+            HostFrameFlagsBits::Esi,
+        ] {
+            assert!(frame.flags.is_set(bit));
+        }
+
+        let mut written = [0; 76];
+        written.pwrite_with(frame, 0, LE).unwrap();
+        assert_eq!(written, bytes);
+    }
+}
@@ -1,22 +1,156 @@
-use super::{Channel, ChannelConstraints, ChannelEvent, ChannelFeatures};
+use super::{
+    Channel, ChannelConstraints, ChannelEvent, ChannelFeatures, ChannelFeaturesBit,
+    ChannelFlagsBit, ChannelIdentify, ChannelMode, ChannelStatus, ControlEvents, RxFilterProgram,
+    Stats, TerminationState,
+};
 use scroll::{Pread, Pwrite, LE};
 use usb_device::class_prelude::*;
+use usb_device::device::UsbDeviceState;
 
 const USB_CLASS_GS: u8 = 0xFF;
 const GS_SUBCLASS: u8 = 0xFF;
 const GS_PROTOCOL: u8 = 0xFF;
 
+/// Alternate setting of the interface without endpoints, in which the controller sleeps.
+pub const LOW_POWER_ALT_SETTING: u8 = 1;
+
+/// Size of the usb-device control buffer. `accept_with` splits the data stage into packets of
+/// the control endpoint's size itself, so a response only has to fit this buffer, not a packet.
+const CONTROL_BUFFER_SIZE: usize = 128;
+
+/// Fails to compile for responses that don't fit the control buffer, which `accept_with` would
+/// otherwise reject at runtime with BufferOverflow.
+struct ResponseFits<const N: usize>;
+
+impl<const N: usize> ResponseFits<N> {
+    const OK: () = assert!(N <= CONTROL_BUFFER_SIZE, "response exceeds the control buffer");
+}
+
+/// Largest bulk packet on a full-speed bus.
+pub const MAX_FS_PACKET_SIZE: usize = 64;
+
+/// Why a bulk max packet size can't be used.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PacketSizeError {
+    /// 512 byte bulk packets only exist on high-speed buses, the RP2040 is full-speed only.
+    HighSpeedOnly,
+    /// Full-speed bulk endpoints only allow 8, 16, 32 or 64 bytes.
+    Invalid,
+}
+
+/// Checks a bulk max packet size against the full-speed limits. Porting to a high-speed stack
+/// means extending this, not just passing 512 to `new`.
+pub const fn validate_packet_size(size: u16) -> Result<u16, PacketSizeError> {
+    match size {
+        8 | 16 | 32 | 64 => Ok(size),
+        512 => Err(PacketSizeError::HighSpeedOnly),
+        _ => Err(PacketSizeError::Invalid),
+    }
+}
+
 pub struct GsUsbClass<'a, B: UsbBus, const C: usize> {
     comm_if: InterfaceNumber,
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
+    #[cfg(feature = "debug-endpoint")]
+    debug_ep: EndpointIn<'a, B>,
     channels: [Channel; C],
+    /// Features advertised at enumeration, the most a channel can be switched to at runtime.
+    advertised: [ChannelFeatures; C],
     config: DeviceConfig,
-    control_event: Option<ChannelEvent>,
+    control_events: ControlEvents,
+    usb_state: UsbDeviceState,
+    usb_address: u8,
+    flow_control: FlowControl,
+    delivery_paused: bool,
+    idle_stall: bool,
+    rx_overflow_policy: RxOverflowPolicy,
+    stats: Stats,
+    fault: u8,
+    temperature: Option<i32>,
+    bit_rates: [u32; C],
+    data_bit_rates: [u32; C],
+    cnf_registers: [[u8; 3]; C],
+    send_results: [SendResult; C],
+    termination: [TerminationState; C],
+    running: [bool; C],
+    mode_flags: [u32; C],
+    identify: [bool; C],
+    alt_setting: u8,
+    /// Holds the larger control IN responses, which would otherwise take their size in stack in
+    /// the USB interrupt on top of the copy usb-device makes.
+    response_buffer: [u8; CONTROL_BUFFER_SIZE],
+}
+
+/// What happens to frames from the host while the transmit queue is full.
+#[repr(u16)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    /// Frames are read as long as their echoes fit the echo queue. The oldest queued frame is
+    /// dropped to make room and echoed as failed.
+    AlwaysAccept = 0,
+    /// The frame is left in the read buffer, so the bulk OUT endpoint NAKs the host until the
+    /// queue has room again.
+    HoldWhenFull = 1,
+}
+
+impl FlowControl {
+    /// Whether a frame should be read from the host given the state of the transmit queue.
+    pub fn accepts_frame(&self, queue_full: bool) -> bool {
+        match self {
+            FlowControl::AlwaysAccept => true,
+            FlowControl::HoldWhenFull => !queue_full,
+        }
+    }
+
+    fn from_raw(raw: u16) -> Option<FlowControl> {
+        match raw {
+            0 => Some(FlowControl::AlwaysAccept),
+            1 => Some(FlowControl::HoldWhenFull),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to frames received from the bus while the queue towards the host is full,
+/// because the host isn't reading.
+#[repr(u16)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RxOverflowPolicy {
+    /// The oldest queued frame is dropped and the new one carries the overflow flag.
+    OverwriteOldest = 0,
+    /// The new frame is dropped.
+    DropNewest = 1,
+    /// Frames are left in the controller, which drops them once both receive buffers are full.
+    StopReading = 2,
+}
+
+impl RxOverflowPolicy {
+    fn from_raw(raw: u16) -> Option<RxOverflowPolicy> {
+        match raw {
+            0 => Some(RxOverflowPolicy::OverwriteOldest),
+            1 => Some(RxOverflowPolicy::DropNewest),
+            2 => Some(RxOverflowPolicy::StopReading),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of the last frame sent on a channel with the SendFrame request, reported by the
+/// GetSendResult request.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SendResult {
+    /// No frame was sent since the last USB reset.
+    None = 0,
+    Pending = 1,
+    Sent = 2,
+    /// The frame couldn't be sent or nobody acknowledged it in time.
+    Failed = 3,
 }
 
 #[repr(u8)]
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Eq, PartialEq)]
 #[allow(dead_code)]
 enum GsUsbRequest {
     HostFormat = 0,
@@ -31,11 +165,39 @@ enum GsUsbRequest {
     SetUserId = 9,
     DataBitTiming = 10,
     BtConstExt = 11,
+    SetTermination = 12,
+    GetTermination = 13,
+    UsbStatus = 0x80,
+    SetIdRemap = 0x81,
+    SetHeartbeat = 0x82,
+    SetFlowControl = 0x83,
+    GetStats = 0x84,
+    ResetStats = 0x85,
+    GetFault = 0x86,
+    SetTransmitOnly = 0x87,
+    GetBitRate = 0x88,
+    StartBurst = 0x89,
+    SetDeliveryPaused = 0x8a,
+    InjectFrame = 0x8b,
+    GetChannelStatus = 0x8c,
+    SetRxFilter = 0x8d,
+    SetRxOverflowPolicy = 0x8e,
+    GetVendorRequests = 0x8f,
+    GetTimerResolution = 0x90,
+    GetCnfRegisters = 0x91,
+    StartTorture = 0x92,
+    GetMaxDataLength = 0x93,
+    SetIdleStall = 0x94,
+    SetChannelFeatures = 0x95,
+    GetTemperature = 0x96,
+    SetDropRemote = 0x97,
+    SendFrame = 0x98,
+    GetSendResult = 0x99,
 }
 
 impl<B: UsbBus, const C: usize> GsUsbClass<'_, B, C> {
-    /// Creates a new GsUsbClass with the provided UsbBus and max_packet_size in bytes. For
-    /// full-speed devices, max_packet_size has to be one of 8, 16, 32 or 64.
+    /// Creates a new GsUsbClass with the provided UsbBus and max_packet_size in bytes.
+    /// max_packet_size has to be one of 8, 16, 32 or 64, see `validate_packet_size`.
     pub fn new(
         alloc: &UsbBusAllocator<B>,
         max_packet_size: u16,
@@ -43,28 +205,75 @@ impl<B: UsbBus, const C: usize> GsUsbClass<'_, B, C> {
         sw_version: u32,
         hw_version: u32,
     ) -> GsUsbClass<'_, B, C> {
-        assert!(C < u8::MAX as usize);
+        assert!(Self::channel_count() < u8::MAX as usize);
+
+        if let Err(error) = validate_packet_size(max_packet_size) {
+            defmt::panic!("bulk packet size {} rejected: {}", max_packet_size, error);
+        }
+
+        // A release build still enumerates, the host just sees what the board definition says.
+        for (index, channel) in channels.iter().enumerate() {
+            if let Err(error) = channel.validate() {
+                if cfg!(debug_assertions) {
+                    defmt::panic!("channel {} misconfigured: {}", index, error);
+                }
+
+                defmt::error!("channel {} misconfigured: {}", index, error);
+            }
+        }
+
         GsUsbClass {
             comm_if: alloc.interface(),
             read_ep: alloc.bulk(max_packet_size),
             write_ep: alloc.bulk(max_packet_size),
+            #[cfg(feature = "debug-endpoint")]
+            debug_ep: alloc.bulk(max_packet_size),
+            advertised: channels.map(|channel| channel.features),
             channels,
             config: DeviceConfig {
                 reserved: [0; 3],
-                icount: (C - 1) as u8,
+                icount: (Self::channel_count() - 1) as u8,
                 sw_version,
                 hw_version,
             },
-            control_event: None,
+            control_events: ControlEvents::new(),
+            usb_state: UsbDeviceState::Default,
+            usb_address: 0,
+            flow_control: FlowControl::HoldWhenFull,
+            delivery_paused: false,
+            idle_stall: true,
+            rx_overflow_policy: RxOverflowPolicy::OverwriteOldest,
+            stats: Stats::default(),
+            fault: 0,
+            temperature: None,
+            bit_rates: [0; C],
+            data_bit_rates: [0; C],
+            cnf_registers: [[0; 3]; C],
+            send_results: [SendResult::None; C],
+            termination: [TerminationState::OFF; C],
+            running: [false; C],
+            mode_flags: [0; C],
+            identify: [false; C],
+            alt_setting: 0,
+            response_buffer: [0; CONTROL_BUFFER_SIZE],
         }
     }
 
+    /// Number of CAN channels, the C parameter.
+    pub const fn channel_count() -> usize {
+        C
+    }
+
     pub fn max_packet_size(&self) -> usize {
         self.write_ep.max_packet_size() as usize
     }
 
+    /// Stalls the bulk IN endpoint while there's nothing to send, unless idle stalling was
+    /// turned off with the SetIdleStall request.
     pub fn stall(&mut self) {
-        self.write_ep.stall();
+        if self.idle_stall {
+            self.write_ep.stall();
+        }
     }
 
     pub fn unstall(&mut self) {
@@ -79,10 +288,231 @@ impl<B: UsbBus, const C: usize> GsUsbClass<'_, B, C> {
         self.read_ep.read(data)
     }
 
+    #[cfg(feature = "debug-endpoint")]
+    pub fn write_debug_packet(&mut self, data: &[u8]) -> usb_device::Result<usize> {
+        self.debug_ep.write(data)
+    }
+
+    /// Returns the oldest pending control event. Several requests can arrive between two calls,
+    /// so callers should loop until this returns None.
+    ///
+    /// Events are pushed by `control_out` and `reset`, both called from UsbDevice::poll. Both sides take `&mut self`, so as long as the class is only reachable
+    /// through one lock (`usb_irq` keeps it behind a critical section), a push can't interleave
+    /// with a dequeue and no event is ever seen half written. An event is either queued whole or
+    /// its request is rejected.
     pub fn read_control_event(&mut self) -> Option<ChannelEvent> {
-        let mut ret_value: Option<ChannelEvent> = None;
-        core::mem::swap(&mut ret_value, &mut self.control_event);
-        ret_value
+        self.control_events.pop()
+    }
+
+    /// Updates the device state reported by the UsbStatus request. The class doesn't own the
+    /// UsbDevice, so this has to be called after every poll.
+    pub fn set_usb_state(&mut self, state: UsbDeviceState) {
+        self.usb_state = state;
+    }
+
+    pub fn usb_state(&self) -> UsbDeviceState {
+        self.usb_state
+    }
+
+    pub fn flow_control(&self) -> FlowControl {
+        self.flow_control
+    }
+
+    pub fn set_flow_control(&mut self, flow_control: FlowControl) {
+        self.flow_control = flow_control;
+    }
+
+    pub fn rx_overflow_policy(&self) -> RxOverflowPolicy {
+        self.rx_overflow_policy
+    }
+
+    /// Whether the host asked to stop receiving frames for now. The channel stays on the bus,
+    /// frames keep queueing for the host until delivery resumes.
+    pub fn delivery_paused(&self) -> bool {
+        self.delivery_paused
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    /// Sets the reserved bytes of the DeviceConfig response, for protocol versions that assign
+    /// them a meaning.
+    #[allow(dead_code)]
+    pub fn set_config_reserved(&mut self, reserved: [u8; 3]) {
+        self.config.reserved = reserved;
+    }
+
+    /// Sets the controller fault code reported by the GetFault request, zero meaning none.
+    pub fn set_fault(&mut self, fault: u8) {
+        self.fault = fault;
+    }
+
+    /// Sets the board temperature in thousandths of a degree Celsius, reported by the
+    /// GetTemperature request.
+    pub fn set_temperature(&mut self, millicelsius: i32) {
+        self.temperature = Some(millicelsius);
+    }
+
+    /// Sets the outcome of the frame sent with the SendFrame request, reported by the
+    /// GetSendResult request.
+    pub fn set_send_result(&mut self, channel: usize, result: SendResult) {
+        if let Some(slot) = self.send_results.get_mut(channel) {
+            *slot = result;
+        }
+    }
+
+    /// Sets the bit rate the channel actually runs at, reported by the GetBitRate request.
+    pub fn set_bit_rate(&mut self, channel: usize, bit_rate: u32) {
+        if let Some(slot) = self.bit_rates.get_mut(channel) {
+            *slot = bit_rate;
+        }
+    }
+
+    /// Sets the data-phase bit rate of an FD channel, reported in ChannelStatus.
+    pub fn set_data_bit_rate(&mut self, channel: usize, bit_rate: u32) {
+        if let Some(slot) = self.data_bit_rates.get_mut(channel) {
+            *slot = bit_rate;
+        }
+    }
+
+    /// Sets the raw bit timing registers read back from the controller, reported by the
+    /// GetCnfRegisters request.
+    pub fn set_cnf_registers(&mut self, channel: usize, cnf: [u8; 3]) {
+        if let Some(slot) = self.cnf_registers.get_mut(channel) {
+            *slot = cnf;
+        }
+    }
+
+    /// Records whether the controller took the channel onto the bus, reported in ChannelStatus.
+    pub fn set_running(&mut self, channel: usize, running: bool) {
+        if let Some(slot) = self.running.get_mut(channel) {
+            *slot = running;
+        }
+    }
+
+    /// Collects the live state of a channel, None if it doesn't exist.
+    pub fn channel_status(&self, channel: usize) -> Option<ChannelStatus> {
+        if channel >= C {
+            return None;
+        }
+
+        Some(ChannelStatus {
+            running: self.running[channel] as u8,
+            identify: self.identify[channel] as u8,
+            termination: self.termination[channel].is_on() as u8,
+            _reserved: 0,
+            mode_flags: self.mode_flags[channel],
+            bit_rate: self.bit_rates[channel],
+            rx_frames: self.stats.rx_frames,
+            tx_frames: self.stats.tx_frames,
+            tx_errors: self.stats.tx_errors,
+            data_bit_rate: self.data_bit_rates[channel],
+        })
+    }
+
+    /// Updates what the class reports about a request once its event is queued, so a request
+    /// refused for a full queue leaves no trace.
+    fn note_queued(&mut self, event: &ChannelEvent) {
+        match event {
+            ChannelEvent::ChannelMode(mode, channel) if mode.is_on() => {
+                self.mode_flags[*channel] = mode.flags.bits();
+            }
+            ChannelEvent::Identify(identify, channel) => {
+                self.identify[*channel] = identify.is_on();
+            }
+            ChannelEvent::Termination(state, channel) => {
+                self.termination[*channel] = *state;
+            }
+            ChannelEvent::SendFrame(_, channel) => {
+                self.send_results[*channel] = SendResult::Pending;
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the channel a request addresses in wValue exists, used by every per-channel
+    /// request. Requests for a channel the device doesn't have are rejected, stalling the control
+    /// pipe, and logged so a host addressing the wrong channel shows up as more than a stall.
+    fn has_channel(channel: usize) -> bool {
+        if channel < C {
+            return true;
+        }
+
+        defmt::warn!("request for channel {} of a device with {}", channel, C);
+        false
+    }
+
+    /// Largest data field a frame on the channel can carry, 64 bytes with FD and 8 without.
+    fn max_data_length(&self, channel: usize) -> u32 {
+        match self.supports(channel, ChannelFeaturesBit::Fd) {
+            true => 64,
+            false => 8,
+        }
+    }
+
+    fn supports(&self, channel: usize, feature: ChannelFeaturesBit) -> bool {
+        self.channels[channel].features.is_set(feature)
+    }
+
+    /// The host only asks for the extended timing constants on channels advertising FD or
+    /// BtConstExt, anything else gets the request rejected.
+    fn has_bt_const_ext(&self, channel: usize) -> bool {
+        self.supports(channel, ChannelFeaturesBit::Fd)
+            || self.supports(channel, ChannelFeaturesBit::BtConstExt)
+    }
+
+    /// Refuses modes asking for features the channel doesn't advertise, so an FD request on a
+    /// classic channel fails instead of the channel starting up and mis-sending FD frames.
+    fn validate_mode(
+        &self,
+        mode: ChannelMode,
+        channel: usize,
+        size: usize,
+    ) -> Result<ChannelMode, scroll::Error> {
+        let features = &self.channels[channel].features;
+
+        if mode.flags.is_set(ChannelFlagsBit::Fd) && !features.is_set(ChannelFeaturesBit::Fd) {
+            return Err(scroll::Error::BadInput {
+                size,
+                msg: "FD mode on a classic channel",
+            });
+        }
+
+        if mode.flags.is_set(ChannelFlagsBit::NoEcho)
+            && !features.is_set(ChannelFeaturesBit::NoEcho)
+        {
+            return Err(scroll::Error::BadInput {
+                size,
+                msg: "no-echo mode on a channel that doesn't advertise it",
+            });
+        }
+
+        Ok(mode)
+    }
+
+    /// Switches the interface to alternate setting `alternative`. Refused while the event queue
+    /// is full, like the vendor requests, so the main loop never misses a switch.
+    fn set_alt_setting(&mut self, alternative: u16) -> bool {
+        if alternative > LOW_POWER_ALT_SETTING as u16 || self.control_events.is_full() {
+            return false;
+        }
+
+        self.alt_setting = alternative as u8;
+        self.control_events.push(ChannelEvent::LowPower(alternative == LOW_POWER_ALT_SETTING as u16));
+        true
+    }
+
+    /// Whether the request is a standard one addressed to the gs_usb interface.
+    fn is_for_interface(&self, req: &control::Request, request: u8) -> bool {
+        req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Interface
+            && req.request == request
+            && req.index == u8::from(self.comm_if) as u16
+    }
+
+    fn usb_status(&self) -> UsbStatus {
+        UsbStatus::new(self.usb_state, self.usb_address, Self::channel_count() as u8)
     }
 }
 
@@ -97,12 +527,54 @@ impl<B: UsbBus, const C: usize> UsbClass<B> for GsUsbClass<'_, B, C> {
         writer.endpoint(&self.write_ep)?;
         writer.endpoint(&self.read_ep)?;
 
+        #[cfg(feature = "debug-endpoint")]
+        writer.endpoint(&self.debug_ep)?;
+
+        writer.interface_alt(
+            self.comm_if,
+            LOW_POWER_ALT_SETTING,
+            USB_CLASS_GS,
+            GS_SUBCLASS,
+            GS_PROTOCOL,
+            None,
+        )?;
+
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.usb_address = 0;
+        self.alt_setting = 0;
+        self.delivery_paused = false;
+        self.send_results = [SendResult::None; C];
+        // Nothing queued before the reset applies any more.
+        self.control_events.clear();
+        self.control_events.push(ChannelEvent::HostReset);
+    }
+
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
 
+        // SET_ADDRESS is handled by UsbDevice, which doesn't expose the address afterwards. Every
+        // class gets to see the request first, so remember it here without accepting it.
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Device
+            && req.request == control::Request::SET_ADDRESS
+        {
+            self.usb_address = req.value as u8;
+            return;
+        }
+
+        // UsbDevice only accepts alternate setting 0 itself, but lets the classes answer
+        // SET_INTERFACE and GET_INTERFACE first.
+        if self.is_for_interface(req, control::Request::SET_INTERFACE) {
+            match self.set_alt_setting(req.value) {
+                true => xfer.accept().ok(),
+                false => xfer.reject().ok(),
+            };
+            return;
+        }
+
         if req.request_type != control::RequestType::Vendor
             || req.recipient != control::Recipient::Interface
             || req.index != u8::from(self.comm_if) as u16
@@ -118,40 +590,204 @@ impl<B: UsbBus, const C: usize> UsbClass<B> for GsUsbClass<'_, B, C> {
             return;
         }
 
+        if let Some(GsUsbRequest::ResetStats) = gs_request {
+            self.stats.reset();
+            xfer.accept().ok();
+            return;
+        }
+
+        // The policy is device-wide, so wValue carries the policy rather than a channel.
+        if let Some(GsUsbRequest::SetFlowControl) = gs_request {
+            match FlowControl::from_raw(req.value) {
+                Some(flow_control) => {
+                    self.flow_control = flow_control;
+                    xfer.accept().ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            }
+            return;
+        }
+
+        if let Some(GsUsbRequest::SetRxOverflowPolicy) = gs_request {
+            match RxOverflowPolicy::from_raw(req.value) {
+                Some(policy) => {
+                    self.rx_overflow_policy = policy;
+                    xfer.accept().ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            }
+            return;
+        }
+
+        // Takes the new gs_device_bt_const feature word, limited to what the channel advertised at
+        // enumeration, which was checked against the controller then. Applied right away so the
+        // requests the features gate follow, the main loop catches up with the event.
+        if let Some(GsUsbRequest::SetChannelFeatures) = gs_request {
+            let features = xfer
+                .data()
+                .pread_with::<ChannelFeatures>(0, LE)
+                .ok()
+                .filter(|features| {
+                    Self::has_channel(channel) && self.advertised[channel].contains(*features)
+                });
+
+            match features {
+                Some(features) if !self.control_events.is_full() => {
+                    self.channels[channel].features = features;
+                    self.control_events.push(ChannelEvent::Features(features, channel));
+                    xfer.accept().ok();
+                }
+                _ => {
+                    xfer.reject().ok();
+                }
+            }
+            return;
+        }
+
+        // Device-wide, wValue is 0 to leave the bulk IN endpoint unstalled while idle, so the host
+        // only ever sees NAKs, and 1 to stall it again. Kept across bus resets like the flow
+        // control.
+        if let Some(GsUsbRequest::SetIdleStall) = gs_request {
+            match req.value {
+                0 | 1 => {
+                    self.idle_stall = req.value == 1;
+
+                    if !self.idle_stall {
+                        self.write_ep.unstall();
+                    }
+
+                    xfer.accept().ok();
+                }
+                _ => {
+                    xfer.reject().ok();
+                }
+            }
+            return;
+        }
+
+        // Device-wide as well, wValue is 1 to pause delivery and 0 to resume it.
+        if let Some(GsUsbRequest::SetDeliveryPaused) = gs_request {
+            match req.value {
+                0 | 1 => {
+                    self.delivery_paused = req.value == 1;
+                    xfer.accept().ok();
+                }
+                _ => {
+                    xfer.reject().ok();
+                }
+            }
+            return;
+        }
+
         let control_event = match gs_request {
-            Some(GsUsbRequest::BitTiming) if channel < C => xfer
+            Some(GsUsbRequest::BitTiming) if Self::has_channel(channel) => xfer
                 .data()
                 .pread_with(0, LE)
                 .map(|timing| ChannelEvent::BitTiming(timing, channel)),
 
-            Some(GsUsbRequest::DataBitTiming) if channel < C => xfer
-                .data()
-                .pread_with(0, LE)
-                .map(|timing| ChannelEvent::DataBitTiming(timing, channel)),
+            // Classic channels have no data phase to time, the host only sends this after
+            // seeing the FD feature.
+            Some(GsUsbRequest::DataBitTiming)
+                if Self::has_channel(channel) && self.supports(channel, ChannelFeaturesBit::Fd) =>
+            {
+                xfer.data()
+                    .pread_with(0, LE)
+                    .map(|timing| ChannelEvent::DataBitTiming(timing, channel))
+            }
 
-            Some(GsUsbRequest::Mode) if channel < C => xfer
+            Some(GsUsbRequest::Mode) if Self::has_channel(channel) => xfer
                 .data()
                 .pread_with(0, LE)
+                .and_then(|mode| self.validate_mode(mode, channel, xfer.data().len()))
                 .map(|mode| ChannelEvent::ChannelMode(mode, channel)),
 
-            Some(GsUsbRequest::Identify) if channel < C => xfer
+            Some(GsUsbRequest::Identify) if Self::has_channel(channel) => xfer
                 .data()
                 .pread_with(0, LE)
                 .map(|identify| ChannelEvent::Identify(identify, channel)),
 
+            Some(GsUsbRequest::SetTermination)
+                if Self::has_channel(channel)
+                    && self.supports(channel, ChannelFeaturesBit::Termination) =>
+            {
+                xfer.data()
+                    .pread_with(0, LE)
+                    .map(|state| ChannelEvent::Termination(state, channel))
+            }
+
+            Some(GsUsbRequest::SetIdRemap) if Self::has_channel(channel) => xfer
+                .data()
+                .pread_with(0, LE)
+                .map(|entry| ChannelEvent::IdRemap(entry, channel)),
+
+            Some(GsUsbRequest::SetHeartbeat) if Self::has_channel(channel) => xfer
+                .data()
+                .pread_with(0, LE)
+                .map(|config| ChannelEvent::Heartbeat(config, channel)),
+
+            Some(GsUsbRequest::SetTransmitOnly) if Self::has_channel(channel) => xfer
+                .data()
+                .pread_with(0, LE)
+                .map(|switch| ChannelEvent::TransmitOnly(switch, channel)),
+
+            Some(GsUsbRequest::SetDropRemote) if Self::has_channel(channel) => xfer
+                .data()
+                .pread_with(0, LE)
+                .map(|switch| ChannelEvent::DropRemote(switch, channel)),
+
+            Some(GsUsbRequest::SetRxFilter) if Self::has_channel(channel) => {
+                RxFilterProgram::new(xfer.data())
+                    .map(|program| ChannelEvent::RxFilter(program, channel))
+                    .ok_or(scroll::Error::TooBig {
+                        size: xfer.data().len(),
+                        len: RxFilterProgram::MAX_SIZE,
+                    })
+            }
+
+            Some(GsUsbRequest::StartBurst) if Self::has_channel(channel) => xfer
+                .data()
+                .pread_with(0, LE)
+                .map(|request| ChannelEvent::Burst(request, channel)),
+
+            Some(GsUsbRequest::StartTorture) if Self::has_channel(channel) => xfer
+                .data()
+                .pread_with(0, LE)
+                .map(|request| ChannelEvent::Torture(request, channel)),
+
+            // The echo id is cleared so the host sees a received frame.
+            #[cfg(feature = "frame-injection")]
+            Some(GsUsbRequest::InjectFrame) if Self::has_channel(channel) => {
+                read_host_frame(xfer.data(), channel).map(|mut frame| {
+                    frame.echo_id = u32::MAX;
+                    ChannelEvent::InjectFrame(frame, channel)
+                })
+            }
+
+            // Sent right away, outside the transmit queue, and never echoed. The host polls
+            // GetSendResult for the outcome.
+            Some(GsUsbRequest::SendFrame) if Self::has_channel(channel) => {
+                read_host_frame(xfer.data(), channel)
+                    .map(|frame| ChannelEvent::SendFrame(frame, channel))
+            }
+
             _ => Err(scroll::Error::BadInput {
                 size: xfer.data().len(),
                 msg: "invalid gs_usb request",
             }),
         };
 
-        self.control_event = control_event.ok();
-
-        match &self.control_event {
-            Some(_) => {
+        // A full queue refuses the event, the host retries once the main loop has caught up.
+        match control_event {
+            Ok(event) if !self.control_events.is_full() => {
+                self.note_queued(&event);
+                self.control_events.push(event);
                 xfer.accept().ok();
             }
-            None => {
+            _ => {
                 xfer.reject().ok();
             }
         }
@@ -160,6 +796,11 @@ impl<B: UsbBus, const C: usize> UsbClass<B> for GsUsbClass<'_, B, C> {
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
 
+        if self.is_for_interface(req, control::Request::GET_INTERFACE) {
+            xfer.accept_with(&[self.alt_setting]).ok();
+            return;
+        }
+
         if req.request_type != control::RequestType::Vendor
             || req.recipient != control::Recipient::Interface
             || req.index != u8::from(self.comm_if) as u16
@@ -174,19 +815,74 @@ impl<B: UsbBus, const C: usize> UsbClass<B> for GsUsbClass<'_, B, C> {
             value: Result<[u8; N], scroll::Error>,
             xfer: ControlIn<B>,
         ) -> Result<(), usb_device::UsbError> {
+            #[allow(clippy::let_unit_value)]
+            let () = ResponseFits::<N>::OK;
+
             match &value {
                 Ok(packed) => xfer.accept_with(packed),
                 Err(_) => xfer.reject(),
             }
         }
 
+        fn reply_from_buffer<B: UsbBus>(
+            value: Result<&[u8], scroll::Error>,
+            xfer: ControlIn<B>,
+        ) -> Result<(), usb_device::UsbError> {
+            match value {
+                Ok(packed) => xfer.accept_with(packed),
+                Err(_) => xfer.reject(),
+            }
+        }
+
         let response = match gs_request {
             Some(GsUsbRequest::DeviceConfig) => reply(self.config.packed(), xfer),
-            Some(GsUsbRequest::BtConst) if channel < C => {
-                reply(BtConst::new(&self.channels[channel]).packed(), xfer)
+            Some(GsUsbRequest::BtConst) if Self::has_channel(channel) => reply_from_buffer(
+                BtConst::new(&self.channels[channel]).pack_into(&mut self.response_buffer),
+                xfer,
+            ),
+            Some(GsUsbRequest::BtConstExt)
+                if Self::has_channel(channel) && self.has_bt_const_ext(channel) =>
+            {
+                reply_from_buffer(
+                    BtConstExt::new(&self.channels[channel]).pack_into(&mut self.response_buffer),
+                    xfer,
+                )
+            }
+            Some(GsUsbRequest::UsbStatus) => reply(self.usb_status().packed(), xfer),
+            Some(GsUsbRequest::GetStats) => reply(self.stats.packed(), xfer),
+            Some(GsUsbRequest::GetFault) => reply(Ok([self.fault, 0, 0, 0]), xfer),
+            Some(GsUsbRequest::GetSendResult) if Self::has_channel(channel) => {
+                reply(Ok([self.send_results[channel] as u8, 0, 0, 0]), xfer)
+            }
+            // Rejected until the first reading is in.
+            Some(GsUsbRequest::GetTemperature) => match self.temperature {
+                Some(millicelsius) => reply(Ok(millicelsius.to_le_bytes()), xfer),
+                None => xfer.reject(),
+            },
+            Some(GsUsbRequest::GetVendorRequests) => reply(Ok(VENDOR_REQUESTS.to_le_bytes()), xfer),
+            Some(GsUsbRequest::GetTimerResolution) => {
+                reply(Ok(TIMER_TICKS_PER_US.to_le_bytes()), xfer)
+            }
+            Some(GsUsbRequest::GetTermination)
+                if Self::has_channel(channel)
+                    && self.supports(channel, ChannelFeaturesBit::Termination) =>
+            {
+                reply(Ok(self.termination[channel].to_le_bytes()), xfer)
+            }
+            Some(GsUsbRequest::GetChannelStatus) if Self::has_channel(channel) => reply(
+                self.channel_status(channel).unwrap_or_default().packed(),
+                xfer,
+            ),
+            Some(GsUsbRequest::GetBitRate) if Self::has_channel(channel) => {
+                reply(Ok(self.bit_rates[channel].to_le_bytes()), xfer)
+            }
+            Some(GsUsbRequest::GetMaxDataLength) if Self::has_channel(channel) => {
+                reply(Ok(self.max_data_length(channel).to_le_bytes()), xfer)
             }
-            Some(GsUsbRequest::BtConstExt) if channel < C => {
-                reply(BtConstExt::new(&self.channels[channel]).packed(), xfer)
+            // CNF1, CNF2 and CNF3 padded to four bytes.
+            Some(GsUsbRequest::GetCnfRegisters) if Self::has_channel(channel) => {
+                let [cnf1, cnf2, cnf3] = self.cnf_registers[channel];
+                reply(Ok([cnf1, cnf2, cnf3, 0]), xfer)
             }
             _ => xfer.reject(),
         };
@@ -195,24 +891,105 @@ impl<B: UsbBus, const C: usize> UsbClass<B> for GsUsbClass<'_, B, C> {
     }
 }
 
-impl GsUsbRequest {
-    fn from_raw(raw: u8) -> Option<GsUsbRequest> {
-        if raw > GsUsbRequest::BtConstExt as u8 {
-            return None;
+/// Timer ticks per microsecond of the timestamps sent to the host, read with the
+/// GetTimerResolution request. The RP2040 timer runs from the 1 MHz watchdog tick.
+const TIMER_TICKS_PER_US: u32 = 1;
+
+/// First request number outside the gs_usb range.
+const VENDOR_REQUEST_BASE: u8 = 0x80;
+
+/// Bit n is set if request 0x80 + n is implemented, read by the host with GetVendorRequests.
+/// Derived from `from_raw`, so it can't disagree with what the class decodes.
+const VENDOR_REQUESTS: u32 = vendor_request_bitmap();
+
+const fn vendor_request_bitmap() -> u32 {
+    let mut bitmap = 0;
+    let mut bit = 0;
+
+    while bit < 32 {
+        if GsUsbRequest::from_raw(VENDOR_REQUEST_BASE + bit).is_some() {
+            bitmap |= 1 << bit;
         }
+        bit += 1;
+    }
+
+    bitmap
+}
+
+/// Reads a gs_host_frame sent with a control request like the bulk OUT endpoint does, missing
+/// data bytes read as zero. The channel is taken from the request.
+fn read_host_frame(data: &[u8], channel: usize) -> Result<super::HostFrame, scroll::Error> {
+    let mut buffer = [0; core::mem::size_of::<super::HostFrame>()];
+    let length = core::cmp::min(data.len(), buffer.len());
+    buffer[..length].copy_from_slice(&data[..length]);
+
+    buffer.pread_with(0, LE).map(|mut frame: super::HostFrame| {
+        frame.channel = channel as u8;
+        frame
+    })
+}
 
-        unsafe { Some(core::mem::transmute(raw)) }
+impl GsUsbRequest {
+    /// Requests behind a disabled feature aren't decoded, so they're rejected like unknown ones.
+    const fn from_raw(raw: u8) -> Option<GsUsbRequest> {
+        match raw {
+            0..=13 => unsafe { Some(core::mem::transmute::<u8, GsUsbRequest>(raw)) },
+            0x80 => Some(GsUsbRequest::UsbStatus),
+            0x81 => Some(GsUsbRequest::SetIdRemap),
+            0x82 => Some(GsUsbRequest::SetHeartbeat),
+            0x83 => Some(GsUsbRequest::SetFlowControl),
+            0x84 => Some(GsUsbRequest::GetStats),
+            0x85 => Some(GsUsbRequest::ResetStats),
+            0x86 => Some(GsUsbRequest::GetFault),
+            0x87 => Some(GsUsbRequest::SetTransmitOnly),
+            0x88 => Some(GsUsbRequest::GetBitRate),
+            0x89 => Some(GsUsbRequest::StartBurst),
+            0x8a => Some(GsUsbRequest::SetDeliveryPaused),
+            #[cfg(feature = "frame-injection")]
+            0x8b => Some(GsUsbRequest::InjectFrame),
+            0x8c => Some(GsUsbRequest::GetChannelStatus),
+            0x8d => Some(GsUsbRequest::SetRxFilter),
+            0x8e => Some(GsUsbRequest::SetRxOverflowPolicy),
+            0x8f => Some(GsUsbRequest::GetVendorRequests),
+            0x90 => Some(GsUsbRequest::GetTimerResolution),
+            0x91 => Some(GsUsbRequest::GetCnfRegisters),
+            0x92 => Some(GsUsbRequest::StartTorture),
+            0x93 => Some(GsUsbRequest::GetMaxDataLength),
+            0x94 => Some(GsUsbRequest::SetIdleStall),
+            0x95 => Some(GsUsbRequest::SetChannelFeatures),
+            0x96 => Some(GsUsbRequest::GetTemperature),
+            0x97 => Some(GsUsbRequest::SetDropRemote),
+            0x98 => Some(GsUsbRequest::SendFrame),
+            0x99 => Some(GsUsbRequest::GetSendResult),
+            _ => None,
+        }
     }
 }
 
+/// gs_device_config. The kernel reads icount and the versions only.
 #[derive(Pwrite)]
 struct DeviceConfig {
+    /// reserved1..3, zero unless set with `set_config_reserved`.
     reserved: [u8; 3],
+    /// Number of channels minus one.
     icount: u8,
     sw_version: u32,
     hw_version: u32,
 }
 
+/// Device-specific diagnostics for enumeration issues, not part of the gs_usb protocol.
+#[derive(Pwrite)]
+struct UsbStatus {
+    configured: u8,
+    suspended: u8,
+    address: u8,
+    channels: u8,
+}
+
+/// Packed sizes of the gs_device_bt_const and gs_device_bt_const_extended responses.
+const BT_CONST_SIZE: usize = 40;
+const BT_CONST_EXT_SIZE: usize = 72;
+
 struct BtConst<'a> {
     features: &'a ChannelFeatures,
     fclk_can: &'a u32,
@@ -238,8 +1015,32 @@ impl DeviceConfig {
     }
 }
 
+// gs_device_config is 12 bytes, a host reading a different size rejects the device.
+const _: () = assert!(DeviceConfig::size() == 12);
+
+impl UsbStatus {
+    fn new(state: UsbDeviceState, address: u8, channels: u8) -> Self {
+        UsbStatus {
+            configured: (state == UsbDeviceState::Configured) as u8,
+            suspended: (state == UsbDeviceState::Suspend) as u8,
+            address,
+            channels,
+        }
+    }
+
+    const fn size() -> usize {
+        core::mem::size_of::<Self>()
+    }
+
+    fn packed(&self) -> Result<[u8; UsbStatus::size()], scroll::Error> {
+        let mut ret_value: [u8; UsbStatus::size()] = [0; UsbStatus::size()];
+        ret_value.pwrite_with(self, 0, LE)?;
+        Ok(ret_value)
+    }
+}
+
 impl BtConst<'_> {
-    fn new(channel: &Channel) -> BtConst {
+    fn new(channel: &Channel) -> BtConst<'_> {
         BtConst {
             features: &channel.features,
             fclk_can: &channel.fclk_can,
@@ -247,12 +1048,18 @@ impl BtConst<'_> {
         }
     }
 
-    fn packed(&self) -> Result<[u8; 40], scroll::Error> {
-        let mut ret_value: [u8; 40] = [0; 40];
+    fn pack_into<'b>(
+        &self,
+        buffer: &'b mut [u8; CONTROL_BUFFER_SIZE],
+    ) -> Result<&'b [u8], scroll::Error> {
+        #[allow(clippy::let_unit_value)]
+        let () = ResponseFits::<BT_CONST_SIZE>::OK;
+
+        let ret_value = &mut buffer[..BT_CONST_SIZE];
         let mut bytes_written: usize = 0;
 
-        bytes_written = ret_value.pwrite_with(self.features, bytes_written, LE)?;
-        bytes_written = ret_value.pwrite_with(self.fclk_can, bytes_written, LE)?;
+        bytes_written += ret_value.pwrite_with(self.features, bytes_written, LE)?;
+        bytes_written += ret_value.pwrite_with(self.fclk_can, bytes_written, LE)?;
         _ = ret_value.pwrite_with(self.constraints, bytes_written, LE)?;
 
         Ok(ret_value)
@@ -260,7 +1067,7 @@ impl BtConst<'_> {
 }
 
 impl BtConstExt<'_> {
-    fn new(channel: &Channel) -> BtConstExt {
+    fn new(channel: &Channel) -> BtConstExt<'_> {
         match &channel.data_constraints {
             Some(data_constraints) => BtConstExt {
                 features: &channel.features,
@@ -277,15 +1084,543 @@ impl BtConstExt<'_> {
         }
     }
 
-    fn packed(&self) -> Result<[u8; 72], scroll::Error> {
-        let mut ret_value: [u8; 72] = [0; 72];
+    fn pack_into<'b>(
+        &self,
+        buffer: &'b mut [u8; CONTROL_BUFFER_SIZE],
+    ) -> Result<&'b [u8], scroll::Error> {
+        #[allow(clippy::let_unit_value)]
+        let () = ResponseFits::<BT_CONST_EXT_SIZE>::OK;
+
+        let ret_value = &mut buffer[..BT_CONST_EXT_SIZE];
         let mut bytes_written: usize = 0;
 
-        bytes_written = ret_value.pwrite_with(self.features, bytes_written, LE)?;
-        bytes_written = ret_value.pwrite_with(self.fclk_can, bytes_written, LE)?;
-        bytes_written = ret_value.pwrite_with(self.constraints, bytes_written, LE)?;
+        bytes_written += ret_value.pwrite_with(self.features, bytes_written, LE)?;
+        bytes_written += ret_value.pwrite_with(self.fclk_can, bytes_written, LE)?;
+        bytes_written += ret_value.pwrite_with(self.constraints, bytes_written, LE)?;
         _ = ret_value.pwrite_with(self.data_constraints, bytes_written, LE)?;
 
         Ok(ret_value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbd_gs::test_bus::{self, Host, RequestKind, TestBus};
+    use crate::usbd_gs::{GsUsbPort, HostCanId, HostFrame, HostFrameFlags};
+    use usb_device::device::UsbDevice;
+
+    fn classic_channel() -> Channel {
+        Channel {
+            features: ChannelFeatures::new(&[
+                ChannelFeaturesBit::ListenOnly,
+                ChannelFeaturesBit::Loopback,
+            ]),
+            fclk_can: 8_000_000,
+            constraints: ChannelConstraints {
+                tseg1_min: 3,
+                tseg1_max: 8,
+                tseg2_min: 2,
+                tseg2_max: 8,
+                sjw_max: 4,
+                brp_min: 1,
+                brp_max: 64,
+                brp_inc: 1,
+            },
+            data_constraints: None,
+        }
+    }
+
+    fn fd_channel() -> Channel {
+        let mut channel = classic_channel();
+        channel.features = ChannelFeatures::new(&[ChannelFeaturesBit::Fd]);
+        channel.data_constraints = Some(channel.constraints);
+        channel
+    }
+
+    type TestClass<'a> = GsUsbClass<'a, TestBus, 1>;
+
+    fn vendor_out(
+        host: &Host,
+        device: &mut UsbDevice<'_, TestBus>,
+        class: &mut TestClass,
+        request: GsUsbRequest,
+        value: u16,
+        data: &[u8],
+    ) -> bool {
+        let request = (RequestKind::Vendor, request as u8, value);
+        test_bus::control_out(host, device, class, request, data)
+    }
+
+    fn vendor_in(
+        host: &Host,
+        device: &mut UsbDevice<'_, TestBus>,
+        class: &mut TestClass,
+        request: GsUsbRequest,
+        value: u16,
+        length: u16,
+    ) -> Option<Vec<u8>> {
+        let request = (RequestKind::Vendor, request as u8, value);
+        test_bus::control_in(host, device, class, request, length)
+    }
+
+    /// Queues control events until the queue is full, torture runs of no frames.
+    fn fill_control_events(
+        host: &Host,
+        device: &mut UsbDevice<'_, TestBus>,
+        class: &mut TestClass,
+    ) {
+        while vendor_out(host, device, class, GsUsbRequest::StartTorture, 0, &[0; 8]) {}
+    }
+
+    const SET_INTERFACE: u8 = 11;
+    const GET_INTERFACE: u8 = 10;
+
+    #[test]
+    fn set_interface_switches_to_the_low_power_setting_and_back() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let set_low_power = (RequestKind::StandardInterface, SET_INTERFACE, 1);
+        assert!(test_bus::control_out(&host, &mut device, &mut class, set_low_power, &[]));
+        assert!(matches!(class.read_control_event(), Some(ChannelEvent::LowPower(true))));
+
+        let get_interface = (RequestKind::StandardInterface, GET_INTERFACE, 0);
+        let alt_setting = test_bus::control_in(&host, &mut device, &mut class, get_interface, 1);
+        assert_eq!(alt_setting, Some(vec![LOW_POWER_ALT_SETTING]));
+
+        let set_default = (RequestKind::StandardInterface, SET_INTERFACE, 0);
+        assert!(test_bus::control_out(&host, &mut device, &mut class, set_default, &[]));
+        assert!(matches!(class.read_control_event(), Some(ChannelEvent::LowPower(false))));
+
+        let alt_setting = test_bus::control_in(&host, &mut device, &mut class, get_interface, 1);
+        assert_eq!(alt_setting, Some(vec![0]));
+    }
+
+    #[test]
+    fn set_interface_to_an_unknown_setting_is_rejected() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let set_interface = (RequestKind::StandardInterface, SET_INTERFACE, 2);
+        assert!(!test_bus::control_out(&host, &mut device, &mut class, set_interface, &[]));
+        assert!(class.read_control_event().is_none());
+    }
+
+    #[test]
+    fn usb_status_packs_state_address_and_channels() {
+        let status = UsbStatus::new(UsbDeviceState::Configured, 5, 1);
+        assert_eq!(status.packed().unwrap(), [1, 0, 5, 1]);
+    }
+
+    #[test]
+    fn usb_status_reports_suspend_as_not_configured() {
+        let status = UsbStatus::new(UsbDeviceState::Suspend, 5, 1);
+        assert_eq!(status.packed().unwrap(), [0, 1, 5, 1]);
+    }
+
+    #[test]
+    fn usb_status_before_enumeration() {
+        let status = UsbStatus::new(UsbDeviceState::Default, 0, 1);
+        assert_eq!(status.packed().unwrap(), [0, 0, 0, 1]);
+    }
+
+    fn send_frame_bytes() -> [u8; 20] {
+        let can_id = HostCanId::new(0x123, &[]).unwrap();
+        let frame = HostFrame::new(None, can_id, 8, 0, HostFrameFlags::new(&[]), [0; 64]);
+        let mut bytes = [0; core::mem::size_of::<HostFrame>()];
+        bytes.pwrite_with(frame, 0, LE).unwrap();
+        bytes[..20].try_into().unwrap()
+    }
+
+    #[test]
+    fn send_frame_result_goes_from_pending_to_the_outcome() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        let get_result = GsUsbRequest::GetSendResult;
+
+        let result = vendor_in(&host, &mut device, &mut class, get_result, 0, 4);
+        assert_eq!(result, Some(vec![SendResult::None as u8, 0, 0, 0]));
+
+        let frame = send_frame_bytes();
+        assert!(vendor_out(&host, &mut device, &mut class, GsUsbRequest::SendFrame, 0, &frame));
+        assert!(matches!(
+            class.read_control_event(),
+            Some(ChannelEvent::SendFrame(_, 0))
+        ));
+
+        let result = vendor_in(&host, &mut device, &mut class, get_result, 0, 4);
+        assert_eq!(result, Some(vec![SendResult::Pending as u8, 0, 0, 0]));
+
+        class.set_send_result(0, SendResult::Sent);
+        let result = vendor_in(&host, &mut device, &mut class, get_result, 0, 4);
+        assert_eq!(result, Some(vec![SendResult::Sent as u8, 0, 0, 0]));
+    }
+
+    #[test]
+    fn refused_send_frame_leaves_the_result_alone() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        fill_control_events(&host, &mut device, &mut class);
+
+        let frame = send_frame_bytes();
+        assert!(!vendor_out(&host, &mut device, &mut class, GsUsbRequest::SendFrame, 0, &frame));
+
+        let get_result = GsUsbRequest::GetSendResult;
+        let result = vendor_in(&host, &mut device, &mut class, get_result, 0, 4);
+        assert_eq!(result, Some(vec![SendResult::None as u8, 0, 0, 0]));
+    }
+
+    /// Mode and identify as reported by GetChannelStatus.
+    fn mode_flags_and_identify(
+        host: &Host,
+        device: &mut UsbDevice<'_, TestBus>,
+        class: &mut TestClass,
+    ) -> (u32, u8) {
+        let status = vendor_in(host, device, class, GsUsbRequest::GetChannelStatus, 0, 64).unwrap();
+        (u32::from_le_bytes(status[4..8].try_into().unwrap()), status[1])
+    }
+
+    fn start_mode(flags: ChannelFlagsBit) -> [u8; 8] {
+        let mut mode = [0; 8];
+        mode[..4].copy_from_slice(&1u32.to_le_bytes());
+        mode[4..].copy_from_slice(&(flags as u32).to_le_bytes());
+        mode
+    }
+
+    #[test]
+    fn queued_mode_and_identify_show_in_the_channel_status() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let mode = start_mode(ChannelFlagsBit::ListenOnly);
+        assert!(vendor_out(&host, &mut device, &mut class, GsUsbRequest::Mode, 0, &mode));
+        let identify = 1u32.to_le_bytes();
+        assert!(vendor_out(&host, &mut device, &mut class, GsUsbRequest::Identify, 0, &identify));
+
+        let status = mode_flags_and_identify(&host, &mut device, &mut class);
+        assert_eq!(status, (ChannelFlagsBit::ListenOnly as u32, 1));
+    }
+
+    #[test]
+    fn refused_mode_and_identify_leave_the_channel_status_alone() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        fill_control_events(&host, &mut device, &mut class);
+
+        let mode = start_mode(ChannelFlagsBit::ListenOnly);
+        assert!(!vendor_out(&host, &mut device, &mut class, GsUsbRequest::Mode, 0, &mode));
+        let identify = 1u32.to_le_bytes();
+        assert!(!vendor_out(&host, &mut device, &mut class, GsUsbRequest::Identify, 0, &identify));
+
+        assert_eq!(mode_flags_and_identify(&host, &mut device, &mut class), (0, 0));
+    }
+
+    #[test]
+    fn termination_requests_use_the_kernel_numbers() {
+        // GS_USB_BREQ_SET_TERMINATION and GS_USB_BREQ_GET_TERMINATION in gs_usb.c.
+        assert_eq!(GsUsbRequest::SetTermination as u8, 12);
+        assert_eq!(GsUsbRequest::GetTermination as u8, 13);
+    }
+
+    #[test]
+    fn termination_is_only_reported_once_queued() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut channel = classic_channel();
+        channel.features = ChannelFeatures::new(&[ChannelFeaturesBit::Termination]);
+        let mut class = TestClass::new(&alloc, 64, [channel], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        let on = 1u32.to_le_bytes();
+        let get_termination = GsUsbRequest::GetTermination;
+
+        fill_control_events(&host, &mut device, &mut class);
+        assert!(!vendor_out(&host, &mut device, &mut class, GsUsbRequest::SetTermination, 0, &on));
+        let state = vendor_in(&host, &mut device, &mut class, get_termination, 0, 4);
+        assert_eq!(state, Some(vec![0, 0, 0, 0]));
+
+        while class.read_control_event().is_some() {}
+
+        assert!(vendor_out(&host, &mut device, &mut class, GsUsbRequest::SetTermination, 0, &on));
+        let state = vendor_in(&host, &mut device, &mut class, get_termination, 0, 4);
+        assert_eq!(state, Some(vec![1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn requests_for_a_missing_channel_queue_nothing() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        let timing = [1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0];
+        let mode = start_mode(ChannelFlagsBit::ListenOnly);
+        let identify = 1u32.to_le_bytes();
+        let frame = send_frame_bytes();
+
+        for (request, data) in [
+            (GsUsbRequest::BitTiming, &timing[..]),
+            (GsUsbRequest::Mode, &mode[..]),
+            (GsUsbRequest::Identify, &identify[..]),
+            (GsUsbRequest::SendFrame, &frame[..]),
+        ] {
+            assert!(!vendor_out(&host, &mut device, &mut class, request, 1, data));
+            assert!(vendor_out(&host, &mut device, &mut class, request, 0, data));
+        }
+
+        let mut queued = 0;
+        while let Some(event) = class.read_control_event() {
+            assert!(!matches!(
+                event,
+                ChannelEvent::BitTiming(_, 1)
+                    | ChannelEvent::ChannelMode(_, 1)
+                    | ChannelEvent::Identify(_, 1)
+                    | ChannelEvent::SendFrame(_, 1)
+            ));
+            queued += 1;
+        }
+        assert_eq!(queued, 4);
+    }
+
+    #[test]
+    fn no_echo_is_only_accepted_where_advertised() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut no_echo = classic_channel();
+        no_echo.features = ChannelFeatures::new(&[ChannelFeaturesBit::NoEcho]);
+        let mut class = GsUsbClass::new(&alloc, 64, [classic_channel(), no_echo], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        let mode = start_mode(ChannelFlagsBit::NoEcho);
+
+        let request = (RequestKind::Vendor, GsUsbRequest::Mode as u8, 0);
+        assert!(!test_bus::control_out(&host, &mut device, &mut class, request, &mode));
+
+        let request = (RequestKind::Vendor, GsUsbRequest::Mode as u8, 1);
+        assert!(test_bus::control_out(&host, &mut device, &mut class, request, &mode));
+        assert!(matches!(
+            class.read_control_event(),
+            Some(ChannelEvent::ChannelMode(_, 1))
+        ));
+    }
+
+    #[test]
+    fn reset_stats_zeroes_every_counter() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        let length = Stats::size() as u16;
+
+        class.stats_mut().rx_frames = 3;
+        class.stats_mut().listen_only_drops = 1;
+        let stats = vendor_in(&host, &mut device, &mut class, GsUsbRequest::GetStats, 0, length);
+        assert!(stats.unwrap().iter().any(|byte| *byte != 0));
+
+        assert!(vendor_out(&host, &mut device, &mut class, GsUsbRequest::ResetStats, 0, &[]));
+
+        let stats = vendor_in(&host, &mut device, &mut class, GsUsbRequest::GetStats, 0, length);
+        assert_eq!(stats, Some(vec![0; Stats::size()]));
+    }
+
+    #[test]
+    fn fd_mode_is_only_accepted_on_fd_channels() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = GsUsbClass::new(&alloc, 64, [classic_channel(), fd_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        let mode = start_mode(ChannelFlagsBit::Fd);
+
+        let request = (RequestKind::Vendor, GsUsbRequest::Mode as u8, 0);
+        assert!(!test_bus::control_out(&host, &mut device, &mut class, request, &mode));
+        assert!(class.read_control_event().is_none());
+
+        let request = (RequestKind::Vendor, GsUsbRequest::Mode as u8, 1);
+        assert!(test_bus::control_out(&host, &mut device, &mut class, request, &mode));
+        assert!(matches!(
+            class.read_control_event(),
+            Some(ChannelEvent::ChannelMode(_, 1))
+        ));
+    }
+
+    #[test]
+    fn bt_const_ext_spans_two_control_packets() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut channel = fd_channel();
+        channel.data_constraints.as_mut().unwrap().brp_max = 32;
+        let mut class = TestClass::new(&alloc, 64, [channel], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let bt_const_ext = GsUsbRequest::BtConstExt;
+        let response = vendor_in(&host, &mut device, &mut class, bt_const_ext, 0, 128).unwrap();
+
+        assert_eq!(response.len(), BT_CONST_EXT_SIZE);
+        assert_eq!(response[4..8], 8_000_000u32.to_le_bytes());
+        // brp_max of the data phase, the first field past the first packet.
+        assert_eq!(response[64..68], 32u32.to_le_bytes());
+
+        // The extended constants start like the classic ones.
+        let bt_const = vendor_in(&host, &mut device, &mut class, GsUsbRequest::BtConst, 0, 64);
+        assert_eq!(bt_const.as_deref(), Some(&response[..BT_CONST_SIZE]));
+    }
+
+    #[test]
+    fn data_phase_requests_are_only_accepted_on_fd_channels() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = GsUsbClass::new(&alloc, 64, [classic_channel(), fd_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+        let timing = [1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0];
+
+        for (channel, fd) in [(0, false), (1, true)] {
+            let request = (RequestKind::Vendor, GsUsbRequest::DataBitTiming as u8, channel);
+            let accepted = test_bus::control_out(&host, &mut device, &mut class, request, &timing);
+            assert_eq!(accepted, fd);
+
+            let request = (RequestKind::Vendor, GsUsbRequest::BtConstExt as u8, channel);
+            let response = test_bus::control_in(&host, &mut device, &mut class, request, 128);
+            assert_eq!(response.is_some(), fd);
+        }
+
+        assert!(matches!(
+            class.read_control_event(),
+            Some(ChannelEvent::DataBitTiming(_, 1))
+        ));
+        assert!(class.read_control_event().is_none());
+    }
+
+    #[test]
+    fn vendor_request_bitmap_lists_the_implemented_requests() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        // UsbStatus through GetSendResult, InjectFrame only when built in.
+        let mut expected = (1u32 << (GsUsbRequest::GetSendResult as u8 - 0x80 + 1)) - 1;
+        if !cfg!(feature = "frame-injection") {
+            expected &= !(1 << (GsUsbRequest::InjectFrame as u8 - 0x80));
+        }
+
+        let get_requests = GsUsbRequest::GetVendorRequests;
+        let bitmap = vendor_in(&host, &mut device, &mut class, get_requests, 0, 4);
+        assert_eq!(bitmap, Some(expected.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn timer_resolution_is_reported_in_ticks_per_microsecond() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let get_resolution = GsUsbRequest::GetTimerResolution;
+        let resolution = vendor_in(&host, &mut device, &mut class, get_resolution, 0, 4);
+        assert_eq!(resolution, Some(vec![1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn device_config_is_the_twelve_byte_gs_device_config() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = GsUsbClass::new(&alloc, 64, [classic_channel(), classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let request = (RequestKind::Vendor, GsUsbRequest::DeviceConfig as u8, 0);
+        let config = test_bus::control_in(&host, &mut device, &mut class, request, 64);
+        assert_eq!(config, Some(vec![0, 0, 0, 1, 1, 0, 0, 0, 2, 0, 0, 0]));
+
+        class.set_config_reserved([4, 5, 6]);
+        let config = test_bus::control_in(&host, &mut device, &mut class, request, 64).unwrap();
+        assert_eq!(config[..4], [4, 5, 6, 1]);
+    }
+
+    #[test]
+    fn every_channel_request_refuses_a_missing_channel() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut channel = classic_channel();
+        channel.features = ChannelFeatures::new(&[
+            ChannelFeaturesBit::Termination,
+            ChannelFeaturesBit::BtConstExt,
+        ]);
+        let mut class = TestClass::new(&alloc, 64, [channel], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        for request in [
+            GsUsbRequest::BtConst,
+            GsUsbRequest::BtConstExt,
+            GsUsbRequest::GetSendResult,
+            GsUsbRequest::GetTermination,
+            GsUsbRequest::GetChannelStatus,
+            GsUsbRequest::GetBitRate,
+            GsUsbRequest::GetMaxDataLength,
+            GsUsbRequest::GetCnfRegisters,
+        ] {
+            assert!(vendor_in(&host, &mut device, &mut class, request, 0, 128).is_some());
+            assert!(vendor_in(&host, &mut device, &mut class, request, 1, 128).is_none());
+        }
+
+        let features = (ChannelFeaturesBit::Termination as u32).to_le_bytes();
+        let set_features = GsUsbRequest::SetChannelFeatures;
+        assert!(!vendor_out(&host, &mut device, &mut class, set_features, 1, &features));
+        assert!(vendor_out(&host, &mut device, &mut class, set_features, 0, &features));
+    }
+
+    #[test]
+    fn bus_reset_drops_queued_events_for_a_host_reset() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut class = TestClass::new(&alloc, 64, [classic_channel()], 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let mode = start_mode(ChannelFlagsBit::ListenOnly);
+        assert!(vendor_out(&host, &mut device, &mut class, GsUsbRequest::Mode, 0, &mode));
+        let frame = send_frame_bytes();
+        assert!(vendor_out(&host, &mut device, &mut class, GsUsbRequest::SendFrame, 0, &frame));
+        let pause = GsUsbRequest::SetDeliveryPaused;
+        assert!(vendor_out(&host, &mut device, &mut class, pause, 1, &[]));
+
+        class.reset();
+
+        assert!(matches!(class.read_control_event(), Some(ChannelEvent::HostReset)));
+        assert!(class.read_control_event().is_none());
+        assert!(!class.delivery_paused());
+
+        let get_result = GsUsbRequest::GetSendResult;
+        let result = vendor_in(&host, &mut device, &mut class, get_result, 0, 4);
+        assert_eq!(result, Some(vec![SendResult::None as u8, 0, 0, 0]));
+    }
+
+    #[test]
+    fn channel_count_is_the_generic_and_reported_to_the_host() {
+        assert_eq!(TestClass::channel_count(), 1);
+        assert_eq!(GsUsbClass::<TestBus, 3>::channel_count(), 3);
+        assert_eq!(GsUsbPort::<TestBus, 3>::channel_count(), 3);
+
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let channels = [classic_channel(), classic_channel(), classic_channel()];
+        let mut class = GsUsbClass::new(&alloc, 64, channels, 1, 2);
+        let mut device = test_bus::device(&alloc);
+
+        let request = (RequestKind::Vendor, GsUsbRequest::UsbStatus as u8, 0);
+        let status = test_bus::control_in(&host, &mut device, &mut class, request, 4).unwrap();
+        assert_eq!(status[3], 3);
+
+        let request = (RequestKind::Vendor, GsUsbRequest::DeviceConfig as u8, 0);
+        let config = test_bus::control_in(&host, &mut device, &mut class, request, 12).unwrap();
+        assert_eq!(config[3], 2);
+    }
+}
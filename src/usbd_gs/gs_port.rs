@@ -1,22 +1,39 @@
 use super::Channel;
 use super::ChannelEvent;
+use super::FlowControl;
 use super::GsUsbClass;
 use super::HostFrame;
+use super::HostFrameFlagsBits;
+use super::RxOverflowPolicy;
+use super::SendResult;
+use super::Stats;
+use super::MAX_FS_PACKET_SIZE;
 
+use scroll::{Pread, Pwrite, LE};
+#[cfg(feature = "debug-endpoint")]
+use ringbuffer::*;
 use usb_device::class_prelude::*;
+use usb_device::device::UsbDeviceState;
 use usb_device::Result;
 
 pub struct GsUsbPort<'a, B: UsbBus, const C: usize> {
     underlying: GsUsbClass<'a, B, C>,
-    read_buffer: [u8; frame_size()],
+    read_buffer: [u8; read_buffer_size()],
     read_state: ReadState,
+    /// Start of the next frame in a full read buffer holding several.
+    read_offset: usize,
     write_buffer: [u8; frame_size()],
     write_state: WriteState,
+    read_errors: u32,
+    #[cfg(feature = "tx-depth-hint")]
+    tx_depth_hint: u8,
+    #[cfg(feature = "debug-endpoint")]
+    debug_log: ConstGenericRingBuffer<u8, 256>,
 }
 
 impl<B: UsbBus, const C: usize> GsUsbPort<'_, B, C> {
-    /// Creates a new GsUsbPort with the provided UsbBus and max_packet_size in bytes. For
-    /// full-speed devices, max_packet_size has to be one of 8, 16, 32 or 64.
+    /// Creates a new GsUsbPort with the provided UsbBus and max_packet_size in bytes.
+    /// max_packet_size has to be one of 8, 16, 32 or 64, see `validate_packet_size`.
     pub fn new(
         alloc: &UsbBusAllocator<B>,
         max_packet_size: u16,
@@ -26,43 +43,182 @@ impl<B: UsbBus, const C: usize> GsUsbPort<'_, B, C> {
     ) -> GsUsbPort<'_, B, C> {
         GsUsbPort {
             underlying: GsUsbClass::new(alloc, max_packet_size, channels, sw_version, hw_version),
-            read_buffer: [0; frame_size()],
+            read_buffer: [0; read_buffer_size()],
             read_state: ReadState::Empty,
+            read_offset: 0,
             write_buffer: [0; frame_size()],
             write_state: WriteState::Ready,
+            read_errors: 0,
+            #[cfg(feature = "tx-depth-hint")]
+            tx_depth_hint: 0,
+            #[cfg(feature = "debug-endpoint")]
+            debug_log: ConstGenericRingBuffer::new(),
         }
     }
 
+    /// Number of bulk OUT errors that caused a partial frame to be dropped.
+    #[allow(dead_code)]
+    pub fn read_errors(&self) -> u32 {
+        self.read_errors
+    }
+
+    /// Number of CAN channels, the C parameter.
+    #[allow(dead_code)]
+    pub const fn channel_count() -> usize {
+        GsUsbClass::<B, C>::channel_count()
+    }
+
     pub fn read_control_event(&mut self) -> Option<ChannelEvent> {
         self.underlying.read_control_event()
     }
 
+    pub fn flow_control(&self) -> FlowControl {
+        self.underlying.flow_control()
+    }
+
+    #[allow(dead_code)]
+    pub fn set_flow_control(&mut self, flow_control: FlowControl) {
+        self.underlying.set_flow_control(flow_control)
+    }
+
+    pub fn rx_overflow_policy(&self) -> RxOverflowPolicy {
+        self.underlying.rx_overflow_policy()
+    }
+
+    pub fn delivery_paused(&self) -> bool {
+        self.underlying.delivery_paused()
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        self.underlying.stats_mut()
+    }
+
+    pub fn set_bit_rate(&mut self, channel: usize, bit_rate: u32) {
+        self.underlying.set_bit_rate(channel, bit_rate)
+    }
+
+    pub fn set_data_bit_rate(&mut self, channel: usize, bit_rate: u32) {
+        self.underlying.set_data_bit_rate(channel, bit_rate)
+    }
+
+    pub fn set_cnf_registers(&mut self, channel: usize, cnf: [u8; 3]) {
+        self.underlying.set_cnf_registers(channel, cnf)
+    }
+
+    pub fn set_running(&mut self, channel: usize, running: bool) {
+        self.underlying.set_running(channel, running)
+    }
+
+    pub fn set_send_result(&mut self, channel: usize, result: SendResult) {
+        self.underlying.set_send_result(channel, result)
+    }
+
+    pub fn set_temperature(&mut self, millicelsius: i32) {
+        self.underlying.set_temperature(millicelsius)
+    }
+
+    pub fn set_fault(&mut self, fault: u8) {
+        self.underlying.set_fault(fault)
+    }
+
+    pub fn set_usb_state(&mut self, state: UsbDeviceState) {
+        self.underlying.set_usb_state(state)
+    }
+
+    pub fn usb_state(&self) -> UsbDeviceState {
+        self.underlying.usb_state()
+    }
+
+    /// Queues a debug message for the debug endpoint, prefixed with its length. Messages that
+    /// don't fit in the remaining space are dropped whole so the stream stays parseable.
+    #[cfg(feature = "debug-endpoint")]
+    pub fn write_debug(&mut self, message: &[u8]) {
+        let length = core::cmp::min(message.len(), u8::MAX as usize);
+
+        if self.debug_log.capacity() - self.debug_log.len() < length + 1 {
+            return;
+        }
+
+        self.debug_log.push(length as u8);
+        message[..length].iter().for_each(|byte| self.debug_log.push(*byte));
+    }
+
+    #[cfg(feature = "debug-endpoint")]
+    fn poll_debug(&mut self) {
+        if self.debug_log.is_empty() {
+            return;
+        }
+
+        let mut packet: [u8; 64] = [0; 64];
+        let size = core::cmp::min(
+            self.debug_log.len(),
+            core::cmp::min(packet.len(), self.underlying.max_packet_size()),
+        );
+
+        packet
+            .iter_mut()
+            .zip(self.debug_log.iter())
+            .for_each(|(dst, src)| *dst = *src);
+
+        if let Ok(written) = self.underlying.write_debug_packet(&packet[..size]) {
+            (0..written).for_each(|_| self.debug_log.skip());
+        }
+    }
+
+    /// Returns the next frame from the host once all of its packets have arrived. Classic frames
+    /// are shorter than HostFrame, the missing data bytes read as zero.
+    ///
+    /// Classic frames can be batched into one transfer, back to back, and are returned one per
+    /// call. A transfer that isn't a whole number of classic frames, like a frame followed by a
+    /// timestamp, or that starts with an FD frame holds a single frame. A batch of more than
+    /// MAX_BATCH frames doesn't fit the read buffer and is dropped whole, counted in
+    /// `read_errors`.
     pub fn read_frame(&mut self) -> Result<HostFrame> {
-        todo!()
-        // match &self.read_state {
-        //     ReadState::Full => {
-        //         self.read_state = ReadState::Empty;
-        //         match HostFrame::unpack(&self.read_buffer) {
-        //             Ok(frame) => Ok(frame),
-        //             Err(_) => Err(UsbError::ParseError),
-        //         }
-        //     }
-        //     _ => Err(UsbError::WouldBlock),
-        // }
+        let length = match self.read_state {
+            ReadState::Full(length) => length,
+            _ => return Err(UsbError::WouldBlock),
+        };
+
+        let (stride, frame) = frame_at(&self.read_buffer[..length], self.read_offset);
+        self.read_offset += stride;
+
+        if self.read_offset >= length {
+            self.read_offset = 0;
+            self.read_state = ReadState::Empty;
+        }
+
+        frame
     }
 
+    /// Sets the hint carried in the reserved byte of the frames sent to the host from now on: the
+    /// number of host frames waiting to be transmitted, saturating at 255. gs_usb hosts ignore
+    /// the byte, so only tooling that knows about the hint reads it.
+    #[cfg(feature = "tx-depth-hint")]
+    pub fn set_tx_depth_hint(&mut self, depth: usize) {
+        self.tx_depth_hint = core::cmp::min(depth, u8::MAX as usize) as u8;
+    }
+
+    /// Queues a frame for the host. Fails with WouldBlock while the previous frame is still being
+    /// written.
     pub fn write_frame(&mut self, frame: &HostFrame) -> Result<()> {
-        todo!()
-        // match &self.write_state {
-        //     WriteState::Ready => match frame.pack_to_slice(&mut self.write_buffer) {
-        //         Ok(_) => {
-        //             self.write_state = WriteState::Writing(frame_size());
-        //             Ok(())
-        //         }
-        //         Err(_) => Err(UsbError::ParseError),
-        //     },
-        //     WriteState::Writing(_) => Err(UsbError::WouldBlock),
-        // }
+        match self.write_state {
+            WriteState::Ready => match self.write_buffer.pwrite_with(frame, 0, LE) {
+                Ok(_) => {
+                    #[cfg(feature = "tx-depth-hint")]
+                    {
+                        self.write_buffer[RESERVED_OFFSET] = self.tx_depth_hint;
+                    }
+
+                    self.write_state = WriteState::Writing {
+                        sent: 0,
+                        packet_size: self.underlying.max_packet_size(),
+                    };
+                    Ok(())
+                }
+                Err(_) => Err(UsbError::ParseError),
+            },
+            WriteState::Writing { .. } => Err(UsbError::WouldBlock),
+        }
     }
 }
 
@@ -72,43 +228,82 @@ impl<B: UsbBus, const C: usize> UsbClass<B> for GsUsbPort<'_, B, C> {
     }
 
     fn reset(&mut self) {
+        self.underlying.reset();
         self.underlying.stall();
     }
 
     fn poll(&mut self) {
-        if self.read_state != ReadState::Full {
+        if self.read_state == ReadState::Discarding {
+            let packet_size = self.underlying.max_packet_size();
+
+            match self.underlying.read_packet(&mut self.read_buffer) {
+                Ok(size) if size == packet_size => {}
+                Ok(_) => self.read_state = ReadState::Empty,
+                Err(_) => {}
+            }
+        } else if !matches!(self.read_state, ReadState::Full(_)) {
             let index = self.read_state.index();
             let packet_size = self.underlying.max_packet_size();
             let read_bytes = self.underlying.read_packet(&mut self.read_buffer[index..]);
 
+            // A zero-length packet before any data belongs to no frame, some hosts send one
+            // after every transfer.
             match read_bytes {
-                Ok(size) if size == packet_size => {
-                    self.read_state = ReadState::WaitingForPacket(index + size)
+                Ok(0) if index == 0 => {}
+                Ok(size) => {
+                    self.read_state = self.read_state.after_packet(size, packet_size);
+
+                    if self.read_state == ReadState::Discarding {
+                        self.read_errors = self.read_errors.wrapping_add(1);
+                        defmt::warn!("bulk OUT transfer too long, dropping it");
+                    }
                 }
-                Ok(_) => self.read_state = ReadState::Full,
                 Err(UsbError::WouldBlock) => {}
-                Err(_) => self.read_state = ReadState::Empty,
+                Err(_) => {
+                    self.read_errors = self.read_errors.wrapping_add(1);
+
+                    // The rest of the transfer is still on its way. Treating it as the start of
+                    // the next frame would deliver garbage, so drop packets until the short packet
+                    // that ends the transfer.
+                    if index > 0 {
+                        defmt::warn!("bulk OUT error after {} bytes, dropping frame", index);
+                        self.read_state = ReadState::Discarding;
+                    } else {
+                        defmt::warn!("bulk OUT error, dropping frame");
+                        self.read_state = ReadState::Empty;
+                    }
+                }
             }
         }
 
+        #[cfg(feature = "debug-endpoint")]
+        self.poll_debug();
+
         let was_writing_ready = self.write_state == WriteState::Ready;
 
-        if let WriteState::Writing(remainder) = self.write_state {
-            if remainder == 0 {
+        if let WriteState::Writing { sent, packet_size } = self.write_state {
+            // A transfer split into packets of one size and continued with another would skip or
+            // repeat bytes. The size is fixed when the endpoint is allocated, so this only guards
+            // against that changing.
+            debug_assert_eq!(packet_size, self.underlying.max_packet_size());
+
+            if sent == frame_size() {
+                // The last packet was full, a zero-length packet ends the transfer.
                 self.underlying.write_packet(&[]).ok();
                 self.write_state = WriteState::Ready;
             } else {
-                let packet_size = self.underlying.max_packet_size();
-                let from_index = frame_size() - remainder;
-                let to_index = core::cmp::min(frame_size(), from_index + packet_size);
+                let to_index = core::cmp::min(frame_size(), sent + packet_size);
                 let written_bytes = self
                     .underlying
-                    .write_packet(&self.write_buffer[from_index..to_index]);
+                    .write_packet(&self.write_buffer[sent..to_index]);
 
                 match written_bytes {
                     Ok(bytes) => {
                         if bytes == packet_size {
-                            self.write_state = WriteState::Writing(remainder - bytes);
+                            self.write_state = WriteState::Writing {
+                                sent: sent + bytes,
+                                packet_size,
+                            };
                         } else {
                             self.write_state = WriteState::Ready;
                         }
@@ -145,11 +340,41 @@ const fn frame_size() -> usize {
     core::mem::size_of::<HostFrame>()
 }
 
+/// Most classic frames the host may batch into one transfer.
+const MAX_BATCH: usize = 9;
+
+/// Three full-speed packets, a whole number of packets of any size so a packet never arrives
+/// with less room left than it needs. Holds an FD frame followed by a 32-bit hardware
+/// timestamp, or MAX_BATCH classic frames.
+const fn read_buffer_size() -> usize {
+    3 * MAX_FS_PACKET_SIZE
+}
+
+/// Offset of the flags after echo_id, can_id, can_dlc and channel.
+const FLAGS_OFFSET: usize = 10;
+
+/// Offset of the reserved byte after echo_id, can_id, can_dlc, channel and flags.
+#[cfg(feature = "tx-depth-hint")]
+const RESERVED_OFFSET: usize = 11;
+
+/// A classic frame on the wire: the 12 byte header and 8 data bytes.
+const CLASSIC_FRAME_SIZE: usize = 12 + 8;
+
+// The buffers are sent and parsed as gs_host_frame, 12 header bytes and 64 data bytes. Padding
+// added by a change to HostFrame would shift every field after it.
+const _: () = assert!(frame_size() == 12 + 64);
+
+const _: () = assert!(read_buffer_size().is_multiple_of(MAX_FS_PACKET_SIZE));
+const _: () = assert!(read_buffer_size() >= frame_size() + 4);
+const _: () = assert!(read_buffer_size() >= MAX_BATCH * CLASSIC_FRAME_SIZE);
+const _: () = assert!(frame_size() >= MAX_FS_PACKET_SIZE);
+
 #[derive(PartialEq, Eq)]
 enum ReadState {
     Empty,
     WaitingForPacket(usize),
-    Full,
+    Full(usize),
+    Discarding,
 }
 
 impl ReadState {
@@ -157,13 +382,395 @@ impl ReadState {
         match self {
             ReadState::Empty => 0,
             ReadState::WaitingForPacket(index) => *index,
-            ReadState::Full => frame_size(),
+            ReadState::Full(length) => *length,
+            ReadState::Discarding => 0,
         }
     }
+
+    /// The state once a packet of `size` bytes was read at `index()`. A transfer ends with a
+    /// short packet, an FD frame with timestamp is 80 bytes and arrives as 64 + 16. No transfer
+    /// the buffer holds is a multiple of the packet size, so a full packet filling the buffer
+    /// means the transfer is longer than that: the rest of it is discarded along with the frames
+    /// already read.
+    fn after_packet(&self, size: usize, packet_size: usize) -> ReadState {
+        let index = self.index();
+
+        match (size == packet_size, index + size < read_buffer_size()) {
+            (true, true) => ReadState::WaitingForPacket(index + size),
+            (true, false) => ReadState::Discarding,
+            (false, _) => ReadState::Full(index + size),
+        }
+    }
+}
+
+/// Parses the frame at `offset` of a complete transfer, returning the number of bytes it takes
+/// in the transfer along with it. A transfer too short to hold the flags is a ParseError and
+/// taken whole.
+fn frame_at(transfer: &[u8], offset: usize) -> (usize, Result<HostFrame>) {
+    let length = transfer.len();
+
+    if length <= FLAGS_OFFSET {
+        return (length, Err(UsbError::ParseError));
+    }
+
+    let fd = transfer[FLAGS_OFFSET] & HostFrameFlagsBits::Fd as u8 != 0;
+    let stride = match length.is_multiple_of(CLASSIC_FRAME_SIZE) && !fd {
+        true => CLASSIC_FRAME_SIZE,
+        false => length,
+    };
+
+    let frame_length = core::cmp::min(stride, frame_size());
+    let mut frame = [0; frame_size()];
+    frame[..frame_length].copy_from_slice(&transfer[offset..offset + frame_length]);
+
+    (stride, frame.pread_with(0, LE).map_err(|_| UsbError::ParseError))
 }
 
 #[derive(PartialEq, Eq)]
 enum WriteState {
     Ready,
-    Writing(usize),
+    /// `sent` bytes of the frame are written, in packets of `packet_size` bytes.
+    Writing { sent: usize, packet_size: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbd_gs::test_bus::{self, RequestKind, TestBus};
+    use usb_device::UsbDirection;
+    use crate::usbd_gs::{ChannelConstraints, ChannelFeatures, HostCanId, HostFrameFlags};
+
+    type TestPort<'a> = GsUsbPort<'a, TestBus, 1>;
+
+    /// The bulk OUT and IN endpoints are the first of each direction the class allocates.
+    const BULK_OUT: usize = 1;
+    const BULK_IN: usize = 1;
+
+    /// The SetIdleStall vendor request.
+    const SET_IDLE_STALL: u8 = 0x94;
+
+    fn port(alloc: &UsbBusAllocator<TestBus>, packet_size: u16) -> TestPort<'_> {
+        let channel = Channel {
+            features: ChannelFeatures::new(&[]),
+            fclk_can: 8_000_000,
+            constraints: ChannelConstraints {
+                tseg1_min: 3,
+                tseg1_max: 8,
+                tseg2_min: 2,
+                tseg2_max: 8,
+                sjw_max: 4,
+                brp_min: 1,
+                brp_max: 64,
+                brp_inc: 1,
+            },
+            data_constraints: None,
+        };
+
+        GsUsbPort::new(alloc, packet_size, [channel], 1, 2)
+    }
+
+    fn classic_frame(id: u32) -> [u8; CLASSIC_FRAME_SIZE] {
+        let frame = HostFrame::new(
+            Some(0),
+            HostCanId::new(id, &[]).unwrap(),
+            8,
+            0,
+            HostFrameFlags::new(&[]),
+            [0; 64],
+        );
+        let mut bytes = [0; frame_size()];
+        bytes.pwrite_with(frame, 0, LE).unwrap();
+        bytes[..CLASSIC_FRAME_SIZE].try_into().unwrap()
+    }
+
+    #[test]
+    fn fd_frame_with_timestamp_arrives_as_64_and_16() {
+        let state = ReadState::Empty.after_packet(64, 64);
+        assert!(state == ReadState::WaitingForPacket(64));
+
+        let state = state.after_packet(16, 64);
+        assert!(state == ReadState::Full(80));
+    }
+
+    #[test]
+    fn fd_frame_without_timestamp_ends_with_a_short_packet() {
+        let state = ReadState::Empty.after_packet(64, 64);
+        let state = state.after_packet(12, 64);
+
+        assert!(state == ReadState::Full(76));
+    }
+
+    #[test]
+    fn short_first_packet_is_a_whole_transfer() {
+        assert!(ReadState::Empty.after_packet(20, 64) == ReadState::Full(20));
+    }
+
+    #[test]
+    fn largest_batch_fits_the_buffer() {
+        let state = ReadState::Empty.after_packet(64, 64);
+        let state = state.after_packet(64, 64);
+        let state = state.after_packet(MAX_BATCH * CLASSIC_FRAME_SIZE - 128, 64);
+
+        assert!(state == ReadState::Full(MAX_BATCH * CLASSIC_FRAME_SIZE));
+    }
+
+    #[test]
+    fn longer_transfer_is_discarded() {
+        let state = ReadState::Empty.after_packet(64, 64);
+        let state = state.after_packet(64, 64);
+
+        assert!(state.after_packet(64, 64) == ReadState::Discarding);
+    }
+
+    #[test]
+    fn buffer_takes_whole_packets_of_any_size() {
+        for packet_size in [8, 16, 32, 64] {
+            let mut state = ReadState::Empty;
+
+            while let ReadState::Empty | ReadState::WaitingForPacket(_) = state {
+                assert!(read_buffer_size() - state.index() >= packet_size);
+                state = state.after_packet(packet_size, packet_size);
+            }
+
+            assert!(state == ReadState::Discarding);
+        }
+    }
+
+    #[test]
+    fn reassembled_fd_frame_is_read_whole() {
+        let mut bytes = [0; 64];
+        bytes[0] = 0x11;
+        bytes[63] = 0x22;
+        let frame = HostFrame::new(
+            Some(3),
+            HostCanId::new(0x123, &[]).unwrap(),
+            15,
+            0,
+            HostFrameFlags::new(&[HostFrameFlagsBits::Fd]),
+            bytes,
+        );
+        // Followed by a hardware timestamp.
+        let mut transfer = [0xaa; 80];
+        transfer.pwrite_with(&frame, 0, LE).unwrap();
+
+        let (stride, frame) = frame_at(&transfer, 0);
+        let frame = frame.ok().unwrap();
+
+        assert_eq!(stride, 80);
+        assert_eq!(frame.echo_id, 3);
+        assert_eq!(frame.can_dlc, 15);
+        assert_eq!(frame.bytes[0], 0x11);
+        assert_eq!(frame.bytes[63], 0x22);
+    }
+
+    #[test]
+    fn batched_classic_frames_are_read_one_by_one() {
+        let mut transfer = [0; 40];
+
+        for (index, id) in [0x100, 0x200].into_iter().enumerate() {
+            let frame = HostFrame::new(
+                Some(index as u32),
+                HostCanId::new(id, &[]).unwrap(),
+                8,
+                0,
+                HostFrameFlags::new(&[]),
+                [0; 64],
+            );
+            let mut bytes = [0; frame_size()];
+            bytes.pwrite_with(frame, 0, LE).unwrap();
+            transfer[index * CLASSIC_FRAME_SIZE..][..CLASSIC_FRAME_SIZE]
+                .copy_from_slice(&bytes[..CLASSIC_FRAME_SIZE]);
+        }
+
+        let (stride, first) = frame_at(&transfer, 0);
+        assert_eq!(stride, CLASSIC_FRAME_SIZE);
+        assert!(first.ok().unwrap().can_id == HostCanId::new(0x100, &[]).unwrap());
+
+        let (_, second) = frame_at(&transfer, stride);
+        assert!(second.ok().unwrap().can_id == HostCanId::new(0x200, &[]).unwrap());
+    }
+
+    #[test]
+    fn transfer_without_flags_is_a_parse_error() {
+        for length in 0..=FLAGS_OFFSET {
+            let transfer = [0; FLAGS_OFFSET + 1];
+            let (stride, frame) = frame_at(&transfer[..length], 0);
+
+            assert_eq!(stride, length);
+            assert!(matches!(frame, Err(UsbError::ParseError)));
+        }
+
+        let (_, frame) = frame_at(&[0; FLAGS_OFFSET + 1], 0);
+        assert!(frame.is_ok());
+    }
+
+    #[test]
+    fn read_error_mid_frame_drops_the_rest_of_the_transfer() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut port = port(&alloc, 64);
+        let _device = test_bus::device(&alloc);
+
+        // An FD frame broken by a corrupted packet, then the short packet that ends its transfer.
+        host.send(BULK_OUT, &[0; 64]);
+        host.send_error(BULK_OUT, UsbError::ParseError);
+        host.send(BULK_OUT, &[0; 12]);
+        host.send(BULK_OUT, &classic_frame(0x123));
+
+        for _ in 0..4 {
+            assert!(matches!(port.read_frame(), Err(UsbError::WouldBlock)));
+            port.poll();
+        }
+
+        let frame = port.read_frame().ok().unwrap();
+        assert!(frame.can_id == HostCanId::new(0x123, &[]).unwrap());
+        assert!(matches!(port.read_frame(), Err(UsbError::WouldBlock)));
+        assert_eq!(port.read_errors(), 1);
+    }
+
+    #[test]
+    fn zero_length_packet_ends_a_transfer_of_full_packets() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut port = port(&alloc, 32);
+        let _device = test_bus::device(&alloc);
+
+        // Eight classic frames are five 32 byte packets, the host ends them with a ZLP. The
+        // leading ZLP belongs to no transfer.
+        let batch: Vec<u8> = (0..8).flat_map(|index| classic_frame(0x100 + index)).collect();
+        host.send(BULK_OUT, &[]);
+        batch.chunks(32).for_each(|packet| host.send(BULK_OUT, packet));
+        host.send(BULK_OUT, &[]);
+        host.send(BULK_OUT, &classic_frame(0x200));
+
+        for _ in 0..7 {
+            assert!(matches!(port.read_frame(), Err(UsbError::WouldBlock)));
+            port.poll();
+        }
+
+        for index in 0..8 {
+            let frame = port.read_frame().ok().unwrap();
+            assert!(frame.can_id == HostCanId::new(0x100 + index, &[]).unwrap());
+        }
+
+        port.poll();
+        let frame = port.read_frame().ok().unwrap();
+        assert!(frame.can_id == HostCanId::new(0x200, &[]).unwrap());
+        assert_eq!(port.read_errors(), 0);
+    }
+
+    #[test]
+    fn frame_is_written_whole_with_any_packet_size() {
+        let mut bytes = [0; 64];
+        bytes.iter_mut().enumerate().for_each(|(index, byte)| *byte = index as u8);
+        let frame = HostFrame::new(
+            Some(7),
+            HostCanId::new(0x123, &[]).unwrap(),
+            15,
+            0,
+            HostFrameFlags::new(&[HostFrameFlagsBits::Fd]),
+            bytes,
+        );
+        let mut expected = [0; frame_size()];
+        expected.pwrite_with(&frame, 0, LE).unwrap();
+
+        for packet_size in [8, 16, 32, 64] {
+            let (bus, host) = TestBus::new();
+            let alloc = UsbBusAllocator::new(bus);
+            let mut port = port(&alloc, packet_size as u16);
+            let _device = test_bus::device(&alloc);
+
+            assert!(port.write_frame(&frame).is_ok());
+            assert!(matches!(port.write_frame(&frame), Err(UsbError::WouldBlock)));
+
+            let mut written = Vec::new();
+
+            loop {
+                port.poll();
+                let packet = host.receive(BULK_IN).unwrap();
+                written.extend_from_slice(&packet);
+
+                if packet.len() < packet_size {
+                    break;
+                }
+            }
+
+            assert_eq!(written, expected);
+            assert!(port.write_frame(&frame).is_ok());
+        }
+    }
+
+    #[test]
+    fn bulk_in_never_stalls_with_idle_stall_off() {
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut port = port(&alloc, 64);
+        let mut device = test_bus::device(&alloc);
+        let bulk_in = EndpointAddress::from_parts(BULK_IN, UsbDirection::In);
+
+        port.reset();
+        assert!(host.is_stalled(bulk_in));
+
+        let request = (RequestKind::Vendor, SET_IDLE_STALL, 0);
+        assert!(test_bus::control_out(&host, &mut device, &mut port, request, &[]));
+        assert!(!host.is_stalled(bulk_in));
+
+        port.reset();
+        assert!(!host.is_stalled(bulk_in));
+
+        let frame = HostFrame::new(
+            Some(0),
+            HostCanId::new(0x123, &[]).unwrap(),
+            8,
+            0,
+            HostFrameFlags::new(&[]),
+            [0; 64],
+        );
+        assert!(port.write_frame(&frame).is_ok());
+
+        // Written as a 64 byte packet and a short one, then idle again.
+        for _ in 0..3 {
+            port.poll();
+            assert!(!host.is_stalled(bulk_in));
+            host.receive(BULK_IN);
+        }
+    }
+
+    #[cfg(feature = "debug-endpoint")]
+    #[test]
+    fn debug_messages_drain_apart_from_frames() {
+        // Allocated after the frame endpoints.
+        const DEBUG_IN: usize = 2;
+
+        let (bus, host) = TestBus::new();
+        let alloc = UsbBusAllocator::new(bus);
+        let mut port = port(&alloc, 64);
+        let _device = test_bus::device(&alloc);
+        let frame = HostFrame::new(
+            Some(0),
+            HostCanId::new(0x123, &[]).unwrap(),
+            8,
+            0,
+            HostFrameFlags::new(&[]),
+            [0; 64],
+        );
+
+        assert!(port.write_frame(&frame).is_ok());
+        port.write_debug(b"one");
+        port.poll();
+        assert_eq!(host.receive(DEBUG_IN), Some(b"\x03one".to_vec()));
+
+        // The host isn't reading frames, debug messages still go out.
+        port.write_debug(b"two");
+        port.poll();
+        assert_eq!(host.receive(DEBUG_IN), Some(b"\x03two".to_vec()));
+
+        // Nor the other way around.
+        port.write_debug(b"three");
+        port.poll();
+        assert_eq!(host.receive(BULK_IN).map(|packet| packet.len()), Some(64));
+        port.poll();
+        assert_eq!(host.receive(BULK_IN).map(|packet| packet.len()), Some(frame_size() - 64));
+        assert_eq!(host.receive(DEBUG_IN), Some(b"\x05three".to_vec()));
+    }
 }
@@ -1,11 +1,17 @@
 mod channel_config;
 mod channel_event;
+mod channel_status;
 mod frame;
 mod gs_class;
 mod gs_port;
+mod stats;
+#[cfg(test)]
+mod test_bus;
 
 pub use channel_config::*;
 pub use channel_event::*;
+pub use channel_status::*;
 pub use frame::*;
 pub use gs_class::*;
 pub use gs_port::*;
+pub use stats::*;
@@ -0,0 +1,82 @@
+use scroll::{Pwrite, LE};
+
+/// Device-wide traffic counters, read by the host with the GetStats request. All counters wrap.
+#[derive(Pwrite, Clone, Copy, Default)]
+pub struct Stats {
+    pub rx_frames: u32,
+    pub tx_frames: u32,
+    pub tx_errors: u32,
+    /// Frames from the host the controller can't send, like FD frames, echoed as failed.
+    pub host_frames_dropped: u32,
+    pub mode_change_failures: u32,
+    /// Received frames lost because the queue towards the host was full.
+    pub rx_frames_dropped: u32,
+    /// Received frames with a DLC of 9 to 15. The driver refuses to decode them, so they're
+    /// dropped instead of reaching the host and not counted in rx_frames.
+    pub dlc_mismatches: u32,
+    /// Frames from the host dropped from the full transmit queue while the controller was busy,
+    /// with flow control accepting every frame. Each is echoed as failed.
+    pub tx_busy_drops: u32,
+    /// Received frames the receive filter kept from the host, remote frames dropped on request
+    /// included.
+    pub rx_filtered: u32,
+    /// Frames from the host that failed because the controller went bus-off.
+    pub bus_off_drops: u32,
+    /// Frames the controller lost because both of its receive buffers were full, unlike
+    /// rx_frames_dropped which counts frames lost in the firmware.
+    pub rx_overruns: u32,
+    /// Frames from the host echoed as failed because the channel was listen-only, unlike
+    /// host_frames_dropped which counts frames the controller can't send in any mode.
+    pub listen_only_drops: u32,
+}
+
+impl Stats {
+    pub const fn size() -> usize {
+        core::mem::size_of::<Self>()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Stats::default();
+    }
+
+    pub fn packed(&self) -> Result<[u8; Stats::size()], scroll::Error> {
+        let mut ret_value: [u8; Stats::size()] = [0; Stats::size()];
+        ret_value.pwrite_with(self, 0, LE)?;
+        Ok(ret_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(stats: &Stats) -> Vec<u32> {
+        stats
+            .packed()
+            .unwrap()
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    // The host reads the counters by position, each one has its own word in declaration order.
+    #[test]
+    fn every_counter_has_its_own_word() {
+        let stats = Stats {
+            rx_frames: 1,
+            tx_frames: 2,
+            tx_errors: 3,
+            host_frames_dropped: 4,
+            mode_change_failures: 5,
+            rx_frames_dropped: 6,
+            dlc_mismatches: 7,
+            tx_busy_drops: 8,
+            rx_filtered: 9,
+            bus_off_drops: 10,
+            rx_overruns: 11,
+            listen_only_drops: 12,
+        };
+
+        assert_eq!(words(&stats), (1..=12).collect::<Vec<u32>>());
+    }
+}
@@ -0,0 +1,278 @@
+//! A UsbBus for host tests, with the host side of the wire exposed. Control transfers run through
+//! a real UsbDevice, so the classes see requests exactly as usb-device hands them over.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use usb_device::bus::PollResult;
+use usb_device::class_prelude::*;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::{Result, UsbDirection};
+
+/// Packet size of the control endpoint the test devices are built with.
+pub const CONTROL_PACKET_SIZE: usize = 64;
+
+#[derive(Default)]
+struct Wire {
+    next_index: [u8; 2],
+    setup: Option<[u8; 8]>,
+    /// Packets the host sent to each OUT endpoint, or the error reading them runs into.
+    out: [VecDeque<Result<Vec<u8>>>; 16],
+    /// Packet written to each IN endpoint that the host hasn't taken yet.
+    in_flight: [Option<Vec<u8>>; 16],
+    in_complete: u16,
+    stalled: [u16; 2],
+}
+
+fn direction_index(direction: UsbDirection) -> usize {
+    match direction {
+        UsbDirection::Out => 0,
+        UsbDirection::In => 1,
+    }
+}
+
+pub struct TestBus(Arc<Mutex<Wire>>);
+
+/// The host end of a TestBus.
+#[derive(Clone)]
+pub struct Host(Arc<Mutex<Wire>>);
+
+impl TestBus {
+    pub fn new() -> (TestBus, Host) {
+        let wire = Arc::new(Mutex::new(Wire::default()));
+        (TestBus(wire.clone()), Host(wire))
+    }
+}
+
+impl UsbBus for TestBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let mut wire = self.0.lock().unwrap();
+
+        match ep_addr {
+            Some(address) => Ok(address),
+            None => {
+                let next = &mut wire.next_index[direction_index(ep_dir)];
+                *next += 1;
+                Ok(EndpointAddress::from_parts(*next as usize, ep_dir))
+            }
+        }
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut wire = self.0.lock().unwrap();
+        let slot = &mut wire.in_flight[ep_addr.index()];
+
+        match slot {
+            Some(_) => Err(UsbError::WouldBlock),
+            None => {
+                *slot = Some(buf.to_vec());
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut wire = self.0.lock().unwrap();
+
+        if ep_addr.index() == 0 {
+            if let Some(setup) = wire.setup.take() {
+                buf[..setup.len()].copy_from_slice(&setup);
+                return Ok(setup.len());
+            }
+        }
+
+        match wire.out[ep_addr.index()].pop_front() {
+            Some(Ok(packet)) if packet.len() > buf.len() => Err(UsbError::BufferOverflow),
+            Some(Ok(packet)) => {
+                buf[..packet.len()].copy_from_slice(&packet);
+                Ok(packet.len())
+            }
+            Some(Err(error)) => Err(error),
+            None => Err(UsbError::WouldBlock),
+        }
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut wire = self.0.lock().unwrap();
+        let bits = &mut wire.stalled[direction_index(ep_addr.direction())];
+
+        match stalled {
+            true => *bits |= 1 << ep_addr.index(),
+            false => *bits &= !(1 << ep_addr.index()),
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let wire = self.0.lock().unwrap();
+        wire.stalled[direction_index(ep_addr.direction())] & (1 << ep_addr.index()) != 0
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut wire = self.0.lock().unwrap();
+
+        let ep_setup = wire.setup.is_some() as u16;
+        let ep_out = (0..16)
+            .filter(|index| !wire.out[*index].is_empty())
+            .fold(0, |bits, index| bits | 1 << index);
+        let ep_in_complete = core::mem::take(&mut wire.in_complete);
+
+        match ep_setup | ep_out | ep_in_complete {
+            0 => PollResult::None,
+            _ => PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            },
+        }
+    }
+}
+
+impl Host {
+    /// Sends a packet to OUT endpoint `index`.
+    pub fn send(&self, index: usize, packet: &[u8]) {
+        self.0.lock().unwrap().out[index].push_back(Ok(packet.to_vec()));
+    }
+
+    /// Makes the next read of OUT endpoint `index` fail with `error`, as a corrupted packet does.
+    pub fn send_error(&self, index: usize, error: UsbError) {
+        self.0.lock().unwrap().out[index].push_back(Err(error));
+    }
+
+    /// Takes the packet written to IN endpoint `index`, completing the write.
+    pub fn receive(&self, index: usize) -> Option<Vec<u8>> {
+        let mut wire = self.0.lock().unwrap();
+        let packet = wire.in_flight[index].take();
+
+        if packet.is_some() {
+            wire.in_complete |= 1 << index;
+        }
+
+        packet
+    }
+
+    pub fn is_stalled(&self, address: EndpointAddress) -> bool {
+        let wire = self.0.lock().unwrap();
+        wire.stalled[direction_index(address.direction())] & (1 << address.index()) != 0
+    }
+
+    /// A SETUP packet also clears a stalled control endpoint.
+    fn setup(&self, setup: [u8; 8]) {
+        let mut wire = self.0.lock().unwrap();
+        wire.setup = Some(setup);
+        wire.in_flight[0] = None;
+        wire.out[0].clear();
+        wire.stalled[0] &= !1;
+        wire.stalled[1] &= !1;
+    }
+}
+
+/// What a control request is addressed to, and its type, as in bmRequestType.
+#[derive(Clone, Copy)]
+pub enum RequestKind {
+    /// A vendor request to the gs_usb interface, what the gs_usb driver sends.
+    Vendor,
+    /// A standard request to the interface, like SET_INTERFACE.
+    StandardInterface,
+}
+
+impl RequestKind {
+    fn request_type(&self) -> u8 {
+        match self {
+            RequestKind::Vendor => 0x41,
+            RequestKind::StandardInterface => 0x01,
+        }
+    }
+}
+
+pub fn device(alloc: &UsbBusAllocator<TestBus>) -> UsbDevice<'_, TestBus> {
+    UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x2323))
+        .max_packet_size_0(CONTROL_PACKET_SIZE as u8)
+        .build()
+}
+
+fn setup_packet(kind: RequestKind, direction: u8, request: u8, value: u16, length: u16) -> [u8; 8] {
+    let [value_low, value_high] = value.to_le_bytes();
+    let [length_low, length_high] = length.to_le_bytes();
+    let request_type = kind.request_type() | direction;
+
+    // wIndex is the gs_usb interface, the first and only one.
+    [request_type, request, value_low, value_high, 0, 0, length_low, length_high]
+}
+
+/// Runs a control OUT transfer, returning whether the device accepted it.
+pub fn control_out<C: UsbClass<TestBus>>(
+    host: &Host,
+    device: &mut UsbDevice<'_, TestBus>,
+    class: &mut C,
+    (kind, request, value): (RequestKind, u8, u16),
+    data: &[u8],
+) -> bool {
+    host.setup(setup_packet(kind, 0x00, request, value, data.len() as u16));
+    device.poll(&mut [class]);
+
+    for chunk in data.chunks(CONTROL_PACKET_SIZE) {
+        host.send(0, chunk);
+        device.poll(&mut [class]);
+    }
+
+    if host.is_stalled(EndpointAddress::from_parts(0, UsbDirection::In)) {
+        return false;
+    }
+
+    // The status stage, a zero-length IN packet.
+    let status = host.receive(0);
+    device.poll(&mut [class]);
+
+    status == Some(Vec::new())
+}
+
+/// Runs a control IN transfer asking for up to `length` bytes, returning the response or None if
+/// the device rejected the request.
+pub fn control_in<C: UsbClass<TestBus>>(
+    host: &Host,
+    device: &mut UsbDevice<'_, TestBus>,
+    class: &mut C,
+    (kind, request, value): (RequestKind, u8, u16),
+    length: u16,
+) -> Option<Vec<u8>> {
+    host.setup(setup_packet(kind, 0x80, request, value, length));
+    device.poll(&mut [class]);
+
+    let mut response = Vec::new();
+
+    loop {
+        if host.is_stalled(EndpointAddress::from_parts(0, UsbDirection::In)) {
+            return None;
+        }
+
+        let packet = host.receive(0).expect("the device answers every IN request");
+        response.extend_from_slice(&packet);
+        device.poll(&mut [class]);
+
+        if packet.len() < CONTROL_PACKET_SIZE || response.len() >= length as usize {
+            break;
+        }
+    }
+
+    // The status stage, a zero-length OUT packet.
+    host.send(0, &[]);
+    device.poll(&mut [class]);
+
+    Some(response)
+}